@@ -4,12 +4,13 @@
 in vec3 fragment_normal;
 in vec4 fragment_color;
 out vec4 color;
-vec3 light_direction = normalize(vec3(0.8, -0.5, 0.6));
 
 uniform mat4 normal_matrix;
+uniform vec3 light_direction; // Normalized
+uniform vec3 light_color;
 
 void main()
 {
 
-    color = fragment_color * vec4(vec3(1.0f, 1.0f, 1.0f) * max(0, dot(normalize(mat3(normal_matrix) * fragment_normal), -light_direction)), 1.0f);
+    color = fragment_color * vec4(light_color * max(0, dot(normalize(mat3(normal_matrix) * fragment_normal), -light_direction)), 1.0f);
 }
\ No newline at end of file
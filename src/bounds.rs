@@ -0,0 +1,258 @@
+// Bounding volumes, used to know how large geometry is without looking at every vertex
+
+// Axis-aligned bounding box. An empty box has min > max, so that the union with anything else
+// is the other thing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: glm::Vec3,
+    pub max: glm::Vec3,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoundingSphere {
+    pub center: glm::Vec3,
+    pub radius: f32,
+}
+
+impl Aabb {
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: glm::vec3(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            max: glm::vec3(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        }
+    }
+
+    // Contains everything, for things whose size is unknown
+    pub fn infinite() -> Aabb {
+        Aabb {
+            min: glm::vec3(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+            max: glm::vec3(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+        }
+    }
+
+    // From a flat list of XYZ coordinates, like Mesh::vertices
+    pub fn from_points(points: &[f32]) -> Aabb {
+        let mut aabb = Aabb::empty();
+        for p in points.chunks(3) {
+            aabb.grow(&glm::vec3(p[0], p[1], p[2]));
+        }
+        aabb
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn grow(&mut self, point: &glm::Vec3) {
+        self.min = glm::min2(&self.min, point);
+        self.max = glm::max2(&self.max, point);
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: glm::min2(&self.min, &other.min),
+            max: glm::max2(&self.max, &other.max),
+        }
+    }
+
+    pub fn center(&self) -> glm::Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    // Half the size along each axis
+    pub fn extents(&self) -> glm::Vec3 {
+        (self.max - self.min) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.;
+        }
+        let size = self.max - self.min;
+        2. * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+
+    // The point in or on the box nearest to the given point, which is itself if it is inside
+    pub fn closest_point(&self, point: &glm::Vec3) -> glm::Vec3 {
+        glm::min2(&glm::max2(point, &self.min), &self.max)
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        (0..3).all(|i| self.min[i] <= other.max[i] && other.min[i] <= self.max[i])
+    }
+
+    /**
+     * The box containing this box after being transformed, e.g. from a node's local space to
+     * world space. Uses the absolute values of the rotation and scale part (Arvo's method),
+     * which is exact for the corners and much cheaper than transforming all eight.
+     */
+    pub fn transformed(&self, transform: &glm::Mat4) -> Aabb {
+        if self.is_empty() {
+            return *self;
+        }
+
+        let center = transform * glm::vec4(self.center().x, self.center().y, self.center().z, 1.);
+        let extents = self.extents();
+        let mut new_extents = glm::zero::<glm::Vec3>();
+        for row in 0..3 {
+            for col in 0..3 {
+                new_extents[row] += transform[(row, col)].abs() * extents[col];
+            }
+        }
+
+        let center = glm::vec3(center.x, center.y, center.z);
+        Aabb {
+            min: center - new_extents,
+            max: center + new_extents,
+        }
+    }
+
+    pub fn bounding_sphere(&self) -> BoundingSphere {
+        BoundingSphere {
+            center: self.center(),
+            radius: if self.is_empty() {
+                0.
+            } else {
+                glm::length(&self.extents())
+            },
+        }
+    }
+}
+
+impl BoundingSphere {
+    /**
+     * Ritter's algorithm: start with a sphere spanning two far apart points, then grow it to
+     * include any point outside. Within about 5% of the optimal sphere.
+     */
+    pub fn from_points(points: &[f32]) -> BoundingSphere {
+        let points: Vec<glm::Vec3> = points
+            .chunks(3)
+            .map(|p| glm::vec3(p[0], p[1], p[2]))
+            .collect();
+        if points.is_empty() {
+            return BoundingSphere {
+                center: glm::zero(),
+                radius: 0.,
+            };
+        }
+
+        let farthest_from = |from: &glm::Vec3| {
+            *points
+                .iter()
+                .max_by(|a, b| {
+                    // NaN positions compare as equal, so broken meshes can still be validated
+                    glm::distance2(from, a)
+                        .partial_cmp(&glm::distance2(from, b))
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                })
+                .unwrap()
+        };
+        let a = farthest_from(&points[0]);
+        let b = farthest_from(&a);
+
+        let mut center = (a + b) * 0.5;
+        let mut radius = glm::distance(&a, &b) * 0.5;
+        for p in &points {
+            let distance = glm::distance(&center, p);
+            if distance > radius {
+                let new_radius = (radius + distance) * 0.5;
+                center += (p - center) * ((new_radius - radius) / distance);
+                radius = new_radius;
+            }
+        }
+
+        BoundingSphere { center, radius }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aabb(min: [f32; 3], max: [f32; 3]) -> Aabb {
+        Aabb {
+            min: glm::make_vec3(&min),
+            max: glm::make_vec3(&max),
+        }
+    }
+
+    // The box around the eight transformed corners, which transformed has to match
+    fn transformed_corners(aabb: &Aabb, transform: &glm::Mat4) -> Aabb {
+        let mut result = Aabb::empty();
+        for i in 0..8 {
+            let corner = glm::vec3(
+                if i & 1 == 0 { aabb.min.x } else { aabb.max.x },
+                if i & 2 == 0 { aabb.min.y } else { aabb.max.y },
+                if i & 4 == 0 { aabb.min.z } else { aabb.max.z },
+            );
+            let p = transform * glm::vec4(corner.x, corner.y, corner.z, 1.);
+            result.grow(&glm::vec3(p.x, p.y, p.z));
+        }
+        result
+    }
+
+    fn assert_close(a: &Aabb, b: &Aabb) {
+        assert!(
+            glm::distance(&a.min, &b.min) < 1e-5 && glm::distance(&a.max, &b.max) < 1e-5,
+            "{:?} != {:?}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn quarter_turns_swap_the_axes() {
+        let bounds = aabb([0., 0., 0.], [2., 1., 1.]);
+        let rotation = glm::rotation(std::f32::consts::FRAC_PI_2, &glm::vec3(0., 0., 1.));
+        assert_close(
+            &bounds.transformed(&rotation),
+            &aabb([-1., 0., 0.], [0., 2., 1.]),
+        );
+    }
+
+    #[test]
+    fn transformed_boxes_are_tight_around_the_corners() {
+        let bounds = aabb([-1., 0., 2.], [3., 0.5, 4.]);
+        let transforms = [
+            glm::rotation(0.7, &glm::vec3(1., 2., 3.).normalize()),
+            glm::translation(&glm::vec3(5., -1., 2.))
+                * glm::rotation(std::f32::consts::FRAC_PI_4, &glm::vec3(0., 1., 0.))
+                * glm::scaling(&glm::vec3(2., 1., 0.5)),
+        ];
+        for transform in &transforms {
+            assert_close(
+                &bounds.transformed(transform),
+                &transformed_corners(&bounds, transform),
+            );
+        }
+
+        // A 45 degree turn widens a unit square by a factor of sqrt(2)
+        let square = aabb([-0.5, -0.5, 0.], [0.5, 0.5, 0.]);
+        let turned = square.transformed(&glm::rotation(
+            std::f32::consts::FRAC_PI_4,
+            &glm::vec3(0., 0., 1.),
+        ));
+        assert!((turned.extents().x - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn empty_boxes_stay_empty() {
+        let transform = glm::translation(&glm::vec3(1., 2., 3.));
+        assert!(Aabb::empty().transformed(&transform).is_empty());
+        assert!(Aabb::empty().union(&Aabb::empty()).is_empty());
+        assert_eq!(Aabb::empty().bounding_sphere().radius, 0.);
+    }
+
+    #[test]
+    fn bounding_spheres_contain_every_point() {
+        #[rustfmt::skip]
+        let points = [
+            0., 0., 0.,  4., 0., 0.,  0., 3., 0.,  1., 1., 5.,  -2., 2., 2.,  3., 3., 3.,
+        ];
+        let sphere = BoundingSphere::from_points(&points);
+        for p in points.chunks(3) {
+            let distance = glm::distance(&sphere.center, &glm::make_vec3(p));
+            assert!(distance <= sphere.radius + 1e-5);
+        }
+    }
+}
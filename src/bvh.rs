@@ -0,0 +1,641 @@
+use std::cmp::Ordering;
+
+use crate::bounds::Aabb;
+use crate::mesh::Mesh;
+use crate::picking::{intersect_aabb, intersect_triangle};
+
+// Split candidates along each axis when building
+const BINS: usize = 12;
+// Nodes with more triangles are always split, even when the split doesn't look worth it
+const MAX_LEAF_SIZE: usize = 8;
+// Cost of visiting a node, relative to testing a triangle
+const TRAVERSAL_COST: f32 = 1.;
+
+#[derive(Clone, Copy, Debug)]
+struct BvhNode {
+    bounds: Aabb,
+    // Leaves hold count triangles of Bvh::triangles starting at first. Inner nodes have a count
+    // of zero, and their two children at first and first + 1.
+    first: u32,
+    count: u32,
+}
+
+/**
+ * Bounding volume hierarchy over the triangles of a mesh, in the mesh's own coordinates, for
+ * finding the few triangles near a ray, sphere, box or point without testing all of them. Built
+ * with the surface area heuristic, and keeps its own copy of the triangles so it doesn't need
+ * the mesh or a GL context afterwards.
+ */
+pub struct Bvh {
+    positions: Vec<glm::Vec3>,
+    indices: Vec<u32>,
+    nodes: Vec<BvhNode>, // The root first, and every node before its children
+    triangles: Vec<u32>, // Triangle indices, grouped by leaf
+}
+
+// Where a ray first hit the mesh
+#[derive(Clone, Copy, Debug)]
+pub struct RayHit {
+    // Index into the mesh's triangles, i.e. its indices / 3
+    pub triangle: usize,
+    // Along the ray, in units of its direction
+    pub distance: f32,
+    // Weights of the triangle's three corners at the hit
+    pub barycentric: glm::Vec3,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ClosestPoint {
+    pub triangle: usize,
+    pub point: glm::Vec3,
+    pub distance: f32,
+}
+
+impl Bvh {
+    pub fn from_mesh(mesh: &Mesh) -> Bvh {
+        let mut bvh = Bvh {
+            positions: (0..mesh.vertex_count() as u32)
+                .map(|i| mesh.position(i))
+                .collect(),
+            indices: mesh.indices.clone(),
+            nodes: vec![],
+            triangles: vec![],
+        };
+        let n_triangles = bvh.indices.len() / 3;
+        if n_triangles == 0 {
+            return bvh;
+        }
+
+        let bounds: Vec<Aabb> = (0..n_triangles).map(|t| bvh.triangle_bounds(t)).collect();
+        bvh.triangles = (0..n_triangles as u32).collect();
+        bvh.nodes.push(BvhNode {
+            bounds: Aabb::empty(),
+            first: 0,
+            count: n_triangles as u32,
+        });
+
+        // Without recursion, so that the deep trees of large meshes can't overflow the stack
+        let mut pending = vec![0];
+        while let Some(node) = pending.pop() {
+            if let Some(left) = bvh.split(node, &bounds) {
+                pending.push(left);
+                pending.push(left + 1);
+            }
+        }
+        bvh
+    }
+
+    /**
+     * Sets the bounds of a node, and splits it in two if the surface area heuristic says that's
+     * cheaper to search than testing all its triangles. The triangles' centers are sorted into
+     * bins along each axis, and every boundary between bins is tried as a split. Returns the
+     * index of the first child if the node was split.
+     */
+    fn split(&mut self, node: usize, bounds: &[Aabb]) -> Option<usize> {
+        let (first, count) = (
+            self.nodes[node].first as usize,
+            self.nodes[node].count as usize,
+        );
+        let triangles = &mut self.triangles[first..first + count];
+
+        let mut node_bounds = Aabb::empty();
+        let mut centers = Aabb::empty();
+        for &t in triangles.iter() {
+            node_bounds = node_bounds.union(&bounds[t as usize]);
+            centers.grow(&bounds[t as usize].center());
+        }
+        self.nodes[node].bounds = node_bounds;
+        if count <= 1 {
+            return None;
+        }
+
+        let bin_of = |t: u32, axis: usize| {
+            let offset = bounds[t as usize].center()[axis] - centers.min[axis];
+            let bin = offset / (centers.max[axis] - centers.min[axis]) * BINS as f32;
+            (bin as usize).min(BINS - 1)
+        };
+
+        // The cost of a split is the area of each side times its triangles
+        let mut best: Option<(usize, usize, f32)> = None; // Axis, first bin on the right, cost
+        for axis in 0..3 {
+            // Also skips NaN, from triangles with NaN corners
+            if centers.max[axis].partial_cmp(&centers.min[axis]) != Some(Ordering::Greater) {
+                continue;
+            }
+            let mut bin_bounds = [Aabb::empty(); BINS];
+            let mut bin_counts = [0usize; BINS];
+            for &t in triangles.iter() {
+                let bin = bin_of(t, axis);
+                bin_bounds[bin] = bin_bounds[bin].union(&bounds[t as usize]);
+                bin_counts[bin] += 1;
+            }
+
+            // Swept from the left, then from the right to find the cost of each boundary
+            let mut left = [(0f32, 0usize); BINS];
+            let (mut left_bounds, mut left_count) = (Aabb::empty(), 0);
+            for bin in 0..BINS - 1 {
+                left_bounds = left_bounds.union(&bin_bounds[bin]);
+                left_count += bin_counts[bin];
+                left[bin + 1] = (left_bounds.surface_area(), left_count);
+            }
+            let (mut right_bounds, mut right_count) = (Aabb::empty(), 0);
+            for bin in (1..BINS).rev() {
+                right_bounds = right_bounds.union(&bin_bounds[bin]);
+                right_count += bin_counts[bin];
+                let (left_area, left_count) = left[bin];
+                if left_count == 0 || right_count == 0 {
+                    continue;
+                }
+                let cost = left_area * left_count as f32
+                    + right_bounds.surface_area() * right_count as f32;
+                if best.is_none_or(|(_, _, best)| cost < best) {
+                    best = Some((axis, bin, cost));
+                }
+            }
+        }
+
+        let area = node_bounds.surface_area();
+        let middle = match best {
+            Some((axis, split, cost))
+                if count > MAX_LEAF_SIZE || TRAVERSAL_COST + cost / area < count as f32 =>
+            {
+                let mut middle = 0;
+                for i in 0..count {
+                    if bin_of(triangles[i], axis) < split {
+                        triangles.swap(i, middle);
+                        middle += 1;
+                    }
+                }
+                middle
+            }
+            // All the centers are in one point, so any split is as good as another
+            None if count > MAX_LEAF_SIZE => count / 2,
+            _ => return None,
+        };
+
+        let left = self.nodes.len();
+        self.nodes.push(BvhNode {
+            bounds: Aabb::empty(),
+            first: first as u32,
+            count: middle as u32,
+        });
+        self.nodes.push(BvhNode {
+            bounds: Aabb::empty(),
+            first: (first + middle) as u32,
+            count: (count - middle) as u32,
+        });
+        self.nodes[node].first = left as u32;
+        self.nodes[node].count = 0;
+        Some(left)
+    }
+
+    /**
+     * Moves the triangles to the mesh's current vertex positions, e.g. for an animated mesh,
+     * by recomputing the bounds from the leaves up instead of rebuilding. The mesh must have the
+     * same triangles as the one the hierarchy was built from, and searches get slower the
+     * further the triangles move from where they were, so rebuild after large changes. Nothing
+     * moves vertices on the CPU yet, as the glTF animations move whole nodes.
+     */
+    #[allow(dead_code)]
+    pub fn refit(&mut self, mesh: &Mesh) {
+        assert_eq!(
+            mesh.vertex_count(),
+            self.positions.len(),
+            "Refitting a bounding volume hierarchy to a different mesh"
+        );
+        for (i, position) in self.positions.iter_mut().enumerate() {
+            *position = mesh.position(i as u32);
+        }
+
+        // Children come after their parents, so going backwards visits them first
+        for i in (0..self.nodes.len()).rev() {
+            let node = self.nodes[i];
+            self.nodes[i].bounds = if node.count > 0 {
+                self.leaf_triangles(&node)
+                    .iter()
+                    .fold(Aabb::empty(), |bounds, &t| {
+                        bounds.union(&self.triangle_bounds(t as usize))
+                    })
+            } else {
+                let left = &self.nodes[node.first as usize];
+                left.bounds
+                    .union(&self.nodes[node.first as usize + 1].bounds)
+            };
+        }
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    pub fn corners(&self, triangle: usize) -> [glm::Vec3; 3] {
+        let i = &self.indices[triangle * 3..triangle * 3 + 3];
+        [
+            self.positions[i[0] as usize],
+            self.positions[i[1] as usize],
+            self.positions[i[2] as usize],
+        ]
+    }
+
+    fn triangle_bounds(&self, triangle: usize) -> Aabb {
+        let [a, b, c] = self.corners(triangle);
+        Aabb {
+            min: glm::min2(&glm::min2(&a, &b), &c),
+            max: glm::max2(&glm::max2(&a, &b), &c),
+        }
+    }
+
+    fn leaf_triangles(&self, node: &BvhNode) -> &[u32] {
+        &self.triangles[node.first as usize..(node.first + node.count) as usize]
+    }
+
+    /**
+     * The closest hit of a ray no further than reach, from either side of the triangles. The
+     * direction need not be normalized, and distances are in units of it. Nearer children are
+     * searched first, so most of the tree behind the first hit is skipped.
+     */
+    pub fn cast_ray(
+        &self,
+        origin: &glm::Vec3,
+        direction: &glm::Vec3,
+        reach: f32,
+    ) -> Option<RayHit> {
+        let entry = |node: usize, reach: f32| {
+            intersect_aabb(origin, direction, &self.nodes[node].bounds)
+                .filter(|&distance| distance <= reach)
+        };
+
+        let mut closest: Option<RayHit> = None;
+        let mut reach = reach;
+        let mut pending: Vec<(usize, f32)> = vec![];
+        if let Some(distance) = self.nodes.first().and_then(|_| entry(0, reach)) {
+            pending.push((0, distance));
+        }
+        while let Some((i, distance)) = pending.pop() {
+            if distance > reach {
+                continue;
+            }
+            let node = &self.nodes[i];
+            if node.count > 0 {
+                for &t in self.leaf_triangles(node) {
+                    let [a, b, c] = self.corners(t as usize);
+                    match intersect_triangle(origin, direction, &a, &b, &c) {
+                        Some((distance, barycentric)) if distance <= reach => {
+                            reach = distance;
+                            closest = Some(RayHit {
+                                triangle: t as usize,
+                                distance,
+                                barycentric,
+                            });
+                        }
+                        _ => {}
+                    }
+                }
+                continue;
+            }
+
+            let (left, right) = (node.first as usize, node.first as usize + 1);
+            let (left_entry, right_entry) = (entry(left, reach), entry(right, reach));
+            let nearer_left =
+                left_entry.unwrap_or(f32::INFINITY) <= right_entry.unwrap_or(f32::INFINITY);
+            let order = if nearer_left {
+                [(right, right_entry), (left, left_entry)]
+            } else {
+                [(left, left_entry), (right, right_entry)]
+            };
+            pending.extend(
+                order
+                    .iter()
+                    .filter_map(|&(child, entry)| entry.map(|distance| (child, distance))),
+            );
+        }
+        closest
+    }
+
+    // Triangles that touch the sphere, in increasing order
+    pub fn triangles_in_sphere(&self, center: &glm::Vec3, radius: f32) -> Vec<usize> {
+        let radius2 = radius * radius;
+        self.collect(
+            |bounds| glm::distance2(&bounds.closest_point(center), center) <= radius2,
+            |[a, b, c]| {
+                glm::distance2(&closest_point_on_triangle(center, &a, &b, &c), center) <= radius2
+            },
+        )
+    }
+
+    // Triangles that touch the box, in increasing order
+    pub fn triangles_in_aabb(&self, aabb: &Aabb) -> Vec<usize> {
+        self.collect(
+            |bounds| bounds.intersects(aabb),
+            |[a, b, c]| triangle_intersects_aabb(&a, &b, &c, aabb),
+        )
+    }
+
+    fn collect<N, T>(&self, touches_node: N, touches_triangle: T) -> Vec<usize>
+    where
+        N: Fn(&Aabb) -> bool,
+        T: Fn([glm::Vec3; 3]) -> bool,
+    {
+        let mut found = vec![];
+        let mut pending = if self.nodes.is_empty() {
+            vec![]
+        } else {
+            vec![0]
+        };
+        while let Some(i) = pending.pop() {
+            let node = &self.nodes[i];
+            if !touches_node(&node.bounds) {
+                continue;
+            }
+            if node.count > 0 {
+                found.extend(
+                    self.leaf_triangles(node)
+                        .iter()
+                        .map(|&t| t as usize)
+                        .filter(|&t| touches_triangle(self.corners(t))),
+                );
+            } else {
+                pending.push(node.first as usize);
+                pending.push(node.first as usize + 1);
+            }
+        }
+        found.sort_unstable();
+        found
+    }
+
+    /**
+     * The point on the mesh nearest to the given point, if there is one within max_distance.
+     * Nodes are searched nearest first, and skipped once they are further away than the
+     * closest point found so far.
+     */
+    pub fn closest_point(&self, point: &glm::Vec3, max_distance: f32) -> Option<ClosestPoint> {
+        let distance2 =
+            |node: usize| glm::distance2(&self.nodes[node].bounds.closest_point(point), point);
+
+        let mut closest: Option<ClosestPoint> = None;
+        let mut reach2 = max_distance * max_distance;
+        let mut pending: Vec<(usize, f32)> = vec![];
+        if !self.nodes.is_empty() {
+            pending.push((0, distance2(0)));
+        }
+        while let Some((i, node_distance2)) = pending.pop() {
+            if node_distance2 > reach2 {
+                continue;
+            }
+            let node = &self.nodes[i];
+            if node.count > 0 {
+                for &t in self.leaf_triangles(node) {
+                    let [a, b, c] = self.corners(t as usize);
+                    let nearest = closest_point_on_triangle(point, &a, &b, &c);
+                    let triangle_distance2 = glm::distance2(&nearest, point);
+                    if triangle_distance2 <= reach2 {
+                        reach2 = triangle_distance2;
+                        closest = Some(ClosestPoint {
+                            triangle: t as usize,
+                            point: nearest,
+                            distance: triangle_distance2.sqrt(),
+                        });
+                    }
+                }
+                continue;
+            }
+
+            let (left, right) = (node.first as usize, node.first as usize + 1);
+            let (left_distance2, right_distance2) = (distance2(left), distance2(right));
+            if left_distance2 <= right_distance2 {
+                pending.push((right, right_distance2));
+                pending.push((left, left_distance2));
+            } else {
+                pending.push((left, left_distance2));
+                pending.push((right, right_distance2));
+            }
+        }
+        closest
+    }
+}
+
+// From Real-Time Collision Detection (Ericson), by which corner, edge or face region p is in
+fn closest_point_on_triangle(
+    p: &glm::Vec3,
+    a: &glm::Vec3,
+    b: &glm::Vec3,
+    c: &glm::Vec3,
+) -> glm::Vec3 {
+    let (ab, ac) = (b - a, c - a);
+    let ap = p - a;
+    let (d1, d2) = (glm::dot(&ab, &ap), glm::dot(&ac, &ap));
+    if d1 <= 0. && d2 <= 0. {
+        return *a;
+    }
+    let bp = p - b;
+    let (d3, d4) = (glm::dot(&ab, &bp), glm::dot(&ac, &bp));
+    if d3 >= 0. && d4 <= d3 {
+        return *b;
+    }
+    let cp = p - c;
+    let (d5, d6) = (glm::dot(&ab, &cp), glm::dot(&ac, &cp));
+    if d6 >= 0. && d5 <= d6 {
+        return *c;
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0. && d1 >= 0. && d3 <= 0. {
+        return a + ab * (d1 / (d1 - d3));
+    }
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0. && d2 >= 0. && d6 <= 0. {
+        return a + ac * (d2 / (d2 - d6));
+    }
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0. && d4 - d3 >= 0. && d5 - d6 >= 0. {
+        return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+
+    let denominator = va + vb + vc;
+    a + ab * (vb / denominator) + ac * (vc / denominator)
+}
+
+/**
+ * Separating axis test, from Akenine-Möller: the triangle misses the box if they don't overlap
+ * along the box's axes, the triangle's normal, or the cross product of a box axis and an edge.
+ */
+fn triangle_intersects_aabb(a: &glm::Vec3, b: &glm::Vec3, c: &glm::Vec3, aabb: &Aabb) -> bool {
+    if aabb.is_empty() {
+        return false;
+    }
+    let (center, half) = (aabb.center(), aabb.extents());
+    let corners = [a - center, b - center, c - center];
+    let separated_along = |axis: &glm::Vec3| {
+        let radius = half.x * axis.x.abs() + half.y * axis.y.abs() + half.z * axis.z.abs();
+        let projected = [
+            glm::dot(&corners[0], axis),
+            glm::dot(&corners[1], axis),
+            glm::dot(&corners[2], axis),
+        ];
+        let min = projected[0].min(projected[1]).min(projected[2]);
+        let max = projected[0].max(projected[1]).max(projected[2]);
+        min > radius || max < -radius
+    };
+
+    let box_axes = [glm::Vec3::x(), glm::Vec3::y(), glm::Vec3::z()];
+    if box_axes.iter().any(&separated_along) {
+        return false;
+    }
+    let edges = [
+        corners[1] - corners[0],
+        corners[2] - corners[1],
+        corners[0] - corners[2],
+    ];
+    if separated_along(&glm::cross(&edges[0], &edges[1])) {
+        return false;
+    }
+    !edges.iter().any(|edge| {
+        box_axes
+            .iter()
+            .any(|axis| separated_along(&glm::cross(axis, edge)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    // Small triangles scattered through a 20 unit cube, many of them overlapping
+    fn scattered_triangles(rng: &mut ChaCha8Rng, count: usize) -> Mesh {
+        let mut vertices = vec![];
+        for _ in 0..count {
+            let center = glm::vec3(
+                rng.gen_range(-10.0..10.0),
+                rng.gen_range(-10.0..10.0),
+                rng.gen_range(-10.0..10.0),
+            );
+            for _ in 0..3 {
+                vertices.extend(&[
+                    center.x + rng.gen_range(-1.5..1.5),
+                    center.y + rng.gen_range(-1.5..1.5),
+                    center.z + rng.gen_range(-1.5..1.5),
+                ]);
+            }
+        }
+        let indices = (0..count as u32 * 3).collect();
+        Mesh::from_shape((vertices, indices), [1.; 4])
+    }
+
+    fn random_point(rng: &mut ChaCha8Rng) -> glm::Vec3 {
+        glm::vec3(
+            rng.gen_range(-12.0..12.0),
+            rng.gen_range(-12.0..12.0),
+            rng.gen_range(-12.0..12.0),
+        )
+    }
+
+    // Every query answered through the hierarchy, against testing every triangle
+    fn assert_matches_brute_force(bvh: &Bvh, rng: &mut ChaCha8Rng) {
+        let all: Vec<usize> = (0..bvh.triangle_count()).collect();
+        for _ in 0..100 {
+            let origin = random_point(rng);
+            let direction = random_point(rng) - origin;
+            let expected = all
+                .iter()
+                .filter_map(|&t| {
+                    let [a, b, c] = bvh.corners(t);
+                    intersect_triangle(&origin, &direction, &a, &b, &c).map(|(d, _)| d)
+                })
+                .fold(None, |closest: Option<f32>, d| {
+                    Some(closest.map_or(d, |c| c.min(d)))
+                });
+            let hit = bvh.cast_ray(&origin, &direction, f32::INFINITY);
+            assert_eq!(hit.map(|hit| hit.distance), expected);
+            if let Some(hit) = hit {
+                let [a, b, c] = bvh.corners(hit.triangle);
+                let at = a * hit.barycentric.x + b * hit.barycentric.y + c * hit.barycentric.z;
+                assert!((at - (origin + direction * hit.distance)).norm() < 1e-3);
+            }
+
+            let center = random_point(rng);
+            let radius = rng.gen_range(0.0..4.0);
+            let expected: Vec<usize> = all
+                .iter()
+                .copied()
+                .filter(|&t| {
+                    let [a, b, c] = bvh.corners(t);
+                    let nearest = closest_point_on_triangle(&center, &a, &b, &c);
+                    glm::distance(&nearest, &center) <= radius
+                })
+                .collect();
+            assert_eq!(bvh.triangles_in_sphere(&center, radius), expected);
+
+            let corner = random_point(rng);
+            let aabb = Aabb {
+                min: corner,
+                max: corner + glm::vec3(rng.gen_range(0.0..6.0), rng.gen_range(0.0..6.0), 3.),
+            };
+            let expected: Vec<usize> = all
+                .iter()
+                .copied()
+                .filter(|&t| {
+                    let [a, b, c] = bvh.corners(t);
+                    triangle_intersects_aabb(&a, &b, &c, &aabb)
+                })
+                .collect();
+            assert_eq!(bvh.triangles_in_aabb(&aabb), expected);
+
+            let point = random_point(rng);
+            let expected = all
+                .iter()
+                .map(|&t| {
+                    let [a, b, c] = bvh.corners(t);
+                    glm::distance(&closest_point_on_triangle(&point, &a, &b, &c), &point)
+                })
+                .fold(f32::INFINITY, f32::min);
+            let closest = bvh.closest_point(&point, f32::INFINITY).unwrap();
+            assert!((closest.distance - expected).abs() < 1e-5);
+            let [a, b, c] = bvh.corners(closest.triangle);
+            assert_eq!(closest.point, closest_point_on_triangle(&point, &a, &b, &c));
+            if expected > 1. {
+                assert!(bvh.closest_point(&point, expected - 0.5).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn queries_match_testing_every_triangle() {
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        let bvh = Bvh::from_mesh(&scattered_triangles(&mut rng, 300));
+        assert!(bvh.nodes.len() > 1);
+        assert_matches_brute_force(&bvh, &mut rng);
+    }
+
+    #[test]
+    fn queries_match_testing_every_triangle_after_refit() {
+        let mut rng = ChaCha8Rng::seed_from_u64(2);
+        let mut mesh = scattered_triangles(&mut rng, 300);
+        let mut bvh = Bvh::from_mesh(&mesh);
+
+        // Each triangle drifts on its own, so the leaves no longer fit what they were built on
+        for triangle in mesh.vertices.chunks_mut(9) {
+            let drift = random_point(&mut rng) / 4.;
+            for corner in triangle.chunks_mut(3) {
+                corner[0] += drift.x;
+                corner[1] += drift.y;
+                corner[2] += drift.z;
+            }
+        }
+        bvh.refit(&mesh);
+        assert_eq!(bvh.corners(7)[0], mesh.position(21));
+        assert_matches_brute_force(&bvh, &mut rng);
+    }
+
+    #[test]
+    fn empty_meshes_have_no_hits() {
+        let bvh = Bvh::from_mesh(&Mesh::from_shape((vec![], vec![]), [1.; 4]));
+        let origin = glm::vec3(0., 0., 0.);
+        assert!(bvh
+            .cast_ray(&origin, &glm::vec3(0., 0., 1.), f32::INFINITY)
+            .is_none());
+        assert!(bvh.triangles_in_sphere(&origin, 10.).is_empty());
+        assert!(bvh.closest_point(&origin, f32::INFINITY).is_none());
+    }
+}
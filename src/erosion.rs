@@ -0,0 +1,254 @@
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::heightmap::Heightmap;
+
+// Material slides down slopes steeper than the talus angle, softening cliffs into scree
+#[derive(Clone, Copy, Debug)]
+pub struct ThermalErosion {
+    pub iterations: u32,
+    pub talus: f32, // Largest stable height difference between neighbouring samples
+    pub rate: f32,  // Fraction of the excess moved per iteration, between 0 and 1
+}
+
+impl Default for ThermalErosion {
+    fn default() -> Self {
+        ThermalErosion {
+            iterations: 50,
+            talus: 0.01,
+            rate: 0.5,
+        }
+    }
+}
+
+// Raindrops roll downhill, carving valleys and leaving sediment where they slow down
+#[derive(Clone, Copy, Debug)]
+pub struct HydraulicErosion {
+    pub seed: u64,
+    pub droplets: u32,
+    pub max_steps: u32,    // How far each droplet may roll
+    pub inertia: f32,      // 0 follows the slope exactly, 1 never turns
+    pub capacity: f32,     // Sediment carried per unit of slope, speed and water
+    pub min_capacity: f32, // Keeps droplets on flat ground eroding a little
+    pub erosion: f32,      // Fraction of the free capacity picked up per step
+    pub deposition: f32,   // Fraction of the excess sediment dropped per step
+    pub evaporation: f32,  // Fraction of the water lost per step
+    pub gravity: f32,
+}
+
+impl Default for HydraulicErosion {
+    fn default() -> Self {
+        HydraulicErosion {
+            seed: 0,
+            droplets: 50_000,
+            max_steps: 64,
+            inertia: 0.05,
+            capacity: 4.0,
+            min_capacity: 0.0001,
+            erosion: 0.3,
+            deposition: 0.3,
+            evaporation: 0.02,
+            gravity: 4.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Erosion {
+    Thermal(ThermalErosion),
+    Hydraulic(HydraulicErosion),
+}
+
+impl Heightmap {
+    pub fn erode(&mut self, erosion: &Erosion) {
+        match erosion {
+            Erosion::Thermal(settings) => self.erode_thermal(settings),
+            Erosion::Hydraulic(settings) => self.erode_hydraulic(settings),
+        }
+    }
+
+    /**
+     * Every iteration, each sample sheds part of its height above the talus to its lower
+     * neighbours, in proportion to how much lower they are. The moves are gathered before they
+     * are applied, so the result does not depend on the order the samples are visited in.
+     */
+    pub fn erode_thermal(&mut self, settings: &ThermalErosion) {
+        let (w, d) = (self.width, self.depth);
+        let mut changes = vec![0.; w * d];
+        const NEIGHBOURS: [(isize, isize); 4] = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+
+        for _ in 0..settings.iterations {
+            changes.iter_mut().for_each(|c| *c = 0.);
+            for z in 0..d {
+                for x in 0..w {
+                    let i = z * w + x;
+                    let h = self.heights[i];
+
+                    let mut drops = [(0, 0.); 4];
+                    let (mut total, mut steepest) = (0., 0f32);
+                    for (k, (dx, dz)) in NEIGHBOURS.iter().enumerate() {
+                        let (nx, nz) = (x as isize + dx, z as isize + dz);
+                        if nx < 0 || nz < 0 || nx >= w as isize || nz >= d as isize {
+                            continue;
+                        }
+                        let n = nz as usize * w + nx as usize;
+                        let drop = h - self.heights[n];
+                        if drop > settings.talus {
+                            drops[k] = (n, drop);
+                            total += drop;
+                            steepest = steepest.max(drop);
+                        }
+                    }
+                    if total == 0. {
+                        continue;
+                    }
+
+                    // Half the excess, so a pair of samples ends up level rather than swapped
+                    let moved = settings.rate * (steepest - settings.talus) / 2.;
+                    changes[i] -= moved;
+                    for &(n, drop) in drops.iter().filter(|(_, drop)| *drop > 0.) {
+                        changes[n] += moved * drop / total;
+                    }
+                }
+            }
+            for (h, c) in self.heights.iter_mut().zip(&changes) {
+                *h += c;
+            }
+        }
+    }
+
+    // Bilinear height and its gradient, at a position inside the grid
+    fn height_and_gradient(&self, x: f32, z: f32) -> (f32, f32, f32) {
+        let (cx, cz) = (x.floor() as usize, z.floor() as usize);
+        let (fx, fz) = (x - cx as f32, z - cz as f32);
+        let i = cz * self.width + cx;
+        let (nw, ne) = (self.heights[i], self.heights[i + 1]);
+        let (sw, se) = (
+            self.heights[i + self.width],
+            self.heights[i + self.width + 1],
+        );
+
+        let height =
+            nw * (1. - fx) * (1. - fz) + ne * fx * (1. - fz) + sw * (1. - fx) * fz + se * fx * fz;
+        let gradient_x = (ne - nw) * (1. - fz) + (se - sw) * fz;
+        let gradient_z = (sw - nw) * (1. - fx) + (se - ne) * fx;
+        (height, gradient_x, gradient_z)
+    }
+
+    /**
+     * Simulates droplets one at a time, each starting at a random position and following the
+     * slope. A droplet picks up sediment while it can carry more, which grows with its speed and
+     * the steepness, and drops it when it slows down or has to climb. Sediment is moved to and
+     * from the four samples around the droplet, weighted by how close they are.
+     */
+    pub fn erode_hydraulic(&mut self, settings: &HydraulicErosion) {
+        let (w, d) = (self.width, self.depth);
+        if w < 2 || d < 2 {
+            return;
+        }
+        // Not StdRng, for the same reason as in Perlin::new
+        let mut rng = ChaCha8Rng::seed_from_u64(settings.seed);
+
+        for _ in 0..settings.droplets {
+            let mut x = rng.gen_range(0.0..(w - 1) as f32);
+            let mut z = rng.gen_range(0.0..(d - 1) as f32);
+            let (mut dir_x, mut dir_z) = (0., 0.);
+            let (mut speed, mut water, mut sediment) = (1., 1., 0.);
+
+            for _ in 0..settings.max_steps {
+                let (cx, cz) = (x.floor() as usize, z.floor() as usize);
+                let (fx, fz) = (x - cx as f32, z - cz as f32);
+                let (height, gradient_x, gradient_z) = self.height_and_gradient(x, z);
+
+                dir_x = dir_x * settings.inertia - gradient_x * (1. - settings.inertia);
+                dir_z = dir_z * settings.inertia - gradient_z * (1. - settings.inertia);
+                let length = (dir_x * dir_x + dir_z * dir_z).sqrt();
+                if length < 1e-9 {
+                    break;
+                }
+                dir_x /= length;
+                dir_z /= length;
+
+                let (next_x, next_z) = (x + dir_x, z + dir_z);
+                if next_x < 0.
+                    || next_z < 0.
+                    || next_x >= (w - 1) as f32
+                    || next_z >= (d - 1) as f32
+                {
+                    break;
+                }
+                let delta = self.height_and_gradient(next_x, next_z).0 - height;
+
+                let corners = [
+                    (cz * w + cx, (1. - fx) * (1. - fz)),
+                    (cz * w + cx + 1, fx * (1. - fz)),
+                    ((cz + 1) * w + cx, (1. - fx) * fz),
+                    ((cz + 1) * w + cx + 1, fx * fz),
+                ];
+                let capacity =
+                    (-delta * speed * water * settings.capacity).max(settings.min_capacity);
+                if sediment > capacity || delta > 0. {
+                    // Uphill, the pit behind is filled at most up to the next height
+                    let amount = if delta > 0. {
+                        delta.min(sediment)
+                    } else {
+                        (sediment - capacity) * settings.deposition
+                    };
+                    sediment -= amount;
+                    for &(i, weight) in &corners {
+                        self.heights[i] += amount * weight;
+                    }
+                } else {
+                    // Never dig deeper than the step down, which would leave a pit
+                    let amount = ((capacity - sediment) * settings.erosion).min(-delta);
+                    sediment += amount;
+                    for &(i, weight) in &corners {
+                        self.heights[i] -= amount * weight;
+                    }
+                }
+
+                speed = (speed * speed - delta * settings.gravity).max(0.).sqrt();
+                water *= 1. - settings.evaporation;
+                x = next_x;
+                z = next_z;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noise::NoiseSettings;
+
+    #[test]
+    fn hydraulic_erosion_is_the_same_for_a_seed() {
+        let noise = NoiseSettings {
+            seed: 42,
+            octaves: 4,
+            frequency: 0.3,
+            ..NoiseSettings::default()
+        };
+        let mut map = Heightmap::from_noise(8, 8, &noise);
+        map.erode_hydraulic(&HydraulicErosion {
+            seed: 7,
+            droplets: 200,
+            ..HydraulicErosion::default()
+        });
+
+        #[rustfmt::skip]
+        let expected = [
+            -0.14928661, -0.08155915, -0.023026794, 0.01424773,
+            0.03446737, 0.13346423, 0.15050836, 0.30197087,
+        ];
+        for (height, expected) in map.heights[24..32].iter().zip(&expected) {
+            assert!(
+                (height - expected).abs() < 1e-5,
+                "{:?}",
+                &map.heights[24..32]
+            );
+        }
+        let total: f32 = map.heights.iter().sum();
+        assert!((total - 9.725293).abs() < 1e-4, "{}", total);
+    }
+}
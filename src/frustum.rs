@@ -0,0 +1,168 @@
+use crate::bounds::Aabb;
+
+// The points where normal . point + distance = 0, with the normal pointing into the frustum
+#[derive(Clone, Copy, Debug)]
+pub struct Plane {
+    pub normal: glm::Vec3,
+    pub distance: f32,
+}
+
+impl Plane {
+    // Positive on the inside
+    pub fn signed_distance(&self, point: &glm::Vec3) -> f32 {
+        glm::dot(&self.normal, point) + self.distance
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Containment {
+    Outside,
+    Intersecting,
+    Inside,
+}
+
+// The part of the world the camera sees, between the left, right, bottom, top, near and far planes
+#[derive(Clone, Copy, Debug)]
+pub struct Frustum {
+    pub planes: [Plane; 6],
+}
+
+impl Frustum {
+    /**
+     * Extracts the planes from a view-projection matrix (Gribb and Hartmann). A point is seen
+     * when -w <= x, y, z <= w in OpenGL's clip space, so each plane is the last row of the
+     * matrix plus or minus one of the others. For a projection matrix alone, the planes are in
+     * view space, and with a model matrix included, in the model's own coordinates.
+     */
+    pub fn from_matrix(view_projection: &glm::Mat4) -> Frustum {
+        let row = |i| glm::row(view_projection, i);
+        let plane = |v: glm::Vec4| {
+            let normal = glm::vec3(v.x, v.y, v.z);
+            let length = glm::length(&normal);
+            Plane {
+                normal: normal / length,
+                distance: v.w / length,
+            }
+        };
+
+        Frustum {
+            planes: [
+                plane(row(3) + row(0)),
+                plane(row(3) - row(0)),
+                plane(row(3) + row(1)),
+                plane(row(3) - row(1)),
+                plane(row(3) + row(2)),
+                plane(row(3) - row(2)),
+            ],
+        }
+    }
+
+    /**
+     * Whether the box is outside, partly inside or wholly inside the frustum. Boxes near a
+     * corner of the frustum may be called intersecting while actually outside, which only
+     * costs drawing them. Unbounded boxes always intersect, and empty ones are outside.
+     */
+    pub fn classify_aabb(&self, aabb: &Aabb) -> Containment {
+        if aabb.is_empty() {
+            return Containment::Outside;
+        }
+        let is_finite = |v: &glm::Vec3| v.iter().all(|c| c.is_finite());
+        if !is_finite(&aabb.min) || !is_finite(&aabb.max) {
+            return Containment::Intersecting;
+        }
+
+        let (center, extents) = (aabb.center(), aabb.extents());
+        let mut containment = Containment::Inside;
+        for plane in &self.planes {
+            // How far the box reaches towards the plane from its center
+            let reach = (0..3)
+                .map(|i| plane.normal[i].abs() * extents[i])
+                .sum::<f32>();
+            let distance = plane.signed_distance(&center);
+            if distance < -reach {
+                return Containment::Outside;
+            }
+            if distance < reach {
+                containment = Containment::Intersecting;
+            }
+        }
+        containment
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Looking down -z with a 90 degree field of view, so the sides are at |x|, |y| = -z
+    fn frustum(view: glm::Mat4) -> Frustum {
+        let projection = glm::perspective(1., std::f32::consts::FRAC_PI_2, 1., 100.);
+        Frustum::from_matrix(&(projection * view))
+    }
+
+    fn aabb(min: [f32; 3], max: [f32; 3]) -> Aabb {
+        Aabb {
+            min: glm::make_vec3(&min),
+            max: glm::make_vec3(&max),
+        }
+    }
+
+    #[test]
+    fn planes_face_into_the_frustum() {
+        let frustum = frustum(glm::identity());
+        let inside = glm::vec3(0., 0., -50.);
+        for plane in &frustum.planes {
+            assert!((glm::length(&plane.normal) - 1.).abs() < 1e-5);
+            assert!(plane.signed_distance(&inside) > 0.);
+        }
+
+        // The near and far planes are where the projection puts them
+        let near = frustum.planes[4].signed_distance(&glm::vec3(0., 0., -1.));
+        let far = frustum.planes[5].signed_distance(&glm::vec3(0., 0., -100.));
+        assert!(near.abs() < 1e-3 && far.abs() < 1e-3, "{} {}", near, far);
+    }
+
+    #[test]
+    fn boxes_are_classified_against_every_plane() {
+        let frustum = frustum(glm::identity());
+        #[rustfmt::skip]
+        let cases = [
+            (aabb([-1., -1., -11.], [1., 1., -9.]),     Containment::Inside),
+            (aabb([9., -1., -11.], [11., 1., -9.]),     Containment::Intersecting),
+            (aabb([-1., -1., -2.], [1., 1., 0.]),       Containment::Intersecting),
+            (aabb([-1., 90., -200.], [1., 95., -50.]),  Containment::Intersecting),
+            (aabb([12., -1., -10.], [14., 1., -9.]),    Containment::Outside),
+            (aabb([-1., -14., -10.], [1., -12., -9.]),  Containment::Outside),
+            (aabb([-1., -1., 1.], [1., 1., 2.]),        Containment::Outside),
+            (aabb([-1., -1., -200.], [1., 1., -150.]),  Containment::Outside),
+        ];
+        for (i, (aabb, expected)) in cases.iter().enumerate() {
+            assert_eq!(frustum.classify_aabb(aabb), *expected, "case {}", i);
+        }
+    }
+
+    #[test]
+    fn planes_follow_the_view() {
+        // The camera moved 100 along x
+        let frustum = frustum(glm::translation(&glm::vec3(-100., 0., 0.)));
+        let ahead = aabb([99., -1., -11.], [101., 1., -9.]);
+        let off_to_the_left = aabb([-1., -1., -11.], [1., 1., -9.]);
+        assert_eq!(frustum.classify_aabb(&ahead), Containment::Inside);
+        assert_eq!(
+            frustum.classify_aabb(&off_to_the_left),
+            Containment::Outside
+        );
+    }
+
+    #[test]
+    fn unbounded_boxes_intersect_and_empty_ones_are_outside() {
+        let frustum = frustum(glm::identity());
+        assert_eq!(
+            frustum.classify_aabb(&Aabb::infinite()),
+            Containment::Intersecting
+        );
+        let half_open = aabb([f32::NEG_INFINITY, -1., -11.], [1., 1., -9.]);
+        assert_eq!(frustum.classify_aabb(&half_open), Containment::Intersecting);
+        assert_eq!(frustum.classify_aabb(&Aabb::empty()), Containment::Outside);
+    }
+}
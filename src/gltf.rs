@@ -0,0 +1,1026 @@
+use std::fmt;
+use std::path::Path;
+
+use crate::json::{Json, JsonError};
+use crate::material::Material;
+use crate::mesh::Mesh;
+use crate::scene_graph::{NodeId, SceneGraph, SceneNode};
+
+// Accessors without a buffer view take memory but no room in the file, so their size is capped
+const MAX_ZEROED_VALUES: usize = 1 << 24;
+
+// Reads glTF 2.0 scenes, either as .gltf JSON with separate or embedded buffers, or as .glb
+pub struct GltfReader {}
+
+#[derive(Debug)]
+pub enum GltfError {
+    Extension {
+        path: String,
+    },
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    Json {
+        path: String,
+        source: JsonError,
+    },
+    Invalid {
+        path: String,
+        reason: String,
+    },
+    Unsupported {
+        path: String,
+        what: String,
+    },
+}
+
+impl fmt::Display for GltfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GltfError::Extension { path } => write!(
+                f,
+                "{}: expected a file with the .gltf or .glb extension",
+                path
+            ),
+            GltfError::Io { path, source } => {
+                write!(f, "{}: failed to read file: {}", path, source)
+            }
+            GltfError::Json { path, source } => write!(f, "{}:{}", path, source),
+            GltfError::Invalid { path, reason } => write!(f, "{}: invalid glTF: {}", path, reason),
+            GltfError::Unsupported { path, what } => write!(f, "{}: unsupported {}", path, what),
+        }
+    }
+}
+
+impl std::error::Error for GltfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GltfError::Io { source, .. } => Some(source),
+            GltfError::Json { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+// A node of the glTF hierarchy, with its transform relative to its parent
+pub struct GltfNode {
+    pub name: String,
+    pub mesh: Option<usize>,
+    pub translation: glm::Vec3,
+    pub rotation: glm::Quat,
+    pub scale: glm::Vec3,
+    pub children: Vec<usize>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AnimatedProperty {
+    Translation,
+    Rotation,
+    Scale,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Interpolation {
+    Step,
+    Linear,
+    CubicSpline, // Every keyframe holds an in-tangent, the value and an out-tangent
+}
+
+// Keyframes for one property of one node
+pub struct AnimationChannel {
+    pub node: usize,
+    pub property: AnimatedProperty,
+    pub interpolation: Interpolation,
+    pub times: Vec<f32>,
+    pub values: Vec<f32>, // XYZ, or XYZW quaternions for rotations
+}
+
+pub struct Animation {
+    pub name: String,
+    pub channels: Vec<AnimationChannel>,
+    pub duration: f32,
+}
+
+pub struct GltfScene {
+    pub meshes: Vec<Mesh>, // The primitives of each glTF mesh merged into one
+    pub mesh_names: Vec<String>,
+    pub nodes: Vec<GltfNode>,
+    pub roots: Vec<usize>, // Nodes of the default scene
+    pub animations: Vec<Animation>,
+    pub warnings: Vec<GltfError>, // Parts of the file that were skipped
+}
+
+// The scene graph nodes made from a GltfScene, for animating them
+pub struct GltfInstance {
+    pub nodes: Vec<Option<NodeId>>, // One per glTF node, None for those outside the scene
+}
+
+/**
+ * The Euler angles for SceneNode::rotation, which rotates about X, then Y, then Z in the
+ * order of matrix multiplication, i.e. R = Rx * Ry * Rz. Near gimbal lock, Z is set to zero.
+ */
+fn euler_from_matrix(m: &glm::Mat3) -> glm::Vec3 {
+    let sin_y = m[(0, 2)].clamp(-1., 1.);
+    if sin_y.abs() < 0.9999 {
+        glm::vec3(
+            (-m[(1, 2)]).atan2(m[(2, 2)]),
+            sin_y.asin(),
+            (-m[(0, 1)]).atan2(m[(0, 0)]),
+        )
+    } else {
+        glm::vec3(m[(2, 1)].atan2(m[(1, 1)]), sin_y.asin(), 0.)
+    }
+}
+
+fn euler_from_quat(q: &glm::Quat) -> glm::Vec3 {
+    euler_from_matrix(&glm::quat_to_mat3(&glm::quat_normalize(q)))
+}
+
+// Interpolates along the shorter of the two arcs between the rotations
+fn slerp(from: &glm::Quat, to: &glm::Quat, t: f32) -> glm::Quat {
+    let to = if glm::quat_dot(from, to) < 0. {
+        -to
+    } else {
+        *to
+    };
+    glm::quat_slerp(&glm::quat_normalize(from), &glm::quat_normalize(&to), t)
+}
+
+fn decode_base64(src: &str) -> Option<Vec<u8>> {
+    let value = |c: u8| match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    };
+
+    let mut bytes = vec![];
+    let (mut bits, mut n_bits) = (0u32, 0);
+    for c in src
+        .bytes()
+        .filter(|&c| c != b'=' && !c.is_ascii_whitespace())
+    {
+        bits = (bits << 6) | value(c)? as u32;
+        n_bits += 6;
+        if n_bits >= 8 {
+            n_bits -= 8;
+            bytes.push((bits >> n_bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+// The JSON and binary buffers of a file, with helpers to read accessors out of them
+struct Document<'a> {
+    path: &'a str,
+    json: Json,
+    buffers: Vec<Vec<u8>>,
+}
+
+impl<'a> Document<'a> {
+    fn invalid(&self, reason: String) -> GltfError {
+        GltfError::Invalid {
+            path: self.path.to_string(),
+            reason,
+        }
+    }
+
+    fn unsupported(&self, what: String) -> GltfError {
+        GltfError::Unsupported {
+            path: self.path.to_string(),
+            what,
+        }
+    }
+
+    // Every component of every element, and the number of components per element
+    fn read_accessor(&self, index: usize) -> Result<(Vec<f64>, usize), GltfError> {
+        let accessor = &self.json["accessors"][index];
+        let count = accessor["count"]
+            .as_usize()
+            .ok_or_else(|| self.invalid(format!("accessor {} has no count", index)))?;
+        let components = match accessor["type"].as_str() {
+            Some("SCALAR") => 1,
+            Some("VEC2") => 2,
+            Some("VEC3") => 3,
+            Some("VEC4") | Some("MAT2") => 4,
+            Some("MAT3") => 9,
+            Some("MAT4") => 16,
+            _ => return Err(self.invalid(format!("accessor {} has an unknown type", index))),
+        };
+        if !accessor["sparse"].is_null() {
+            return Err(self.unsupported(format!("sparse accessor {}", index)));
+        }
+        let too_large = || self.invalid(format!("accessor {} is too large", index));
+        let n_values = count.checked_mul(components).ok_or_else(too_large)?;
+
+        // Accessors without a buffer view are all zeros
+        let view_index = match accessor["bufferView"].as_usize() {
+            Some(view_index) => view_index,
+            None if n_values > MAX_ZEROED_VALUES => return Err(too_large()),
+            None => return Ok((vec![0.; n_values], components)),
+        };
+
+        let component_type = accessor["componentType"].as_usize().unwrap_or(0);
+        let size = match component_type {
+            5120 | 5121 => 1,
+            5122 | 5123 => 2,
+            5125 | 5126 => 4,
+            _ => {
+                return Err(
+                    self.invalid(format!("accessor {} has an unknown component type", index))
+                )
+            }
+        };
+        let normalized = accessor["normalized"].as_bool().unwrap_or(false);
+
+        let view = &self.json["bufferViews"][view_index];
+        let buffer = view["buffer"]
+            .as_usize()
+            .and_then(|b| self.buffers.get(b))
+            .ok_or_else(|| self.invalid(format!("buffer view {} has no buffer", view_index)))?;
+        let view_start = view["byteOffset"].as_usize().unwrap_or(0);
+        let view_end = view["byteLength"]
+            .as_usize()
+            .and_then(|length| view_start.checked_add(length))
+            .filter(|&end| end <= buffer.len())
+            .ok_or_else(|| {
+                self.invalid(format!(
+                    "buffer view {} reaches past its buffer",
+                    view_index
+                ))
+            })?;
+        let start = view_start
+            .checked_add(accessor["byteOffset"].as_usize().unwrap_or(0))
+            .ok_or_else(too_large)?;
+        let stride = view["byteStride"].as_usize().unwrap_or(size * components);
+
+        // Checked before allocating, so a bogus count can't ask for more memory than the file has
+        let end = match count {
+            0 => Some(start),
+            _ => stride
+                .checked_mul(count - 1)
+                .and_then(|offset| offset.checked_add(start))
+                .and_then(|last| last.checked_add(size * components)),
+        };
+        if end.is_none_or(|end| end > view_end) {
+            return Err(self.invalid(format!("accessor {} reaches past its buffer view", index)));
+        }
+
+        let mut values = Vec::with_capacity(n_values);
+        for element in 0..count {
+            for component in 0..components {
+                let at = start + element * stride + component * size;
+                let b = &buffer[at..at + size];
+                let value = match component_type {
+                    5120 => b[0] as i8 as f64,
+                    5121 => b[0] as f64,
+                    5122 => i16::from_le_bytes([b[0], b[1]]) as f64,
+                    5123 => u16::from_le_bytes([b[0], b[1]]) as f64,
+                    5125 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    _ => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                };
+                values.push(match (normalized, component_type) {
+                    (true, 5120) => (value / 127.).max(-1.),
+                    (true, 5121) => value / 255.,
+                    (true, 5122) => (value / 32767.).max(-1.),
+                    (true, 5123) => value / 65535.,
+                    _ => value,
+                });
+            }
+        }
+
+        Ok((values, components))
+    }
+
+    fn floats(&self, index: usize) -> Result<(Vec<f32>, usize), GltfError> {
+        let (values, components) = self.read_accessor(index)?;
+        Ok((values.into_iter().map(|v| v as f32).collect(), components))
+    }
+
+    fn material(&self, index: usize) -> Material {
+        let json = &self.json["materials"][index];
+        let mut material = Material::new(json["name"].as_str().unwrap_or("material"));
+        let pbr = &json["pbrMetallicRoughness"];
+        if let Some(factor) = pbr["baseColorFactor"].as_f32s().filter(|f| f.len() == 4) {
+            material.diffuse = [factor[0], factor[1], factor[2]];
+            material.dissolve = factor[3];
+        }
+        material.diffuse_texture = pbr["baseColorTexture"]["index"]
+            .as_usize()
+            .and_then(|texture| self.json["textures"][texture]["source"].as_usize())
+            .and_then(|image| self.json["images"][image]["uri"].as_str())
+            .map(|uri| uri.to_string());
+        material
+    }
+
+    // One primitive as a Mesh, or None if it is not made of triangles
+    fn primitive(&self, mesh: usize, json: &Json) -> Result<Option<Mesh>, GltfError> {
+        let attributes = &json["attributes"];
+        let position = attributes["POSITION"].as_usize().ok_or_else(|| {
+            self.invalid(format!("mesh {} has a primitive without positions", mesh))
+        })?;
+        let (vertices, components) = self.floats(position)?;
+        if components != 3 {
+            return Err(self.invalid(format!("mesh {} has positions that are not VEC3", mesh)));
+        }
+        let n_vertices = vertices.len() / 3;
+
+        // Other attributes must have one element per position, or Mesh::merge would misalign
+        // the primitives
+        let attribute = |name: &str, types: &[usize]| -> Result<Option<_>, GltfError> {
+            let accessor = match attributes[name].as_usize() {
+                Some(accessor) => accessor,
+                None => return Ok(None),
+            };
+            let (values, components) = self.floats(accessor)?;
+            if !types.contains(&components) {
+                return Err(self.invalid(format!("mesh {} has {} of the wrong type", mesh, name)));
+            }
+            if values.len() != n_vertices * components {
+                return Err(self.invalid(format!(
+                    "mesh {} has {} {} for {} positions",
+                    mesh,
+                    values.len() / components,
+                    name,
+                    n_vertices
+                )));
+            }
+            Ok(Some((values, components)))
+        };
+
+        let corners: Vec<u32> = match json["indices"].as_usize() {
+            Some(indices) => self
+                .read_accessor(indices)?
+                .0
+                .into_iter()
+                .map(|i| i as u32)
+                .collect(),
+            None => (0..n_vertices as u32).collect(),
+        };
+        if corners.iter().any(|&i| i as usize >= n_vertices) {
+            return Err(self.invalid(format!("mesh {} has indices out of range", mesh)));
+        }
+
+        let indices: Vec<u32> = match json["mode"].as_usize().unwrap_or(4) {
+            4 => corners,
+            // Strips alternate winding, so every other triangle is flipped back
+            5 => (0..corners.len().saturating_sub(2))
+                .flat_map(|i| {
+                    if i % 2 == 0 {
+                        vec![corners[i], corners[i + 1], corners[i + 2]]
+                    } else {
+                        vec![corners[i + 1], corners[i], corners[i + 2]]
+                    }
+                })
+                .collect(),
+            6 => (1..corners.len().saturating_sub(1))
+                .flat_map(|i| vec![corners[0], corners[i], corners[i + 1]])
+                .collect(),
+            _ => return Ok(None),
+        };
+
+        let material = json["material"].as_usize().map(|m| self.material(m));
+        let base_color = material.as_ref().map_or([1.0; 4], |m| m.diffuse_color());
+
+        let mut primitive = Mesh::from_shape((vertices, indices), base_color);
+        if let Some((normals, _)) = attribute("NORMAL", &[3])? {
+            primitive.normals = normals;
+        }
+        if let Some((mut texcoords, _)) = attribute("TEXCOORD_0", &[2])? {
+            // glTF puts the origin of textures in the top left corner, OpenGL in the bottom left
+            for v in texcoords.iter_mut().skip(1).step_by(2) {
+                *v = 1. - *v;
+            }
+            primitive.texcoords = texcoords;
+        }
+        if let Some((tangents, _)) = attribute("TANGENT", &[4])? {
+            primitive.tangents = tangents;
+        }
+        // Vertex colours are multiplied with the base colour of the material
+        if let Some((colors, components)) = attribute("COLOR_0", &[3, 4])? {
+            primitive.colors = colors
+                .chunks(components)
+                .flat_map(|c| {
+                    let alpha = if components == 4 { c[3] } else { 1. };
+                    vec![
+                        c[0] * base_color[0],
+                        c[1] * base_color[1],
+                        c[2] * base_color[2],
+                        alpha * base_color[3],
+                    ]
+                })
+                .collect();
+        }
+        primitive.material = material;
+
+        Ok(Some(primitive))
+    }
+
+    fn node(&self, index: usize) -> Result<GltfNode, GltfError> {
+        let json = &self.json["nodes"][index];
+        let mut node = GltfNode {
+            name: json["name"]
+                .as_str()
+                .map_or(format!("node_{}", index), |n| n.to_string()),
+            mesh: json["mesh"].as_usize(),
+            translation: glm::zero(),
+            rotation: glm::quat_identity(),
+            scale: glm::vec3(1., 1., 1.),
+            children: json["children"]
+                .members()
+                .iter()
+                .filter_map(|c| c.as_usize())
+                .collect(),
+        };
+
+        if let Some(m) = json["matrix"].as_f32s().filter(|m| m.len() == 16) {
+            // Column major, and assumed to be made of translation, rotation and scale only
+            let matrix = glm::make_mat4(&m);
+            node.translation = glm::vec3(m[12], m[13], m[14]);
+            node.scale = glm::vec3(
+                glm::length(&glm::vec3(m[0], m[1], m[2])),
+                glm::length(&glm::vec3(m[4], m[5], m[6])),
+                glm::length(&glm::vec3(m[8], m[9], m[10])),
+            );
+            let mut rotation = glm::mat4_to_mat3(&matrix);
+            for col in 0..3 {
+                if node.scale[col] > 0. {
+                    let column = rotation.column(col) / node.scale[col];
+                    rotation.set_column(col, &column);
+                }
+            }
+            node.rotation = glm::mat3_to_quat(&rotation);
+        } else {
+            if let Some(t) = json["translation"].as_f32s().filter(|t| t.len() == 3) {
+                node.translation = glm::vec3(t[0], t[1], t[2]);
+            }
+            if let Some(r) = json["rotation"].as_f32s().filter(|r| r.len() == 4) {
+                node.rotation = glm::quat(r[0], r[1], r[2], r[3]);
+            }
+            if let Some(s) = json["scale"].as_f32s().filter(|s| s.len() == 3) {
+                node.scale = glm::vec3(s[0], s[1], s[2]);
+            }
+        }
+
+        for &child in &node.children {
+            if self.json["nodes"][child].is_null() {
+                return Err(self.invalid(format!("node {} has a missing child {}", index, child)));
+            }
+        }
+        if let Some(mesh) = node.mesh {
+            if self.json["meshes"][mesh].is_null() {
+                return Err(self.invalid(format!("node {} has a missing mesh {}", index, mesh)));
+            }
+        }
+
+        Ok(node)
+    }
+
+    fn animation(
+        &self,
+        index: usize,
+        warnings: &mut Vec<GltfError>,
+    ) -> Result<Animation, GltfError> {
+        let json = &self.json["animations"][index];
+        let mut animation = Animation {
+            name: json["name"]
+                .as_str()
+                .map_or(format!("animation_{}", index), |n| n.to_string()),
+            channels: vec![],
+            duration: 0.,
+        };
+
+        for channel in json["channels"].members() {
+            let target = &channel["target"];
+            let property = match target["path"].as_str() {
+                Some("translation") => AnimatedProperty::Translation,
+                Some("rotation") => AnimatedProperty::Rotation,
+                Some("scale") => AnimatedProperty::Scale,
+                path => {
+                    warnings.push(self.unsupported(format!(
+                        "animation of {} in {}",
+                        path.unwrap_or("nothing"),
+                        animation.name
+                    )));
+                    continue;
+                }
+            };
+            let node = match target["node"].as_usize() {
+                Some(node) if !self.json["nodes"][node].is_null() => node,
+                _ => {
+                    return Err(self.invalid(format!(
+                        "animation {} targets a missing node",
+                        animation.name
+                    )))
+                }
+            };
+
+            let sampler = &json["samplers"][channel["sampler"].as_usize().unwrap_or(usize::MAX)];
+            let (input, output) = match (sampler["input"].as_usize(), sampler["output"].as_usize())
+            {
+                (Some(input), Some(output)) => (input, output),
+                _ => {
+                    return Err(self.invalid(format!(
+                        "animation {} has a channel without a sampler",
+                        animation.name
+                    )))
+                }
+            };
+            let interpolation = match sampler["interpolation"].as_str() {
+                Some("STEP") => Interpolation::Step,
+                Some("CUBICSPLINE") => Interpolation::CubicSpline,
+                _ => Interpolation::Linear,
+            };
+
+            let (times, _) = self.floats(input)?;
+            let (values, _) = self.floats(output)?;
+            let components = if property == AnimatedProperty::Rotation {
+                4
+            } else {
+                3
+            };
+            let per_key = if interpolation == Interpolation::CubicSpline {
+                3
+            } else {
+                1
+            };
+            if times.is_empty() || values.len() != times.len() * components * per_key {
+                return Err(self.invalid(format!(
+                    "animation {} has keyframes without values",
+                    animation.name
+                )));
+            }
+
+            animation.duration = animation.duration.max(*times.last().unwrap());
+            animation.channels.push(AnimationChannel {
+                node,
+                property,
+                interpolation,
+                times,
+                values,
+            });
+        }
+
+        Ok(animation)
+    }
+}
+
+impl AnimationChannel {
+    // The value at the given time, held constant before the first and after the last keyframe
+    pub fn sample(&self, time: f32) -> Vec<f32> {
+        let n = if self.property == AnimatedProperty::Rotation {
+            4
+        } else {
+            3
+        };
+        let cubic = self.interpolation == Interpolation::CubicSpline;
+        let key = |k: usize, part: usize| {
+            let at = if cubic { (k * 3 + part) * n } else { k * n };
+            &self.values[at..at + n]
+        };
+        let value = |k: usize| key(k, 1);
+
+        let next = self.times.partition_point(|&t| t <= time);
+        if next == 0 {
+            return value(0).to_vec();
+        }
+        if next == self.times.len() {
+            return value(next - 1).to_vec();
+        }
+        let k = next - 1;
+        let dt = self.times[next] - self.times[k];
+        let s = if dt > 0. {
+            (time - self.times[k]) / dt
+        } else {
+            0.
+        };
+
+        let mut result: Vec<f32> = match self.interpolation {
+            Interpolation::Step => value(k).to_vec(),
+            Interpolation::Linear if n == 4 => {
+                let (a, b) = (value(k), value(next));
+                let slerped = slerp(
+                    &glm::quat(a[0], a[1], a[2], a[3]),
+                    &glm::quat(b[0], b[1], b[2], b[3]),
+                    s,
+                );
+                vec![slerped.i, slerped.j, slerped.k, slerped.w]
+            }
+            Interpolation::Linear => (0..n)
+                .map(|i| value(k)[i] * (1. - s) + value(next)[i] * s)
+                .collect(),
+            // Hermite spline, using the out-tangent of this key and the in-tangent of the next
+            Interpolation::CubicSpline => {
+                let (s2, s3) = (s * s, s * s * s);
+                (0..n)
+                    .map(|i| {
+                        (2. * s3 - 3. * s2 + 1.) * value(k)[i]
+                            + (s3 - 2. * s2 + s) * dt * key(k, 2)[i]
+                            + (-2. * s3 + 3. * s2) * value(next)[i]
+                            + (s3 - s2) * dt * key(next, 0)[i]
+                    })
+                    .collect()
+            }
+        };
+
+        if n == 4 {
+            let length = result.iter().map(|c| c * c).sum::<f32>().sqrt();
+            if length > 0. {
+                result.iter_mut().for_each(|c| *c /= length);
+            }
+        }
+        result
+    }
+}
+
+impl Animation {
+    // Poses the nodes of the instance at the given time, looping after the last keyframe
+    pub fn apply(&self, time: f32, instance: &GltfInstance, graph: &mut SceneGraph) {
+        let time = if self.duration > 0. {
+            time.rem_euclid(self.duration)
+        } else {
+            0.
+        };
+
+        for channel in &self.channels {
+            let node = match instance.nodes[channel.node].and_then(|id| graph.get_mut(id)) {
+                Some(node) => node,
+                None => continue,
+            };
+            let v = channel.sample(time);
+            match channel.property {
+                AnimatedProperty::Translation => node.position = glm::vec3(v[0], v[1], v[2]),
+                AnimatedProperty::Rotation => {
+                    node.rotation = euler_from_quat(&glm::quat(v[0], v[1], v[2], v[3]))
+                }
+                AnimatedProperty::Scale => node.scale = glm::vec3(v[0], v[1], v[2]),
+            }
+        }
+    }
+}
+
+impl GltfScene {
+    pub fn mesh_index(&self, name: &str) -> Option<usize> {
+        self.mesh_names.iter().position(|n| n == name)
+    }
+
+    pub fn animation(&self, name: &str) -> Option<&Animation> {
+        self.animations.iter().find(|a| a.name == name)
+    }
+
+    /**
+     * Adds nodes mirroring the glTF hierarchy to the graph, with the roots of the default scene
+     * below parent. Nodes are created by make_node, so the caller decides what each one draws,
+     * and the rest is set from the file. Can be called several times, e.g. for a fleet of
+     * helicopters. Rotations become Euler angles, and scale is kept in SceneNode::scale.
+     */
+    pub fn instantiate<F>(
+        &self,
+        graph: &mut SceneGraph,
+        parent: NodeId,
+        mut make_node: F,
+    ) -> GltfInstance
+    where
+        F: FnMut(&GltfNode) -> SceneNode,
+    {
+        let mut nodes = vec![None; self.nodes.len()];
+
+        // Parents are added before their children. A node listed twice is only added once.
+        let mut pending: Vec<(usize, NodeId)> =
+            self.roots.iter().rev().map(|&r| (r, parent)).collect();
+        while let Some((i, parent)) = pending.pop() {
+            if nodes[i].is_some() {
+                continue;
+            }
+            let gltf_node = &self.nodes[i];
+            let mut node = make_node(gltf_node);
+            node.name = gltf_node.name.clone();
+            node.position = gltf_node.translation;
+            node.rotation = euler_from_quat(&gltf_node.rotation);
+            node.scale = gltf_node.scale;
+
+            let id = graph.add_child(parent, node);
+            nodes[i] = Some(id);
+            pending.extend(gltf_node.children.iter().rev().map(|&child| (child, id)));
+        }
+
+        GltfInstance { nodes }
+    }
+}
+
+impl GltfReader {
+    pub fn read(gltf_path: &str) -> Result<GltfScene, GltfError> {
+        let path = Path::new(gltf_path);
+        let is_binary = match path.extension().and_then(|e| e.to_str()) {
+            Some("gltf") => false,
+            Some("glb") => true,
+            _ => {
+                return Err(GltfError::Extension {
+                    path: gltf_path.to_string(),
+                })
+            }
+        };
+
+        let io_error = |path: &Path, source| GltfError::Io {
+            path: path.to_string_lossy().to_string(),
+            source,
+        };
+        let bytes = std::fs::read(path).map_err(|e| io_error(path, e))?;
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+
+        GltfReader::parse_named(gltf_path, &bytes, is_binary, |uri| {
+            let buffer_path = directory.join(uri);
+            std::fs::read(&buffer_path).map_err(|e| io_error(&buffer_path, e))
+        })
+    }
+
+    #[cfg(test)]
+    pub fn parse_gltf_bytes<F>(
+        bytes: &[u8],
+        is_binary: bool,
+        load_buffer: F,
+    ) -> Result<GltfScene, GltfError>
+    where
+        F: FnMut(&str) -> Result<Vec<u8>, GltfError>,
+    {
+        GltfReader::parse_named("<source>", bytes, is_binary, load_buffer)
+    }
+
+    // A .glb file is a header followed by a JSON chunk and optionally a binary chunk
+    fn split_glb<'b>(
+        path: &str,
+        bytes: &'b [u8],
+    ) -> Result<(&'b [u8], Option<&'b [u8]>), GltfError> {
+        let invalid = |reason: &str| GltfError::Invalid {
+            path: path.to_string(),
+            reason: reason.to_string(),
+        };
+        let u32_at = |at: usize| {
+            bytes
+                .get(at..at + 4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+        };
+
+        if bytes.get(0..4) != Some(b"glTF") {
+            return Err(invalid("missing glb header"));
+        }
+        if u32_at(4) != Some(2) {
+            return Err(GltfError::Unsupported {
+                path: path.to_string(),
+                what: "glb version, expected 2".to_string(),
+            });
+        }
+
+        let mut chunks = vec![];
+        let mut at = 12;
+        while let (Some(length), Some(kind)) = (u32_at(at), u32_at(at + 4)) {
+            let data = bytes
+                .get(at + 8..at + 8 + length)
+                .ok_or_else(|| invalid("glb chunk reaches past the end of the file"))?;
+            chunks.push((kind, data));
+            at += 8 + length;
+        }
+
+        const JSON_CHUNK: usize = 0x4E4F534A;
+        const BIN_CHUNK: usize = 0x004E4942;
+        let json = chunks
+            .iter()
+            .find(|(kind, _)| *kind == JSON_CHUNK)
+            .map(|(_, data)| *data)
+            .ok_or_else(|| invalid("glb without a JSON chunk"))?;
+        let bin = chunks
+            .iter()
+            .find(|(kind, _)| *kind == BIN_CHUNK)
+            .map(|(_, data)| *data);
+        Ok((json, bin))
+    }
+
+    /**
+     * Buffers other than embedded data URIs are loaded through load_buffer, given their URI
+     * relative to the file.
+     */
+    fn parse_named<F>(
+        path: &str,
+        bytes: &[u8],
+        is_binary: bool,
+        mut load_buffer: F,
+    ) -> Result<GltfScene, GltfError>
+    where
+        F: FnMut(&str) -> Result<Vec<u8>, GltfError>,
+    {
+        let (json_bytes, bin) = if is_binary {
+            GltfReader::split_glb(path, bytes)?
+        } else {
+            (bytes, None)
+        };
+        let json_src = std::str::from_utf8(json_bytes).map_err(|_| GltfError::Invalid {
+            path: path.to_string(),
+            reason: "JSON is not UTF-8".to_string(),
+        })?;
+        let json = Json::parse(json_src).map_err(|source| GltfError::Json {
+            path: path.to_string(),
+            source,
+        })?;
+
+        let mut document = Document {
+            path,
+            json,
+            buffers: vec![],
+        };
+        if document.json["asset"]["version"].as_str() != Some("2.0") {
+            return Err(document.unsupported("glTF version, expected 2.0".to_string()));
+        }
+
+        // Only the first buffer of a .glb may leave out its URI, and refers to the binary chunk
+        let mut buffers = vec![];
+        for (i, buffer) in document.json["buffers"].members().iter().enumerate() {
+            let data = match buffer["uri"].as_str() {
+                Some(uri) if uri.starts_with("data:") => uri
+                    .split_once(',')
+                    .and_then(|(_, data)| decode_base64(data))
+                    .ok_or_else(|| {
+                        document.invalid(format!("buffer {} has a malformed data URI", i))
+                    })?,
+                Some(uri) => load_buffer(uri)?,
+                None if i == 0 && bin.is_some() => bin.unwrap().to_vec(),
+                None => return Err(document.invalid(format!("buffer {} has no data", i))),
+            };
+            if data.len() < buffer["byteLength"].as_usize().unwrap_or(0) {
+                return Err(
+                    document.invalid(format!("buffer {} is shorter than its byteLength", i))
+                );
+            }
+            buffers.push(data);
+        }
+        document.buffers = buffers;
+
+        let mut warnings = vec![];
+        let mut meshes = vec![];
+        let mut mesh_names = vec![];
+        for (i, mesh) in document.json["meshes"].members().iter().enumerate() {
+            let mut primitives = vec![];
+            for primitive in mesh["primitives"].members() {
+                match document.primitive(i, primitive)? {
+                    Some(primitive) => primitives.push(primitive),
+                    None => warnings.push(document.unsupported(format!(
+                        "primitive of mesh {} which is not made of triangles",
+                        i
+                    ))),
+                }
+            }
+            // Nothing else draws, so a mesh without triangles would be empty
+            if primitives.is_empty() {
+                return Err(document.unsupported(format!("mesh {} without triangles", i)));
+            }
+            meshes.push(Mesh::merge(primitives));
+            mesh_names.push(
+                mesh["name"]
+                    .as_str()
+                    .map_or(format!("mesh_{}", i), |n| n.to_string()),
+            );
+        }
+
+        let nodes = (0..document.json["nodes"].members().len())
+            .map(|i| document.node(i))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Without scenes, every node that is nobody's child is a root
+        let scene = document.json["scene"].as_usize().unwrap_or(0);
+        let roots: Vec<usize> = match document.json["scenes"][scene].get("nodes") {
+            Some(roots) => roots
+                .members()
+                .iter()
+                .filter_map(|r| r.as_usize())
+                .collect(),
+            None => (0..nodes.len())
+                .filter(|&i| !nodes.iter().any(|n| n.children.contains(&i)))
+                .collect(),
+        };
+        if let Some(&r) = roots.iter().find(|&&r| r >= nodes.len()) {
+            return Err(document.invalid(format!("scene {} has a missing node {}", scene, r)));
+        }
+
+        let animations = (0..document.json["animations"].members().len())
+            .map(|i| document.animation(i, &mut warnings))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(GltfScene {
+            meshes,
+            mesh_names,
+            nodes,
+            roots,
+            animations,
+            warnings,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A triangle whose buffer holds its three positions followed by extra, which the second
+    // buffer view covers. The accessors and attributes are added after those of the positions.
+    fn parse_triangle(
+        extra: &[f32],
+        accessors: &str,
+        attributes: &str,
+    ) -> Result<GltfScene, GltfError> {
+        let mut floats = vec![0., 0., 0., 1., 0., 0., 0., 1., 0.];
+        floats.extend(extra);
+        let bytes: Vec<u8> = floats.iter().flat_map(|f| f.to_le_bytes()).collect();
+        let src = format!(
+            r#"{{
+                "asset": {{ "version": "2.0" }},
+                "buffers": [{{ "uri": "triangle.bin", "byteLength": {} }}],
+                "bufferViews": [
+                    {{ "buffer": 0, "byteLength": 36 }},
+                    {{ "buffer": 0, "byteOffset": 36, "byteLength": {} }}
+                ],
+                "accessors": [
+                    {{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3" }}
+                    {}
+                ],
+                "meshes": [{{ "primitives": [{{ "attributes": {{ "POSITION": 0 {} }} }}] }}],
+                "nodes": [{{ "mesh": 0 }}]
+            }}"#,
+            bytes.len(),
+            extra.len() * 4,
+            accessors,
+            attributes
+        );
+        GltfReader::parse_gltf_bytes(src.as_bytes(), false, |_| Ok(bytes.clone()))
+    }
+
+    #[test]
+    fn reads_attributes_matching_the_positions() {
+        let scene = parse_triangle(
+            &[0., 0., 1., 0., 0., 1., 0., 0., 1.],
+            r#", { "bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC3" }"#,
+            r#", "NORMAL": 1"#,
+        )
+        .unwrap();
+        assert_eq!(scene.meshes[0].vertices.len(), 9);
+        assert_eq!(
+            scene.meshes[0].normals,
+            vec![0., 0., 1., 0., 0., 1., 0., 0., 1.]
+        );
+    }
+
+    #[test]
+    fn rejects_attributes_with_another_count_than_the_positions() {
+        let result = parse_triangle(
+            &[0., 0., 1., 0., 0., 1.],
+            r#", { "bufferView": 1, "componentType": 5126, "count": 2, "type": "VEC3" }"#,
+            r#", "NORMAL": 1"#,
+        );
+        assert!(matches!(result, Err(GltfError::Invalid { .. })));
+    }
+
+    #[test]
+    fn rejects_colors_that_are_not_vec3_or_vec4() {
+        for &(kind, count) in &[("SCALAR", 3), ("VEC2", 3)] {
+            let accessor = format!(
+                r#", {{ "bufferView": 1, "componentType": 5126, "count": {}, "type": "{}" }}"#,
+                count, kind
+            );
+            let result = parse_triangle(&[1.; 6], &accessor, r#", "COLOR_0": 1"#);
+            assert!(matches!(result, Err(GltfError::Invalid { .. })));
+        }
+    }
+
+    #[test]
+    fn rejects_meshes_without_triangles() {
+        // Three points, at the origin as their accessor has no buffer view
+        let src = r#"{
+            "asset": { "version": "2.0" },
+            "accessors": [{ "componentType": 5126, "count": 3, "type": "VEC3" }],
+            "meshes": [{ "primitives": [{ "attributes": { "POSITION": 0 }, "mode": 0 }] }]
+        }"#;
+        let points = GltfReader::parse_gltf_bytes(src.as_bytes(), false, |_| unreachable!());
+        assert!(matches!(points, Err(GltfError::Unsupported { .. })));
+    }
+
+    #[test]
+    fn rejects_huge_accessors_without_a_buffer_view() {
+        let accessor = r#", { "componentType": 5126, "count": 1000000000000, "type": "VEC3" }"#;
+        let result = parse_triangle(&[], accessor, r#", "NORMAL": 1"#);
+        assert!(matches!(result, Err(GltfError::Invalid { .. })));
+    }
+
+    #[test]
+    fn rejects_accessor_counts_past_the_buffer_view() {
+        // 2^62 VEC4 elements overflow the number of values, and 1000 don't fit in 12 bytes
+        for &count in &["4611686018427387904", "1000"] {
+            let accessor = format!(
+                r#", {{ "bufferView": 1, "componentType": 5126, "count": {}, "type": "VEC4" }}"#,
+                count
+            );
+            let result = parse_triangle(&[0.; 3], &accessor, r#", "TANGENT": 1"#);
+            assert!(matches!(result, Err(GltfError::Invalid { .. })));
+        }
+    }
+}
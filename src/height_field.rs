@@ -0,0 +1,199 @@
+use crate::bounds::Aabb;
+use crate::bvh::Bvh;
+use crate::mesh::Mesh;
+
+// The ground at a point, as seen from above
+#[derive(Clone, Copy, Debug)]
+pub struct GroundSample {
+    pub height: f32,
+    pub normal: glm::Vec3, // Always pointing up
+}
+
+/**
+ * Answers height queries on a terrain mesh, in the mesh's own coordinates. The triangles are
+ * sorted into a grid of columns over the XZ plane, each listing the triangles that cross it, so
+ * a query only tests the few triangles of one column.
+ */
+pub struct HeightField {
+    positions: Vec<glm::Vec3>,
+    normals: Vec<glm::Vec3>, // Empty if the mesh has none, then face normals are used
+    indices: Vec<u32>,
+
+    min_x: f32,
+    min_z: f32,
+    cell_size: f32,
+    columns: usize,
+    rows: usize,
+    cell_starts: Vec<u32>, // Where the triangles of each cell start in cell_triangles
+    cell_triangles: Vec<u32>,
+}
+
+impl HeightField {
+    pub fn from_mesh(mesh: &Mesh) -> HeightField {
+        let positions: Vec<glm::Vec3> = (0..mesh.vertex_count() as u32)
+            .map(|i| mesh.position(i))
+            .collect();
+        let normals = if mesh.normals.len() == mesh.vertices.len() {
+            mesh.normals
+                .chunks(3)
+                .map(|n| glm::vec3(n[0], n[1], n[2]))
+                .collect()
+        } else {
+            vec![]
+        };
+        let indices = mesh.indices.clone();
+        let n_triangles = indices.len() / 3;
+
+        // Around two triangles per cell, for a terrain spread evenly over its bounds
+        let (min, max) = (mesh.bounds.min, mesh.bounds.max);
+        let (size_x, size_z) = ((max.x - min.x).max(0.), (max.z - min.z).max(0.));
+        let area = (size_x * size_z).max(f32::EPSILON);
+        let cell_size = (area * 2. / n_triangles.max(1) as f32).sqrt().max(1e-3);
+        let columns = ((size_x / cell_size).ceil() as usize).max(1);
+        let rows = ((size_z / cell_size).ceil() as usize).max(1);
+
+        // Every cell's triangles are stored one after the other in one array, found as those
+        // crossing the box of the column, from the bottom of the terrain to the top
+        let bvh = Bvh::from_mesh(mesh);
+        let mut cell_starts = Vec::with_capacity(columns * rows + 1);
+        let mut cell_triangles = vec![];
+        cell_starts.push(0);
+        for row in 0..rows {
+            for column in 0..columns {
+                let x = min.x + column as f32 * cell_size;
+                let z = min.z + row as f32 * cell_size;
+                let column_box = Aabb {
+                    min: glm::vec3(x, min.y, z),
+                    max: glm::vec3(x + cell_size, max.y, z + cell_size),
+                };
+                let crossing = bvh.triangles_in_aabb(&column_box);
+                cell_triangles.extend(crossing.into_iter().map(|t| t as u32));
+                cell_starts.push(cell_triangles.len() as u32);
+            }
+        }
+
+        HeightField {
+            positions,
+            normals,
+            indices,
+            min_x: min.x,
+            min_z: min.z,
+            cell_size,
+            columns,
+            rows,
+            cell_starts,
+            cell_triangles,
+        }
+    }
+
+    fn corners(&self, triangle: usize) -> [glm::Vec3; 3] {
+        let i = &self.indices[triangle * 3..triangle * 3 + 3];
+        [
+            self.positions[i[0] as usize],
+            self.positions[i[1] as usize],
+            self.positions[i[2] as usize],
+        ]
+    }
+
+    fn cell_of(&self, x: f32, z: f32) -> (usize, usize) {
+        let column = ((x - self.min_x) / self.cell_size).floor().max(0.) as usize;
+        let row = ((z - self.min_z) / self.cell_size).floor().max(0.) as usize;
+        (column.min(self.columns - 1), row.min(self.rows - 1))
+    }
+
+    /**
+     * The highest ground at (x, z), or None outside the terrain. Normals are interpolated from
+     * the vertex normals where the mesh has them, so following the ground is smooth.
+     */
+    pub fn sample(&self, x: f32, z: f32) -> Option<GroundSample> {
+        let (column, row) = self.cell_of(x, z);
+        let cell = row * self.columns + column;
+        let triangles = &self.cell_triangles
+            [self.cell_starts[cell] as usize..self.cell_starts[cell + 1] as usize];
+
+        let mut best: Option<GroundSample> = None;
+        for &t in triangles {
+            let [a, b, c] = self.corners(t as usize);
+
+            // Barycentric coordinates in the XZ plane, with a little slack along the edges
+            let det = (b.z - c.z) * (a.x - c.x) + (c.x - b.x) * (a.z - c.z);
+            if det.abs() < f32::EPSILON {
+                continue;
+            }
+            let u = ((b.z - c.z) * (x - c.x) + (c.x - b.x) * (z - c.z)) / det;
+            let v = ((c.z - a.z) * (x - c.x) + (a.x - c.x) * (z - c.z)) / det;
+            let w = 1. - u - v;
+            const SLACK: f32 = -1e-5;
+            if u < SLACK || v < SLACK || w < SLACK {
+                continue;
+            }
+
+            let height = u * a.y + v * b.y + w * c.y;
+            if best.is_some_and(|best| best.height >= height) {
+                continue;
+            }
+
+            let normal = if self.normals.is_empty() {
+                glm::cross(&(b - a), &(c - a))
+            } else {
+                let i = &self.indices[t as usize * 3..t as usize * 3 + 3];
+                self.normals[i[0] as usize] * u
+                    + self.normals[i[1] as usize] * v
+                    + self.normals[i[2] as usize] * w
+            };
+            let normal = if normal.y < 0. { -normal } else { normal };
+            best = Some(GroundSample {
+                height,
+                normal: glm::normalize(&normal),
+            });
+        }
+        best
+    }
+
+    /**
+     * How high a point above (x, z) must be to keep the given clearance from the ground. On a
+     * slope the ground is nearer at a right angle than straight down, by the Y of its normal, so
+     * the height is raised to make up for it, at most fourfold on the steepest slopes.
+     */
+    pub fn clear_height(&self, x: f32, z: f32, clearance: f32) -> Option<f32> {
+        self.sample(x, z)
+            .map(|ground| ground.height + clearance / ground.normal.y.max(0.25))
+    }
+
+    // Lifts the position to at least the given clearance from the ground. Returns if it moved.
+    pub fn keep_above(&self, position: &mut glm::Vec3, clearance: f32) -> bool {
+        match self.clear_height(position.x, position.z, clearance) {
+            Some(height) if position.y < height => {
+                position.y = height;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clearance_is_kept_at_right_angles_to_slopes() {
+        // A 45 degree slope rising along X, as two triangles
+        let vertices = vec![0., 0., 0., 0., 0., 4., 4., 4., 4., 4., 4., 0.];
+        let slope = Mesh::from_shape((vertices, vec![0, 1, 2, 0, 2, 3]), [1.; 4]);
+        let field = HeightField::from_mesh(&slope);
+
+        let ground = field.sample(1., 2.).unwrap();
+        assert!((ground.height - 1.).abs() < 1e-5);
+        assert!((ground.normal - glm::vec3(-1., 1., 0.).normalize()).norm() < 1e-5);
+
+        let height = field.clear_height(1., 2., 2.).unwrap();
+        assert!((height - (1. + 2. * 2f32.sqrt())).abs() < 1e-5);
+
+        let mut position = glm::vec3(1., 0., 2.);
+        assert!(field.keep_above(&mut position, 2.));
+        assert_eq!(position.y, height);
+        assert!(!field.keep_above(&mut position, 2.));
+        assert!(field.clear_height(5., 2., 2.).is_none());
+    }
+}
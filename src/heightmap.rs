@@ -0,0 +1,211 @@
+use std::fmt;
+
+use crate::mesh::Mesh;
+
+// A grid of heights between 0 and 1, stored row by row. Rows run along Z and columns along X.
+#[derive(Clone)]
+pub struct Heightmap {
+    pub width: usize,
+    pub depth: usize,
+    pub heights: Vec<f32>,
+}
+
+#[derive(Debug)]
+pub struct HeightmapError {
+    pub path: String,
+    pub source: image::ImageError,
+}
+
+impl fmt::Display for HeightmapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: failed to read heightmap: {}",
+            self.path, self.source
+        )
+    }
+}
+
+impl std::error::Error for HeightmapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+// How a heightmap is turned into a mesh
+#[derive(Clone, Copy, Debug)]
+pub struct TerrainSettings {
+    pub spacing: f32,        // Distance between neighbouring samples along X and Z
+    pub vertical_scale: f32, // Height of a white pixel, black being 0
+    pub skirt_depth: f32,    // How far the skirt hangs below the edges, 0 for no skirt
+}
+
+// Colours at fractions of the height range, from the lowest point to the highest
+pub const TERRAIN_COLORS: [(f32, [f32; 4]); 5] = [
+    (0.0, [0.20, 0.17, 0.13, 1.0]), // Valley floors
+    (0.3, [0.42, 0.36, 0.27, 1.0]),
+    (0.6, [0.55, 0.53, 0.50, 1.0]), // Bare rock
+    (0.85, [0.72, 0.72, 0.72, 1.0]),
+    (1.0, [0.95, 0.95, 0.97, 1.0]), // Peaks
+];
+
+impl Default for TerrainSettings {
+    fn default() -> Self {
+        TerrainSettings {
+            spacing: 1.0,
+            vertical_scale: 1.0,
+            skirt_depth: 0.0,
+        }
+    }
+}
+
+impl Heightmap {
+    pub fn new(width: usize, depth: usize) -> Heightmap {
+        Heightmap {
+            width,
+            depth,
+            heights: vec![0.; width * depth],
+        }
+    }
+
+    // Any format the image crate reads, with coloured images converted to their luminance
+    pub fn load(path: &str) -> Result<Heightmap, HeightmapError> {
+        let image = image::open(path).map_err(|source| HeightmapError {
+            path: path.to_string(),
+            source,
+        })?;
+        Ok(Heightmap::from_image(&image))
+    }
+
+    // 16 bits per channel are kept, so 16 bit PNGs don't end up terraced
+    pub fn from_image(image: &image::DynamicImage) -> Heightmap {
+        let luma = image.to_luma16();
+        Heightmap {
+            width: luma.width() as usize,
+            depth: luma.height() as usize,
+            heights: luma.pixels().map(|p| p[0] as f32 / 65535.).collect(),
+        }
+    }
+
+    // Clamped to the edges, so neighbours of border samples can be asked for
+    pub fn get(&self, x: isize, z: isize) -> f32 {
+        let x = x.max(0).min(self.width as isize - 1) as usize;
+        let z = z.max(0).min(self.depth as isize - 1) as usize;
+        self.heights[z * self.width + x]
+    }
+
+    /**
+     * A grid centred on the origin in XZ, facing up, with texture coordinates spanning the whole
+     * heightmap. Normals come from central differences of the heights. The skirt is a wall
+     * around the edges, which hides the gaps between neighbouring terrain tiles or LODs.
+     */
+    pub fn to_mesh(&self, settings: &TerrainSettings) -> Mesh {
+        let (w, d) = (self.width, self.depth);
+        if w < 2 || d < 2 {
+            return Mesh::from_shape((vec![], vec![]), [1.0, 1.0, 1.0, 1.0]);
+        }
+
+        let spacing = settings.spacing;
+        let scale = settings.vertical_scale;
+        let origin_x = -((w - 1) as f32) * spacing / 2.;
+        let origin_z = -((d - 1) as f32) * spacing / 2.;
+
+        let mut vertices = Vec::with_capacity(w * d * 3);
+        let mut normals = Vec::with_capacity(w * d * 3);
+        let mut texcoords = Vec::with_capacity(w * d * 2);
+        for z in 0..d {
+            for x in 0..w {
+                let (xi, zi) = (x as isize, z as isize);
+                vertices.extend(&[
+                    origin_x + x as f32 * spacing,
+                    self.get(xi, zi) * scale,
+                    origin_z + z as f32 * spacing,
+                ]);
+
+                // Differences are halved at the edges, where one side is clamped
+                let dx = (self.get(xi + 1, zi) - self.get(xi - 1, zi)) * scale
+                    / (spacing * (x.min(1) + (w - 1 - x).min(1)) as f32);
+                let dz = (self.get(xi, zi + 1) - self.get(xi, zi - 1)) * scale
+                    / (spacing * (z.min(1) + (d - 1 - z).min(1)) as f32);
+                let normal = glm::normalize(&glm::vec3(-dx, 1., -dz));
+                normals.extend(&[normal.x, normal.y, normal.z]);
+
+                // The top row of the image is the far edge, at negative Z
+                texcoords.extend(&[x as f32 / (w - 1) as f32, 1. - z as f32 / (d - 1) as f32]);
+            }
+        }
+
+        let index = |x: usize, z: usize| (z * w + x) as u32;
+        let mut indices = Vec::with_capacity((w - 1) * (d - 1) * 6);
+        for z in 0..d - 1 {
+            for x in 0..w - 1 {
+                let (a, b) = (index(x, z), index(x + 1, z));
+                let (c, e) = (index(x, z + 1), index(x + 1, z + 1));
+                indices.extend(&[a, c, b, b, c, e]);
+            }
+        }
+
+        if settings.skirt_depth > 0. {
+            // The border walked so the outside is always on the left, seen from above
+            let border: Vec<u32> = (0..w - 1)
+                .map(|x| index(x, 0))
+                .chain((0..d - 1).map(|z| index(w - 1, z)))
+                .chain((1..w).rev().map(|x| index(x, d - 1)))
+                .chain((1..d).rev().map(|z| index(0, z)))
+                .collect();
+
+            let first_skirt = (vertices.len() / 3) as u32;
+            for &top in &border {
+                let top = top as usize;
+                vertices.extend(&[
+                    vertices[top * 3],
+                    vertices[top * 3 + 1] - settings.skirt_depth,
+                    vertices[top * 3 + 2],
+                ]);
+                normals.extend(&[normals[top * 3], normals[top * 3 + 1], normals[top * 3 + 2]]);
+                texcoords.extend(&[texcoords[top * 2], texcoords[top * 2 + 1]]);
+            }
+
+            for k in 0..border.len() {
+                let next = (k + 1) % border.len();
+                let (top, top_next) = (border[k], border[next]);
+                let (bottom, bottom_next) = (first_skirt + k as u32, first_skirt + next as u32);
+                indices.extend(&[top, top_next, bottom, top_next, bottom_next, bottom]);
+            }
+        }
+
+        let mut mesh = Mesh::from_shape((vertices, indices), [1.0, 1.0, 1.0, 1.0]);
+        mesh.normals = normals;
+        mesh.texcoords = texcoords;
+        mesh
+    }
+}
+
+impl Mesh {
+    /**
+     * Colours every vertex by where its height lies between the lowest and highest vertex,
+     * blending between the two closest stops of the gradient, which must be sorted.
+     */
+    pub fn color_by_height(&mut self, gradient: &[(f32, [f32; 4])]) {
+        let (min, max) = (self.bounds.min.y, self.bounds.max.y);
+        let range = (max - min).max(f32::EPSILON);
+        self.colors = self
+            .vertices
+            .chunks(3)
+            .flat_map(|v| {
+                let t = (v[1] - min) / range;
+                let next = gradient.iter().position(|&(stop, _)| stop >= t);
+                let color = match next {
+                    Some(0) => gradient[0].1,
+                    Some(k) => {
+                        let ((a, from), (b, to)) = (gradient[k - 1], gradient[k]);
+                        let s = (t - a) / (b - a);
+                        [0, 1, 2, 3].map(|c| from[c] + (to[c] - from[c]) * s)
+                    }
+                    None => gradient.last().map_or([1.0; 4], |&(_, color)| color),
+                };
+                color.to_vec()
+            })
+            .collect();
+    }
+}
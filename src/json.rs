@@ -0,0 +1,620 @@
+use std::fmt;
+use std::ops::Index;
+
+// A parsed JSON value. Objects keep their keys in the order they appear in the source.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+// Where parsing failed. Line and column are 1-based, and count characters.
+#[derive(Clone, Debug)]
+pub struct JsonError {
+    pub line: usize,
+    pub column: usize,
+    pub expected: &'static str,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: expected {}",
+            self.line, self.column, self.expected
+        )
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+// Returned when indexing something that is not there, so lookups can be chained
+static NULL: Json = Json::Null;
+
+// Arrays and objects nest at most this deep, as the parser recurses once per level
+const MAX_DEPTH: usize = 128;
+
+impl Json {
+    pub fn parse(src: &str) -> Result<Json, JsonError> {
+        let mut parser = JsonParser {
+            src,
+            chars: src.char_indices().peekable(),
+            depth: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        match parser.chars.peek() {
+            None => Ok(value),
+            Some(_) => Err(parser.error("end of input")),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == Json::Null
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_f32(&self) -> Option<f32> {
+        self.as_f64().map(|n| n as f32)
+    }
+
+    // Only for whole, non-negative numbers, as used for indices and counts
+    pub fn as_usize(&self) -> Option<usize> {
+        match self {
+            Json::Number(n) if *n >= 0. && n.fract() == 0. => Some(*n as usize),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    // Anything but an array is treated as empty, which suits optional arrays
+    pub fn members(&self) -> &[Json] {
+        match self {
+            Json::Array(items) => items,
+            _ => &[],
+        }
+    }
+
+    // The key and value of each member of an object, and empty for anything else
+    pub fn entries(&self) -> &[(String, Json)] {
+        match self {
+            Json::Object(members) => members,
+            _ => &[],
+        }
+    }
+
+    // The numbers of an array, if it only holds numbers
+    pub fn as_f32s(&self) -> Option<Vec<f32>> {
+        match self {
+            Json::Array(items) => items.iter().map(|item| item.as_f32()).collect(),
+            _ => None,
+        }
+    }
+}
+
+// json["key"] and json[0] give Null for missing members, instead of panicking
+impl Index<&str> for Json {
+    type Output = Json;
+    fn index(&self, key: &str) -> &Json {
+        self.get(key).unwrap_or(&NULL)
+    }
+}
+
+impl Index<usize> for Json {
+    type Output = Json;
+    fn index(&self, i: usize) -> &Json {
+        self.members().get(i).unwrap_or(&NULL)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Json {
+        Json::Bool(b)
+    }
+}
+
+impl From<f32> for Json {
+    fn from(n: f32) -> Json {
+        Json::Number(n as f64)
+    }
+}
+
+// Counts and seeds, which are exact up to 2^53
+impl From<usize> for Json {
+    fn from(n: usize) -> Json {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Json {
+        Json::String(s.to_string())
+    }
+}
+
+// An array of numbers, e.g. from a glm vector's as_slice()
+impl From<&[f32]> for Json {
+    fn from(numbers: &[f32]) -> Json {
+        Json::Array(numbers.iter().map(|&n| Json::from(n)).collect())
+    }
+}
+
+impl Json {
+    /**
+     * Like to_string, but with every member of an object or array on its own line, indented by
+     * two spaces per level. Arrays of only numbers, strings and such stay on one line, so
+     * vectors and colours read naturally.
+     */
+    pub fn pretty(&self) -> String {
+        let mut out = String::new();
+        self.write(&mut out, Some(0));
+        out
+    }
+
+    fn write(&self, out: &mut String, indent: Option<usize>) {
+        let is_flat = |items: &[Json]| {
+            items
+                .iter()
+                .all(|item| !matches!(item, Json::Array(_) | Json::Object(_)))
+        };
+        let newline = |out: &mut String, depth: usize| {
+            out.push('\n');
+            out.push_str(&"  ".repeat(depth));
+        };
+
+        match self {
+            Json::Null => out.push_str("null"),
+            Json::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            Json::Number(n) => write_number(out, *n),
+            Json::String(s) => write_string(out, s),
+            Json::Array(items) => {
+                let indent = indent.filter(|_| !is_flat(items));
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                        if indent.is_none() {
+                            out.push(' ');
+                        }
+                    }
+                    if let Some(depth) = indent {
+                        newline(out, depth + 1);
+                    }
+                    item.write(out, indent.map(|depth| depth + 1));
+                }
+                if let (Some(depth), false) = (indent, items.is_empty()) {
+                    newline(out, depth);
+                }
+                out.push(']');
+            }
+            Json::Object(members) => {
+                out.push('{');
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    match indent {
+                        Some(depth) => newline(out, depth + 1),
+                        None if i > 0 => out.push(' '),
+                        None => {}
+                    }
+                    write_string(out, key);
+                    out.push_str(": ");
+                    value.write(out, indent.map(|depth| depth + 1));
+                }
+                if let (Some(depth), false) = (indent, members.is_empty()) {
+                    newline(out, depth);
+                }
+                out.push('}');
+            }
+        }
+    }
+}
+
+// JSON has no infinities or NaN, so they become null
+fn write_number(out: &mut String, n: f64) {
+    if !n.is_finite() {
+        out.push_str("null");
+    } else if n.fract() == 0. && n.abs() < 1e15 {
+        out.push_str(&(n as i64).to_string());
+    } else if (n as f32) as f64 == n {
+        // Numbers that came from an f32 are written as such, i.e. 0.3 rather than 0.30000001...
+        write_digits(out, n as f32, n);
+    } else {
+        write_digits(out, n, n);
+    }
+}
+
+// Very large and very small numbers get an exponent, rather than hundreds of zeros
+fn write_digits<T: fmt::Display + fmt::LowerExp>(out: &mut String, digits: T, n: f64) {
+    if n.abs() >= 1e15 || n.abs() < 1e-6 {
+        out.push_str(&format!("{:e}", digits));
+    } else {
+        out.push_str(&digits.to_string());
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+// Compact, on a single line
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut out = String::new();
+        self.write(&mut out, None);
+        f.write_str(&out)
+    }
+}
+
+struct JsonParser<'a> {
+    src: &'a str,
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+    depth: usize, // Of the array or object being parsed
+}
+
+impl<'a> JsonParser<'a> {
+    // Points at the next character, or the end of the input
+    fn error(&mut self, expected: &'static str) -> JsonError {
+        let offset = self.chars.peek().map_or(self.src.len(), |&(i, _)| i);
+        let before = &self.src[..offset];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        JsonError {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            expected,
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(&(_, c)) = self.chars.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.chars.next();
+        }
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        if self.chars.peek().map(|&(_, c)| c) == Some(expected) {
+            self.chars.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_word(&mut self, word: &'static str, value: Json) -> Result<Json, JsonError> {
+        for expected in word.chars() {
+            if !self.eat(expected) {
+                return Err(self.error(word));
+            }
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, JsonError> {
+        self.skip_whitespace();
+        match self.chars.peek().map(|&(_, c)| c) {
+            Some('{') | Some('[') => {
+                if self.depth == MAX_DEPTH {
+                    return Err(self.error("less deeply nested arrays and objects"));
+                }
+                self.depth += 1;
+                let value = match self.chars.next() {
+                    Some((_, '{')) => self.object(),
+                    _ => self.array(),
+                };
+                self.depth -= 1;
+                value
+            }
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some('t') => self.expect_word("true", Json::Bool(true)),
+            Some('f') => self.expect_word("false", Json::Bool(false)),
+            Some('n') => self.expect_word("null", Json::Null),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            _ => Err(self.error("a value")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, JsonError> {
+        let mut members = vec![];
+        self.skip_whitespace();
+        if self.eat('}') {
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.chars.peek().map(|&(_, c)| c) != Some('"') {
+                return Err(self.error("a key"));
+            }
+            let key = self.string()?;
+            self.skip_whitespace();
+            if !self.eat(':') {
+                return Err(self.error("`:`"));
+            }
+            members.push((key, self.value()?));
+            self.skip_whitespace();
+            if self.eat('}') {
+                return Ok(Json::Object(members));
+            }
+            if !self.eat(',') {
+                return Err(self.error("`,` or `}`"));
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, JsonError> {
+        let mut items = vec![];
+        self.skip_whitespace();
+        if self.eat(']') {
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            if self.eat(']') {
+                return Ok(Json::Array(items));
+            }
+            if !self.eat(',') {
+                return Err(self.error("`,` or `]`"));
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self.chars.peek().and_then(|&(_, c)| c.to_digit(16));
+            match digit {
+                Some(d) => {
+                    self.chars.next();
+                    code = code * 16 + d;
+                }
+                None => return Err(self.error("four hex digits")),
+            }
+        }
+        Ok(code)
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.chars.next();
+        let mut s = String::new();
+        loop {
+            let c = match self.chars.peek() {
+                Some(&(_, c)) => c,
+                None => return Err(self.error("`\"`")),
+            };
+            match c {
+                '"' => {
+                    self.chars.next();
+                    return Ok(s);
+                }
+                '\\' => {
+                    self.chars.next();
+                    let escaped = self.chars.peek().map(|&(_, c)| c);
+                    let unescaped = match escaped {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => {
+                            self.chars.next();
+                            let mut code = self.hex4()?;
+                            // Characters outside the basic plane are written as surrogate pairs
+                            if (0xD800..0xDC00).contains(&code) {
+                                if !(self.eat('\\') && self.eat('u')) {
+                                    return Err(self.error("a low surrogate"));
+                                }
+                                let low = self.hex4()?;
+                                if !(0xDC00..0xE000).contains(&low) {
+                                    return Err(self.error("a low surrogate"));
+                                }
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                            }
+                            s.push(std::char::from_u32(code).unwrap_or('\u{FFFD}'));
+                            continue;
+                        }
+                        _ => return Err(self.error("an escape sequence")),
+                    };
+                    self.chars.next();
+                    s.push(unescaped);
+                }
+                c if (c as u32) < 0x20 => return Err(self.error("`\"`")),
+                c => {
+                    self.chars.next();
+                    s.push(c);
+                }
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, JsonError> {
+        let start = self.chars.peek().map_or(self.src.len(), |&(i, _)| i);
+        while let Some(&(_, c)) = self.chars.peek() {
+            if !(c.is_ascii_digit() || c == '-' || c == '+' || c == '.' || c == 'e' || c == 'E') {
+                break;
+            }
+            self.chars.next();
+        }
+        let end = self.chars.peek().map_or(self.src.len(), |&(i, _)| i);
+        let number = &self.src[start..end];
+        if !is_json_number(number) {
+            return Err(self.error("a number"));
+        }
+        // Too large for an f64, e.g. 1e400
+        match number.parse::<f64>() {
+            Ok(n) if n.is_finite() => Ok(Json::Number(n)),
+            _ => Err(self.error("a smaller number")),
+        }
+    }
+}
+
+// An optional minus, an integer part without leading zeros, and optionally a fraction and an
+// exponent, which is stricter than what Rust parses, e.g. "01", "1." and ".5" are not allowed
+fn is_json_number(number: &str) -> bool {
+    let digits = |s: &str| s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let mut rest = number.strip_prefix('-').unwrap_or(number);
+
+    let integer = digits(rest);
+    if integer == 0 || (integer > 1 && rest.starts_with('0')) {
+        return false;
+    }
+    rest = &rest[integer..];
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let n = digits(fraction);
+        if n == 0 {
+            return false;
+        }
+        rest = &fraction[n..];
+    }
+    if let Some(exponent) = rest.strip_prefix(|c| c == 'e' || c == 'E') {
+        let exponent = exponent
+            .strip_prefix(|c| c == '+' || c == '-')
+            .unwrap_or(exponent);
+        let n = digits(exponent);
+        if n == 0 {
+            return false;
+        }
+        rest = &exponent[n..];
+    }
+    rest.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_at(src: &str) -> (usize, usize, &'static str) {
+        let error = Json::parse(src).unwrap_err();
+        (error.line, error.column, error.expected)
+    }
+
+    #[test]
+    fn parses_nested_values_in_order() {
+        let json = Json::parse(r#"{ "b": [1, true, null], "a": { "c": "d" }, "e": [] }"#).unwrap();
+        let keys: Vec<&str> = json.entries().iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["b", "a", "e"]);
+        assert_eq!(json["b"][0].as_usize(), Some(1));
+        assert_eq!(json["b"][1].as_bool(), Some(true));
+        assert!(json["b"][2].is_null());
+        assert_eq!(json["a"]["c"].as_str(), Some("d"));
+        assert!(json["e"].members().is_empty());
+        assert!(json["missing"][3]["deeper"].is_null());
+    }
+
+    #[test]
+    fn parses_escapes_and_surrogate_pairs() {
+        let json = Json::parse(r#""\"\\\/\b\f\n\r\t\u00e9\ud83d\ude81""#).unwrap();
+        assert_eq!(json.as_str(), Some("\"\\/\u{8}\u{c}\n\r\té🚁"));
+
+        assert_eq!(error_at(r#""\ud83d""#), (1, 8, "a low surrogate"));
+        assert_eq!(error_at(r#""\ud83d\u0041""#), (1, 14, "a low surrogate"));
+        assert_eq!(error_at(r#""\u12x4""#), (1, 6, "four hex digits"));
+        assert_eq!(error_at(r#""\q""#), (1, 3, "an escape sequence"));
+        assert_eq!(error_at("\"tab\there\""), (1, 5, "`\"`"));
+        assert_eq!(error_at(r#""open"#), (1, 6, "`\"`"));
+    }
+
+    #[test]
+    fn parses_numbers_as_json_writes_them() {
+        let cases = [
+            ("0", 0.),
+            ("-0.5", -0.5),
+            ("12.25e1", 122.5),
+            ("1E-2", 0.01),
+            ("-3e+2", -300.),
+        ];
+        for &(src, n) in &cases {
+            assert_eq!(Json::parse(src).unwrap().as_f64(), Some(n), "{}", src);
+        }
+        for src in &[
+            "01", "1.", ".5", "-", "1e", "1e+", "+1", "1.2.3", "--1", "0x10",
+        ] {
+            assert!(Json::parse(src).is_err(), "{} should not parse", src);
+        }
+        assert_eq!(error_at("[1e400]").2, "a smaller number");
+
+        assert_eq!(Json::parse("-2.5").unwrap().as_usize(), None);
+        assert_eq!(Json::parse("7").unwrap().as_usize(), Some(7));
+    }
+
+    #[test]
+    fn errors_point_at_what_went_wrong() {
+        assert_eq!(error_at("{\n  \"a\": 1,\n  \"b\" 2\n}"), (3, 7, "`:`"));
+        assert_eq!(error_at("[1, 2"), (1, 6, "`,` or `]`"));
+        assert_eq!(error_at("{ \"a\": 1 \"b\": 2 }"), (1, 10, "`,` or `}`"));
+        assert_eq!(error_at("{ a: 1 }"), (1, 3, "a key"));
+        assert_eq!(error_at("[tru]"), (1, 5, "true"));
+        assert_eq!(error_at("1 2"), (1, 3, "end of input"));
+        assert_eq!(error_at(""), (1, 1, "a value"));
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error_rather_than_a_stack_overflow() {
+        let nested = |depth: usize| "[".repeat(depth) + &"]".repeat(depth);
+        assert!(Json::parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(
+            error_at(&nested(MAX_DEPTH + 1)),
+            (1, MAX_DEPTH + 1, "less deeply nested arrays and objects")
+        );
+        assert!(Json::parse(&"{\"a\": ".repeat(1_000_000)).is_err());
+    }
+
+    #[test]
+    fn written_json_parses_back_the_same() {
+        let src = r#"{ "name": "a \"quoted\" name\n", "vector": [0.3, -1, 1e-7, 1e20],
+                       "nested": [{ "empty": {} }, []], "flags": [true, false, null] }"#;
+        let json = Json::parse(src).unwrap();
+        assert_eq!(Json::parse(&json.pretty()).unwrap(), json);
+        assert_eq!(Json::parse(&json.to_string()).unwrap(), json);
+        assert!(json.pretty().contains("\"vector\": [0.3, -1, 1e-7, 1e20]"));
+        assert_eq!(Json::from(f32::NAN).to_string(), "null");
+    }
+}
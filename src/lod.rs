@@ -0,0 +1,100 @@
+use crate::mesh::Mesh;
+
+// A simplified version of a mesh, and how small it must look before it is used
+pub struct LodLevel {
+    pub mesh: Mesh,
+    pub max_screen_size: f32, // Fraction of the screen height covered by the bounds
+}
+
+// Levels of detail of a mesh, from the full mesh to the coarsest
+pub struct LodChain {
+    pub levels: Vec<LodLevel>,
+}
+
+impl LodChain {
+    /**
+     * The first level is the mesh itself, used at any size. Each further level has `reduction`
+     * times the triangles of the one before, and takes over once the mesh covers less than
+     * `first_switch` of the screen height, shrinking by the square root of `reduction` for each
+     * level so the triangles keep about the same size on screen. Stops early if a level
+     * cannot be simplified any further.
+     */
+    pub fn build(mesh: &Mesh, levels: usize, reduction: f32, first_switch: f32) -> LodChain {
+        let mut chain = vec![LodLevel {
+            mesh: mesh.clone(),
+            max_screen_size: f32::INFINITY,
+        }];
+
+        let mut max_screen_size = first_switch;
+        while chain.len() < levels {
+            let previous = &chain[chain.len() - 1].mesh;
+            let previous_triangles = previous.indices.len() / 3;
+            let target = (previous_triangles as f32 * reduction) as usize;
+            let simplified = previous.simplify(target, f32::INFINITY);
+            if simplified.indices.len() / 3 >= previous_triangles {
+                break;
+            }
+
+            chain.push(LodLevel {
+                mesh: simplified,
+                max_screen_size,
+            });
+            max_screen_size *= reduction.sqrt();
+        }
+
+        LodChain { levels: chain }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 16 by 16 grid of unit quads on a gentle dome, so there is something to simplify
+    fn dome() -> Mesh {
+        let n = 16;
+        let mut vertices = vec![];
+        for y in 0..=n {
+            for x in 0..=n {
+                let (x, y) = (x as f32, y as f32);
+                let z = -0.01 * ((x - 8.) * (x - 8.) + (y - 8.) * (y - 8.));
+                vertices.extend(&[x, y, z]);
+            }
+        }
+        let mut indices = vec![];
+        for y in 0..n {
+            for x in 0..n {
+                let i = y * (n + 1) + x;
+                indices.extend(&[i, i + 1, i + n + 2, i, i + n + 2, i + n + 1]);
+            }
+        }
+        Mesh::from_shape((vertices, indices), [1., 1., 1., 1.])
+    }
+
+    #[test]
+    fn every_level_halves_the_triangles() {
+        let mesh = dome();
+        let chain = LodChain::build(&mesh, 3, 0.5, 0.25);
+
+        assert_eq!(chain.levels.len(), 3);
+        assert_eq!(chain.levels[0].mesh.indices, mesh.indices);
+        for pair in chain.levels.windows(2) {
+            let (finer, coarser) = (&pair[0].mesh, &pair[1].mesh);
+            assert!(coarser.indices.len() / 3 <= finer.indices.len() / 3 / 2);
+        }
+
+        let sizes: Vec<f32> = chain.levels.iter().map(|l| l.max_screen_size).collect();
+        assert_eq!(sizes[..2], [f32::INFINITY, 0.25]);
+        assert!((sizes[2] - 0.25 * 0.5f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn chains_stop_once_nothing_is_left_to_simplify() {
+        // A single triangle only has boundary vertices, which are never collapsed
+        let triangle = Mesh::from_shape(
+            (vec![0., 0., 0., 1., 0., 0., 0., 1., 0.], vec![0, 1, 2]),
+            [1., 1., 1., 1.],
+        );
+        assert_eq!(LodChain::build(&triangle, 4, 0.5, 0.25).levels.len(), 1);
+    }
+}
@@ -8,38 +8,75 @@
 #![allow(unused_variables)]
 */
 extern crate nalgebra_glm as glm;
-use std::mem::ManuallyDrop;
-use std::pin::Pin;
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::{mem, os::raw::c_void, ptr};
 
+mod bounds;
+mod bvh;
+mod erosion;
+mod frustum;
+mod gltf;
+mod height_field;
+mod heightmap;
+mod json;
+mod lod;
+mod material;
 mod mesh;
+mod mesh_cache;
+mod model;
+mod noise;
+mod normals;
 mod obj_reader;
+mod obj_writer;
+mod picking;
+mod ply_reader;
+mod scene_file;
 mod scene_graph;
 mod shader;
 mod shape_generator;
+mod simplify;
+mod stl_reader;
+mod tangents;
 mod toolbox;
 mod util;
+mod validation;
 
 use glm::pi;
 use glutin::event::{
     DeviceEvent,
     ElementState::{Pressed, Released},
-    Event, KeyboardInput,
+    Event, KeyboardInput, MouseButton,
     VirtualKeyCode::{self, *},
     WindowEvent,
 };
+use frustum::{Containment, Frustum};
 use glutin::event_loop::ControlFlow;
 use obj_reader::ObjReader;
+use obj_writer::ObjWriter;
+use picking::Ray;
 use rand::Rng;
-use scene_graph::SceneNode;
+use scene_graph::{NodeId, SceneGraph, SceneNode};
 use shape_generator::ShapeGenerator;
 
 // initial window size
 const INITIAL_SCREEN_W: u32 = 800;
 const INITIAL_SCREEN_H: u32 = 600;
 
+// Helicopters fly at this altitude, unless that would bring them closer to the ground than the
+// clearance. The camera is kept above the ground too.
+const HELICOPTER_ALTITUDE: f32 = 0.;
+const HELICOPTER_CLEARANCE: f32 = 3.;
+const CAMERA_CLEARANCE: f32 = 1.;
+
+// The scene is built from the file given on the command line, or else the first file, and saved
+// to the second when F5 is pressed. E.g. `cargo run -- ./resources/scene_noise.json` flies over
+// generated terrain instead of the lunar surface.
+const SCENE_PATH: &str = "./resources/scene.json";
+const SAVED_SCENE_PATH: &str = "./resources/scene_saved.json";
+// The mesh last clicked on is written as an .obj file to this directory when F6 is pressed
+const EXPORT_DIR: &str = "./exported";
+
 // == // Helper functions to make interacting with OpenGL a little bit prettier. You *WILL* need these! // == //
 
 // Get the size of an arbitrary array of numbers measured in bytes
@@ -56,6 +93,7 @@ fn pointer_to_array<T>(val: &[T]) -> *const c_void {
 
 // Get the size of the given type in bytes
 // Example usage:  size_of::<u64>()
+#[allow(dead_code)]
 fn size_of<T>() -> i32 {
     mem::size_of::<T>() as i32
 }
@@ -74,11 +112,11 @@ struct Position {
     yaw: f32,
 }
 
-struct HelicopterNode {
-    body: ManuallyDrop<Pin<Box<SceneNode>>>,
-    door: ManuallyDrop<Pin<Box<SceneNode>>>,
-    main_rotor: ManuallyDrop<Pin<Box<SceneNode>>>,
-    tail_rotor: ManuallyDrop<Pin<Box<SceneNode>>>,
+// How many of the drawable nodes were drawn in a frame, and how many were culled as off screen
+#[derive(Default)]
+struct DrawCounts {
+    drawn: usize,
+    culled: usize,
 }
 
 static mut uniform_time_location: i32 = 0;
@@ -90,10 +128,12 @@ static mut uniform_normal_matrix_location: i32 = 0;
 
 // == // Generate your VAO here
 unsafe fn create_vao(
-    vertices: &Vec<f32>,
-    indices: &Vec<u32>,
-    colors: &Vec<f32>,
-    normals: &Vec<f32>,
+    vertices: &[f32],
+    indices: &[u32],
+    colors: &[f32],
+    normals: &[f32],
+    texcoords: &[f32],
+    tangents: &[f32],
 ) -> u32 {
     // Generate a VAO and bind it
     let n_vao: gl::types::GLsizei = 1;
@@ -124,7 +164,7 @@ unsafe fn create_vao(
         3,
         gl::FLOAT,
         gl::FALSE,
-        0 * size_of::<f32>(),
+        0,
         offset::<f32>(0),
     );
     gl::EnableVertexAttribArray(vap_index);
@@ -151,7 +191,7 @@ unsafe fn create_vao(
         4,
         gl::FLOAT,
         gl::FALSE,
-        0 * size_of::<f32>(),
+        0,
         offset::<f32>(0),
     );
     gl::EnableVertexAttribArray(vap_index + 1);
@@ -159,7 +199,7 @@ unsafe fn create_vao(
     // TASK 3.1 b)
 
     // Generate a NBO for colors and bind it
-    if normals.len() > 0 {
+    if !normals.is_empty() {
         let n_nbo = 1;
         let mut nbo_ids = 0;
 
@@ -180,12 +220,68 @@ unsafe fn create_vao(
             3,
             gl::FLOAT,
             gl::FALSE,
-            0 * size_of::<f32>(),
+            0,
             offset::<f32>(0),
         );
         gl::EnableVertexAttribArray(vap_index + 2);
     }
 
+    // Generate a TBO for texture coordinates and bind it
+    if !texcoords.is_empty() {
+        let n_tbo = 1;
+        let mut tbo_ids = 0;
+
+        gl::GenBuffers(n_tbo, &mut tbo_ids);
+        gl::BindBuffer(gl::ARRAY_BUFFER, tbo_ids);
+
+        // Fill TBO with data
+        gl::BufferData(
+            gl::ARRAY_BUFFER,
+            byte_size_of_array(texcoords),
+            pointer_to_array(texcoords),
+            gl::STATIC_DRAW,
+        );
+
+        // Configure a VAP for the texture coordinate and enable it
+        gl::VertexAttribPointer(
+            vap_index + 3,
+            2,
+            gl::FLOAT,
+            gl::FALSE,
+            0,
+            offset::<f32>(0),
+        );
+        gl::EnableVertexAttribArray(vap_index + 3);
+    }
+
+    // Generate a buffer for tangents (with handedness in w) and bind it
+    if !tangents.is_empty() {
+        let n_tangent_bo = 1;
+        let mut tangent_bo_ids = 0;
+
+        gl::GenBuffers(n_tangent_bo, &mut tangent_bo_ids);
+        gl::BindBuffer(gl::ARRAY_BUFFER, tangent_bo_ids);
+
+        // Fill tangent buffer with data
+        gl::BufferData(
+            gl::ARRAY_BUFFER,
+            byte_size_of_array(tangents),
+            pointer_to_array(tangents),
+            gl::STATIC_DRAW,
+        );
+
+        // Configure a VAP for the tangent and enable it
+        gl::VertexAttribPointer(
+            vap_index + 4,
+            4,
+            gl::FLOAT,
+            gl::FALSE,
+            0,
+            offset::<f32>(0),
+        );
+        gl::EnableVertexAttribArray(vap_index + 4);
+    }
+
     // Generate a IBO and bind it
     let n_ibo = 1;
     let mut ibo_ids = 0;
@@ -231,6 +327,11 @@ fn main() {
     // Make a reference of this tuple to send to the render thread
     let mouse_delta = Arc::clone(&arc_mouse_delta);
 
+    // Set up shared tuple for tracking the cursor position in pixels, and left clicks
+    let arc_cursor = Arc::new(Mutex::new((0f32, 0f32, false)));
+    // Make a reference of this tuple to send to the render thread
+    let cursor = Arc::clone(&arc_cursor);
+
     // Set up shared tuple for tracking changes to the window size
     let arc_window_size = Arc::new(Mutex::new((INITIAL_SCREEN_W, INITIAL_SCREEN_H, false)));
     // Make a reference of this tuple to send to the render thread
@@ -291,7 +392,9 @@ fn main() {
         // let (verticies, indicies) = ShapeGenerator::generate_circle(20, 0.5);
         // let (verticies, indicies) = ShapeGenerator::generate_spiral(50, 0.05, 3., 1., 0.15);
         // let (verticies, indicies) = ShapeGenerator::generate_square(2.0);
-        // let (verticies, indicies) = ObjReader::read("./resources/monke.obj");
+        // let monke = ObjReader::read("./resources/monke.obj", obj_reader::ParseMode::Lenient)
+        //     .unwrap_or_else(|e| panic!("{}", e));
+        // let (verticies, indicies) = (monke.vertices, monke.indices);
         // let (verticies, indicies) = ShapeGenerator::generate_sine(100, 2., 0.19);
 
         // TASK 2.1 b)
@@ -333,73 +436,138 @@ fn main() {
         // let colors = vec![1., 0., 0., 1., 0., 1., 0., 1., 0., 0., 1., 1.];
 
         // TASK 3.1 a)
-        let terrain_mesh = mesh::Terrain::load("./resources/lunarsurface.obj");
-
-        unsafe fn create_vao_from_mesh(m: &mesh::Mesh) -> u32 {
-            create_vao(&m.vertices, &m.indices, &m.colors, &m.normals)
+        // The terrain comes from the scene file, loaded below, which can build it from a
+        // grayscale heightmap or generate it from seeded noise weathered by erosion.
+
+        // Meshes without normals would be lit as black, so they are generated if missing.
+        // Broken meshes could read outside the buffers on the GPU, so they are repaired first,
+        // and meshes with nothing left to draw get no VAO, i.e. 0.
+        unsafe fn create_vao_from_mesh(m: &mut mesh::Mesh) -> u32 {
+            let issues = m.validate();
+            if issues.iter().any(|issue| issue.is_fatal()) {
+                for issue in &issues {
+                    println!("Mesh issue: {}", issue);
+                }
+                match m.repair(0.) {
+                    Ok(summary) => println!(
+                        "Repaired mesh: welded {} vertices, removed {} vertices and {} triangles",
+                        summary.welded_vertices, summary.removed_vertices, summary.removed_triangles
+                    ),
+                    Err(issue) => {
+                        println!("Skipped mesh: {}", issue);
+                        return 0;
+                    }
+                }
+            }
+            m.ensure_normals();
+            // Textured meshes get tangents too, for normal mapping
+            if m.tangents.is_empty() && !m.texcoords.is_empty() {
+                if let Err(e) = m.compute_tangents() {
+                    println!("Mesh issue: {}", e);
+                }
+            }
+            create_vao(
+                &m.vertices,
+                &m.indices,
+                &m.colors,
+                &m.normals,
+                &m.texcoords,
+                &m.tangents,
+            )
         }
 
-        let terrain_vao = unsafe { create_vao_from_mesh(&terrain_mesh) };
-
         // let colors = vec![0.5, 0.5, 0.5, 1.];
         // let normals = Vec::new();
-        // let my_vao = unsafe { create_vao(&verticies, &indicies, &colors, &normals) };
+        // let my_vao = unsafe {
+        //     create_vao(&verticies, &indicies, &colors, &normals, &Vec::new(), &Vec::new())
+        // };
 
         // TASK 3.2 a)
         // TASK 3.2 b)
 
-        let mut terrain_node = SceneNode::from_vao(terrain_vao, terrain_mesh.indices.len() as i32);
-
-        let helicopter_mesh = mesh::Helicopter::load("./resources/helicopter.obj");
-
-        let heli_body_vao = unsafe { create_vao_from_mesh(&helicopter_mesh.body) };
-        let heli_door_vao = unsafe { create_vao_from_mesh(&helicopter_mesh.door) };
-        let heli_main_rotor_vao = unsafe { create_vao_from_mesh(&helicopter_mesh.main_rotor) };
-        let heli_tail_rotor_vao = unsafe { create_vao_from_mesh(&helicopter_mesh.tail_rotor) };
-
-        // Create array of helicopters from vao
-        let mut helicopter_nodes: Vec<HelicopterNode> = Vec::new();
-        for _ in 0..5 {
-            let mut heli_body_node =
-                SceneNode::from_vao(heli_body_vao, helicopter_mesh.body.indices.len() as i32);
-            let mut heli_door_node =
-                SceneNode::from_vao(heli_door_vao, helicopter_mesh.door.indices.len() as i32);
-            let mut heli_main_rotor_node = SceneNode::from_vao(
-                heli_main_rotor_vao,
-                helicopter_mesh.main_rotor.indices.len() as i32,
-            );
-            let mut heli_tail_rotor_node = SceneNode::from_vao(
-                heli_tail_rotor_vao,
-                helicopter_mesh.tail_rotor.indices.len() as i32,
-            );
-            heli_tail_rotor_node.reference_point = glm::vec3(0.35, 2.3, 10.4);
-
-            heli_body_node.add_child(&mut heli_door_node);
-            heli_body_node.add_child(&mut heli_main_rotor_node);
-            heli_body_node.add_child(&mut heli_tail_rotor_node);
-
-            helicopter_nodes.push(HelicopterNode {
-                body: heli_body_node,
-                door: heli_door_node,
-                main_rotor: heli_main_rotor_node,
-                tail_rotor: heli_tail_rotor_node,
-            });
+        // Coarser versions of a mesh, as (VAO, index count, max screen size) for SceneNode::add_lod
+        unsafe fn create_lod_vaos(m: &mesh::Mesh) -> Vec<(u32, i32, f32)> {
+            lod::LodChain::build(m, 4, 0.5, 0.5)
+                .levels
+                .iter_mut()
+                .skip(1)
+                .map(|level| {
+                    let vao = create_vao_from_mesh(&mut level.mesh);
+                    (vao, level.mesh.index_count, level.max_screen_size)
+                })
+                .filter(|&(vao, _, _)| vao != 0)
+                .collect()
         }
 
-        helicopter_nodes
-            .iter()
-            .for_each(|h| terrain_node.add_child(&h.body));
-
-        let mut scene_node = SceneNode::new();
-        scene_node.add_child(&terrain_node);
+        // The scene, from the terrain and the helicopters to the camera, lights and shaders,
+        // comes from the scene file, so it can be changed without recompiling
+        let scene_path = std::env::args().nth(1).unwrap_or_else(|| SCENE_PATH.to_string());
+        let mut scene = scene_file::SceneFile::read(&scene_path)
+            .unwrap_or_else(|e| panic!("{}", e));
+        let mut scene_meshes = scene.load_meshes().unwrap_or_else(|e| panic!("{}", e));
+        let scene_vaos: Vec<_> = scene_meshes
+            .iter_mut()
+            .zip(&scene.meshes)
+            .map(|(mesh, description)| unsafe {
+                let vao = create_vao_from_mesh(mesh);
+                let lods = if description.lods && vao != 0 {
+                    create_lod_vaos(mesh)
+                } else {
+                    vec![]
+                };
+                (vao, lods)
+            })
+            .collect();
+        // For picking and keeping the camera out of the helicopters, which test a mesh's
+        // triangles through its bounding volume hierarchy
+        let scene_bvhs: Vec<bvh::Bvh> = scene_meshes.iter().map(bvh::Bvh::from_mesh).collect();
+        println!(
+            "Built bounding volume hierarchies over {} triangles",
+            scene_bvhs.iter().map(|bvh| bvh.triangle_count()).sum::<usize>()
+        );
+        // glTF rigs and their animations, e.g. placing the rotors of the helicopters
+        let scene_models = scene.load_models().unwrap_or_else(|e| panic!("{}", e));
+
+        let mut scene_graph = SceneGraph::new();
+        let scene_root = scene_graph.root();
+        let make_node = |mesh: usize| {
+            let (vao, lods) = &scene_vaos[mesh];
+            let mut node = SceneNode::from_mesh(*vao, &scene_meshes[mesh]);
+            for &(vao, index_count, max_screen_size) in lods {
+                node.add_lod(vao, index_count, max_screen_size);
+            }
+            node
+        };
+        let scene_instance =
+            scene.instantiate(&mut scene_graph, scene_root, &scene_models, make_node);
+
+        // Helicopters and the camera are kept above the terrain mesh, as long as its node isn't
+        // moved, and helicopters are found by their tag
+        let terrain_ground = scene
+            .mesh_index("terrain")
+            .map(|mesh| height_field::HeightField::from_mesh(&scene_meshes[mesh]));
+        let helicopters: Vec<NodeId> = scene_graph.tagged("helicopter").collect();
+
+        // PLY and STL files become meshes the same way, e.g. for a scan or a CAD part:
+        // let mut scan_mesh = ply_reader::PlyReader::read("./resources/scan.ply")
+        //     .unwrap_or_else(|e| panic!("{}", e));
+        // let scan_vao = unsafe { create_vao_from_mesh(&mut scan_mesh) };
+        // let scan_node =
+        //     scene_graph.add_child(scene_root, SceneNode::from_mesh(scan_vao, &scan_mesh));
+
+        // Nodes can also be reached by name, e.g. to open the door of the third helicopter:
+        // let door = scene_graph.find("terrain/heli_2/body/door").unwrap();
+        // or all of them at once, with scene_graph.query("terrain/heli_*/body/door"),
+        // and scene_graph.print() shows the names of the whole tree
 
         // == // Set up your shaders here
 
         // Create shader object
         let simple_shader = unsafe {
-            shader::ShaderBuilder::new()
-                .attach_file("./shaders/simple.vert")
-                .attach_file("./shaders/sunlight.frag")
+            scene
+                .shaders
+                .iter()
+                .fold(shader::ShaderBuilder::new(), |builder, path| builder.attach_file(path))
                 .link()
         };
 
@@ -413,26 +581,42 @@ fn main() {
 
             uniform_matrix_location = simple_shader.get_uniform_location("matrix");
             uniform_normal_matrix_location = simple_shader.get_uniform_location("normal_matrix");
+
+            // Lit by the first light of the scene, or by the sun it always had without one
+            let (light_direction, light_color) = scene.lights.first().map_or(
+                (glm::vec3(0.8, -0.5, 0.6), glm::vec3(1., 1., 1.)),
+                |light| (light.direction, light.color),
+            );
+            gl::Uniform3fv(
+                simple_shader.get_uniform_location("light_direction"),
+                1,
+                glm::normalize(&light_direction).as_ptr(),
+            );
+            gl::Uniform3fv(
+                simple_shader.get_uniform_location("light_color"),
+                1,
+                light_color.as_ptr(),
+            );
         };
 
         // Perspective projection properties
-        let fovy = 20.;
-        let near = 1.;
-        let far = 1000.;
+        let fovy = scene.camera.fovy;
+        let near = scene.camera.near;
+        let far = scene.camera.far;
 
         let mut camera_pos = Position {
-            x: 0.,
-            y: 0.,
-            z: 0.,
-            pitch: 0.,
-            yaw: 0.,
+            x: scene.camera.position.x,
+            y: scene.camera.position.y,
+            z: scene.camera.position.z,
+            pitch: scene.camera.pitch,
+            yaw: scene.camera.yaw,
         };
 
         let mut perspective: glm::Mat4;
         let move_z: glm::Mat4 = glm::translation(&glm::vec3(0., 0., 0.));
         let mut translate_camera: glm::Mat4 =
             glm::translation(&glm::vec3(-camera_pos.x, -camera_pos.y, -camera_pos.z));
-        let mut pitch_camera: glm::Mat4 = glm::rotation(camera_pos.pitch, &glm::vec3(1., 0., 0.));
+        let mut pitch_camera: glm::Mat4 = glm::rotation(-camera_pos.pitch, &glm::vec3(1., 0., 0.));
         let mut yaw_camera: glm::Mat4 = glm::rotation(camera_pos.yaw, &glm::vec3(0., 1., 0.));
 
         let mut view_direction = glm::vec3(
@@ -442,40 +626,58 @@ fn main() {
         );
         let mut normal_view_direction = glm::cross(&view_direction, &glm::vec3(0., 1., 0.));
         let movement_speed = 10.;
-
+        let mut save_was_pressed = false;
+        let mut export_was_pressed = false;
+        let mut selected: Option<NodeId> = None;
+        let mut selected_mesh: Option<usize> = None;
+
+        /**
+         * Draws the node and everything below it, skipping whatever is outside the frustum.
+         * Whole subtrees are skipped when their world bounds are outside, and drawn without
+         * further tests when inside, so only nodes straddling the edge of the view are tested
+         * one by one. Needs the world bounds to be up to date.
+         */
         unsafe fn draw_scene(
-            node: &scene_graph::SceneNode,
+            graph: &SceneGraph,
+            node_id: NodeId,
             view_projection_matrix: &glm::Mat4,
-            transformation_so_far: &glm::Mat4,
+            frustum: &Frustum,
+            parent_containment: Containment,
+            counts: &mut DrawCounts,
         ) {
             // Perform any logic needed before drawing the node
-            let position_transformation = glm::translation(&glm::vec3(
-                node.position.x,
-                node.position.y,
-                node.position.z,
-            ));
-
-            let rotation_transformation = // Comment here to get nice formatting
-                glm::translation(&glm::vec3(
-                    node.reference_point.x,
-                    node.reference_point.y,
-                    node.reference_point.z,
-                )) // Comment there to get nice formatting
-                * glm::rotation(node.rotation.x, &glm::vec3(1., 0., 0.))
-                * glm::rotation(node.rotation.y, &glm::vec3(0., 1., 0.))
-                * glm::rotation(node.rotation.z, &glm::vec3(0., 0., 1.))
-                * glm::translation(&glm::vec3(
-                    -node.reference_point.x,
-                    -node.reference_point.y,
-                    -node.reference_point.z,
-                ));
-
-            let next_transformation: glm::Mat4 =
-                transformation_so_far * position_transformation * rotation_transformation;
-
-            // Check if node is drawable, if so: set uniforms, bind VAO and draw VAO
-            if node.vao_id != 0 {
-                let uniform_matrix = view_projection_matrix * next_transformation;
+            let node = &graph[node_id];
+            let containment = match parent_containment {
+                Containment::Inside => Containment::Inside,
+                _ => frustum.classify_aabb(&node.world_bounds),
+            };
+            if containment == Containment::Outside {
+                counts.culled += graph
+                    .depth_first(node_id)
+                    .filter(|&id| graph[id].vao_id != 0)
+                    .count();
+                return;
+            }
+            let transformation = graph.world_transform(node_id);
+
+            // What the node draws may still be outside when only some of its children are not
+            let is_drawable = node.vao_id != 0;
+            let is_visible = is_drawable
+                && (containment == Containment::Inside
+                    || node.local_bounds.is_none_or(|bounds| {
+                        frustum.classify_aabb(&bounds.transformed(&transformation))
+                            != Containment::Outside
+                    }));
+
+            // Check if node is drawable and visible, if so: pick the level of detail, set
+            // uniforms, bind VAO and draw VAO
+            if is_drawable && !is_visible {
+                counts.culled += 1;
+            } else if is_visible {
+                let (vao_id, index_count) =
+                    node.lod_for(node.screen_size(&transformation, view_projection_matrix));
+
+                let uniform_matrix = view_projection_matrix * transformation;
                 gl::UniformMatrix4fv(
                     uniform_matrix_location,
                     1,
@@ -483,30 +685,41 @@ fn main() {
                     uniform_matrix.as_ptr(),
                 );
 
+                // Uneven scale would skew the normals, unless they use the inverse transpose
+                let normal_matrix = glm::transpose(&glm::inverse(&transformation));
                 gl::UniformMatrix4fv(
                     uniform_normal_matrix_location,
                     1,
                     gl::FALSE,
-                    next_transformation.as_ptr(),
+                    normal_matrix.as_ptr(),
                 );
 
-                gl::BindVertexArray(node.vao_id);
+                gl::BindVertexArray(vao_id);
                 gl::DrawElements(
                     gl::TRIANGLES,
-                    node.index_count,
+                    index_count,
                     gl::UNSIGNED_INT,
-                    0 as *const _,
-                )
+                    ptr::null(),
+                );
+                counts.drawn += 1;
             }
             // Recurse
-            for &child in &node.children {
-                draw_scene(&*child, view_projection_matrix, &next_transformation);
+            for &child in node.children() {
+                draw_scene(
+                    graph,
+                    child,
+                    view_projection_matrix,
+                    frustum,
+                    containment,
+                    counts,
+                );
             }
         }
 
         // The main rendering loop
         let first_frame_time = std::time::Instant::now();
         let mut prevous_frame_time = first_frame_time;
+        let mut last_title_update = first_frame_time;
         loop {
             // Compute time passed since the previous frame and since the start of the program
             let now = std::time::Instant::now();
@@ -643,6 +856,82 @@ fn main() {
                     }
                 }
             }
+            // Keep the camera from flying into the ground
+            let mut camera_position = glm::vec3(camera_pos.x, camera_pos.y, camera_pos.z);
+            let mut camera_moved = terrain_ground
+                .as_ref()
+                .is_some_and(|ground| ground.keep_above(&mut camera_position, CAMERA_CLEARANCE));
+
+            // or into the helicopters, pushing it straight away from the nearest part it is too
+            // close to. Helicopters aren't scaled, so distances are the same in their parts.
+            for &heli in &helicopters {
+                for id in scene_graph.depth_first(heli) {
+                    let mesh = match scene_instance.meshes.get(&id) {
+                        Some(&mesh) => mesh,
+                        None => continue,
+                    };
+                    let world = scene_graph.world_transform(id);
+                    let local = (glm::inverse(&world) * camera_position.push(1.)).xyz();
+                    let closest = scene_bvhs[mesh].closest_point(&local, CAMERA_CLEARANCE);
+                    if let Some(closest) = closest {
+                        // Right on the surface, out along the side of the triangle it is on
+                        let away = if closest.distance > 0. {
+                            (local - closest.point) / closest.distance
+                        } else {
+                            let [a, b, c] = scene_bvhs[mesh].corners(closest.triangle);
+                            let normal = glm::cross(&(b - a), &(c - a));
+                            let normal = if normal.y < 0. { -normal } else { normal };
+                            glm::normalize(&normal)
+                        };
+                        let pushed = closest.point + away * CAMERA_CLEARANCE;
+                        camera_position = (world * pushed.push(1.)).xyz();
+                        camera_moved = true;
+                    }
+                }
+            }
+            if camera_moved {
+                camera_pos.x = camera_position.x;
+                camera_pos.y = camera_position.y;
+                camera_pos.z = camera_position.z;
+                translate_camera = glm::translation(&-camera_position);
+            }
+
+            // Save the scene as it is now, once per press of F5
+            let save_pressed = pressed_keys
+                .lock()
+                .is_ok_and(|keys| keys.contains(&VirtualKeyCode::F5));
+            if save_pressed && !save_was_pressed {
+                scene.camera.position = glm::vec3(camera_pos.x, camera_pos.y, camera_pos.z);
+                scene.camera.pitch = camera_pos.pitch;
+                scene.camera.yaw = camera_pos.yaw;
+                scene.capture(&scene_graph, scene_graph.root(), &scene_instance);
+                match scene.write(SAVED_SCENE_PATH) {
+                    Ok(()) => println!("Saved the scene to {}", SAVED_SCENE_PATH),
+                    Err(e) => println!("Failed to save the scene: {}", e),
+                }
+            }
+            save_was_pressed = save_pressed;
+
+            // Export the mesh last clicked on, once per press of F6, e.g. to look at it in Blender
+            let export_pressed = pressed_keys
+                .lock()
+                .is_ok_and(|keys| keys.contains(&VirtualKeyCode::F6));
+            if export_pressed && !export_was_pressed {
+                match selected_mesh {
+                    Some(mesh) => {
+                        let path = format!("{}/{}.obj", EXPORT_DIR, scene.meshes[mesh].name);
+                        let written = std::fs::create_dir_all(EXPORT_DIR)
+                            .and_then(|()| ObjWriter::write(&scene_meshes[mesh], &path));
+                        match written {
+                            Ok(()) => println!("Exported the mesh to {}", path),
+                            Err(e) => println!("Failed to export the mesh: {}", e),
+                        }
+                    }
+                    None => println!("Click on a mesh to export it"),
+                }
+            }
+            export_was_pressed = export_pressed;
+
             // Handle mouse movement. delta contains the x and y movement of the mouse since last frame in pixels
             if let Ok(mut delta) = mouse_delta.lock() {
                 // == // Optionally access the acumulated mouse movement between
@@ -656,24 +945,25 @@ fn main() {
             unsafe {
                 // heli_body_node.rotation.y = 0.5 * elapsed;
 
-                let helicoper_nodes_n = helicopter_nodes.len();
-
-                for (i, h) in helicopter_nodes.iter_mut().enumerate() {
-                    let lap = 2. * std::f32::consts::PI;
-                    let heading = toolbox::simple_heading_animation(
-                        elapsed + lap / (helicoper_nodes_n as f32) * (i as f32),
-                    );
-
-                    h.body.position.x = heading.x;
-                    h.body.position.z = heading.z;
-                    h.body.rotation.x = heading.pitch;
-                    h.body.rotation.z = heading.roll;
-                    h.body.rotation.y = heading.yaw;
-
-                    h.main_rotor.rotation.y = 10. * elapsed;
-                    h.tail_rotor.rotation.x = 10. * elapsed;
+                // The helicopters follow their heading and the rotors spin, as the scene file says
+                scene_instance.animate(&mut scene_graph, &scene_models, elapsed);
+
+                // Whatever the animation, helicopters keep clear of the ground
+                for &heli in &helicopters {
+                    let body = &mut scene_graph[heli];
+                    body.position.y = terrain_ground
+                        .as_ref()
+                        .and_then(|ground| {
+                            ground.clear_height(body.position.x, body.position.z,
+                                                HELICOPTER_CLEARANCE)
+                        })
+                        .map_or(HELICOPTER_ALTITUDE, |height| height.max(HELICOPTER_ALTITUDE));
                 }
 
+                // Transforms and bounds follow the animated nodes, so they are updated afterwards
+                scene_graph.update_transforms();
+                scene_graph.update_world_bounds();
+
                 // Clear the color and depth buffers
                 gl::ClearColor(0.035, 0.046, 0.078, 1.0); // night sky, full opacity
                 gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
@@ -687,10 +977,88 @@ fn main() {
                 perspective *= translate_camera;
                 perspective *= move_z;
 
+                // Pick whatever is under the cursor when the left mouse button is pressed. Any part
+                // of a helicopter selects the whole helicopter.
+                let click = cursor.lock().ok().filter(|cursor| cursor.2).map(|mut cursor| {
+                    cursor.2 = false;
+                    (cursor.0, cursor.1)
+                });
+                if let Some((x, y)) = click {
+                    let size = context.window().inner_size();
+                    let ray = Ray::from_screen(
+                        x,
+                        y,
+                        size.width as f32,
+                        size.height as f32,
+                        &perspective,
+                    );
+                    let hit = ray.cast(&scene_graph, scene_graph.root(), |id| {
+                        scene_instance.meshes.get(&id).map(|&mesh| &scene_bvhs[mesh])
+                    });
+                    selected = hit.map(|hit| {
+                        scene_graph
+                            .ancestors(hit.node)
+                            .find(|&id| scene_graph[id].has_tag("helicopter"))
+                            .unwrap_or(hit.node)
+                    });
+                    selected_mesh =
+                        hit.and_then(|hit| scene_instance.meshes.get(&hit.node).copied());
+                    match hit {
+                        Some(hit) => {
+                            // The corner of the triangle nearest the hit has the largest weight
+                            let corner = (0..3)
+                                .max_by(|&i, &j| hit.barycentric[i].total_cmp(&hit.barycentric[j]))
+                                .unwrap_or(0);
+                            let mesh = scene_instance.meshes[&hit.node];
+                            let vertex = scene_meshes[mesh].indices[hit.triangle * 3 + corner];
+
+                            // How finely the mesh is divided there, e.g. to see where a LOD or
+                            // a simplified mesh lost detail
+                            let [a, b, c] = scene_bvhs[mesh].corners(hit.triangle);
+                            let local = a * hit.barycentric.x
+                                + b * hit.barycentric.y
+                                + c * hit.barycentric.z;
+                            let nearby = scene_bvhs[mesh].triangles_in_sphere(&local, 1.).len();
+                            println!(
+                                "Selected {}, hit {} at ({:.1}, {:.1}, {:.1}), {:.1} away, \
+                                 nearest vertex {}, {} triangles within 1",
+                                scene_graph.path(selected.unwrap()),
+                                scene_graph.path(hit.node),
+                                hit.point.x, hit.point.y, hit.point.z,
+                                hit.distance,
+                                vertex,
+                                nearby
+                            )
+                        }
+                        None => println!("Selected nothing"),
+                    }
+                }
+
                 gl::Uniform1f(uniform_time_location, elapsed);
                 // gl::UniformMatrix4fv(uniform_matrix_location, 1, gl::FALSE, perspective.as_ptr());
 
-                draw_scene(&scene_node, &perspective, &glm::identity());
+                let mut draw_counts = DrawCounts::default();
+                draw_scene(
+                    &scene_graph,
+                    scene_graph.root(),
+                    &perspective,
+                    &Frustum::from_matrix(&perspective),
+                    Containment::Intersecting,
+                    &mut draw_counts,
+                );
+
+                // Shown in the title once a second, as printing them every frame would flood
+                // the terminal
+                if now.duration_since(last_title_update).as_secs_f32() >= 1. {
+                    let selection = selected.map_or(String::new(), |node| {
+                        format!(", {} selected", scene_graph.path(node))
+                    });
+                    context.window().set_title(&format!(
+                        "Gloom-rs - {} drawn, {} culled{}",
+                        draw_counts.drawn, draw_counts.culled, selection
+                    ));
+                    last_title_update = now;
+                }
 
                 // Display the new color buffer on the display
                 context.swap_buffers().unwrap(); // we use "double buffering" to avoid artifacts
@@ -785,6 +1153,28 @@ fn main() {
                     _ => {}
                 }
             }
+            // Keep track of the cursor and left clicks, for picking in the rendering thread
+            Event::WindowEvent {
+                event: WindowEvent::CursorMoved { position, .. },
+                ..
+            } => {
+                if let Ok(mut cursor) = arc_cursor.lock() {
+                    *cursor = (position.x as f32, position.y as f32, cursor.2);
+                }
+            }
+            Event::WindowEvent {
+                event:
+                    WindowEvent::MouseInput {
+                        state: Pressed,
+                        button: MouseButton::Left,
+                        ..
+                    },
+                ..
+            } => {
+                if let Ok(mut cursor) = arc_cursor.lock() {
+                    cursor.2 = true;
+                }
+            }
             Event::DeviceEvent {
                 event: DeviceEvent::MouseMotion { delta },
                 ..
@@ -0,0 +1,152 @@
+use std::path::Path;
+
+use crate::obj_reader::{ObjError, ObjLocation, ParseMode};
+
+// Surface properties as described by a Wavefront .mtl file
+#[derive(Clone, Debug)]
+pub struct Material {
+    pub name: String,
+    pub ambient: [f32; 3],               // Ka
+    pub diffuse: [f32; 3],               // Kd
+    pub specular: [f32; 3],              // Ks
+    pub shininess: f32,                  // Ns
+    pub dissolve: f32,                   // d, or 1 - Tr
+    pub diffuse_texture: Option<String>, // map_Kd, relative to the .mtl file
+}
+
+impl Material {
+    pub fn new(name: &str) -> Material {
+        Material {
+            name: name.to_string(),
+            ambient: [0.; 3],
+            diffuse: [1.; 3],
+            specular: [0.; 3],
+            shininess: 0.,
+            dissolve: 1.,
+            diffuse_texture: None,
+        }
+    }
+
+    // The diffuse colour with dissolve as alpha, in the same layout as Mesh::colors
+    pub fn diffuse_color(&self) -> [f32; 4] {
+        [
+            self.diffuse[0],
+            self.diffuse[1],
+            self.diffuse[2],
+            self.dissolve,
+        ]
+    }
+}
+
+pub struct MtlReader {}
+
+impl MtlReader {
+    // The materials, and the warnings collected in lenient mode
+    pub fn read(
+        mtl_path: &str,
+        mode: ParseMode,
+    ) -> Result<(Vec<Material>, Vec<ObjError>), ObjError> {
+        let path = Path::new(mtl_path);
+
+        // See if extension is mtl
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("mtl") => (),
+            _ => {
+                return Err(ObjError::Extension {
+                    path: mtl_path.to_string(),
+                    expected: "mtl",
+                })
+            }
+        }
+
+        let mtl_src = std::fs::read_to_string(path).map_err(|source| ObjError::Io {
+            path: mtl_path.to_string(),
+            source,
+        })?;
+
+        MtlReader::parse_named_src(mtl_path, &mtl_src, mode)
+    }
+
+    #[cfg(test)]
+    pub fn parse_mtl_src(mtl_src: &str, mode: ParseMode) -> Result<Vec<Material>, ObjError> {
+        MtlReader::parse_named_src("<source>", mtl_src, mode).map(|(materials, _)| materials)
+    }
+
+    fn parse_named_src(
+        path: &str,
+        mtl_src: &str,
+        mode: ParseMode,
+    ) -> Result<(Vec<Material>, Vec<ObjError>), ObjError> {
+        let mut materials: Vec<Material> = vec![];
+        let mut warnings = vec![];
+
+        for (i, line) in mtl_src.lines().enumerate() {
+            let lexemes: Vec<&str> = line.split_whitespace().collect();
+            if lexemes.is_empty() || lexemes[0].starts_with('#') {
+                continue;
+            }
+
+            let syntax_error = |token: &str, expected: &'static str| ObjError::Syntax {
+                location: ObjLocation::of(path, i + 1, line, token),
+                token: token.to_string(),
+                expected,
+            };
+            let end_of_line = &line[line.len()..];
+
+            // Every directive but newmtl modifies the latest material
+            if lexemes[0] == "newmtl" {
+                if lexemes.len() < 2 {
+                    return Err(syntax_error(end_of_line, "a material name"));
+                }
+                materials.push(Material::new(&lexemes[1..].join(" ")));
+                continue;
+            }
+            let material = match materials.last_mut() {
+                Some(material) => material,
+                None => return Err(syntax_error(lexemes[0], "`newmtl`")),
+            };
+
+            let floats = |n: usize| -> Result<Vec<f32>, ObjError> {
+                if lexemes.len() - 1 < n {
+                    return Err(syntax_error(end_of_line, "another component"));
+                }
+                lexemes[1..=n]
+                    .iter()
+                    .map(|&lex| lex.parse().map_err(|_| syntax_error(lex, "a number")))
+                    .collect()
+            };
+            let color = |floats: Vec<f32>| [floats[0], floats[1], floats[2]];
+
+            match lexemes[0] {
+                "Ka" => material.ambient = color(floats(3)?),
+                "Kd" => material.diffuse = color(floats(3)?),
+                "Ks" => material.specular = color(floats(3)?),
+                "Ns" => material.shininess = floats(1)?[0],
+                "d" => material.dissolve = floats(1)?[0],
+                "Tr" => material.dissolve = 1. - floats(1)?[0],
+                // Texture options such as `-s 1 1 1` come before the file name
+                "map_Kd" => match lexemes.last() {
+                    Some(&file) if lexemes.len() > 1 => {
+                        material.diffuse_texture = Some(file.to_string())
+                    }
+                    _ => return Err(syntax_error(end_of_line, "a texture file name")),
+                },
+                // Understood, but not used by any of our shaders
+                "illum" | "Ni" | "Ke" | "Tf" | "map_Ka" | "map_Ks" | "map_Ns" | "map_d"
+                | "map_Bump" | "map_bump" | "bump" | "disp" | "refl" => (),
+                token => {
+                    let error = ObjError::UnknownDirective {
+                        location: ObjLocation::of(path, i + 1, line, token),
+                        token: token.to_string(),
+                    };
+                    match mode {
+                        ParseMode::Strict => return Err(error),
+                        ParseMode::Lenient => warnings.push(error),
+                    }
+                }
+            }
+        }
+
+        Ok((materials, warnings))
+    }
+}
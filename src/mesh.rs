@@ -1,4 +1,10 @@
-use tobj;
+use crate::bounds::{Aabb, BoundingSphere};
+use crate::erosion::Erosion;
+use crate::heightmap::{Heightmap, TerrainSettings, TERRAIN_COLORS};
+use crate::material::Material;
+use crate::model::Model;
+use crate::noise::NoiseSettings;
+use crate::obj_reader::{ObjMesh, ParseMode};
 
 // internal helper
 fn generate_color_vec(color: [f32; 4], num: usize) -> Vec<f32> {
@@ -7,67 +13,208 @@ fn generate_color_vec(color: [f32; 4], num: usize) -> Vec<f32> {
 
 // Mesh
 
+#[derive(Clone)]
 pub struct Mesh {
     pub vertices    : Vec<f32>,
     pub normals     : Vec<f32>,
     pub colors      : Vec<f32>,
+    pub texcoords   : Vec<f32>,    // UV, empty if the mesh is not textured
+    pub tangents    : Vec<f32>,    // XYZ and handedness W, empty until generated
     pub indices     : Vec<u32>,
     pub index_count : i32,
+    pub material    : Option<Material>,
+
+    pub bounds          : Aabb,           // In the mesh's own coordinates
+    pub bounding_sphere : BoundingSphere, // Call update_bounds if the vertices are changed
 }
 
 impl Mesh {
-    pub fn from(mesh: tobj::Mesh, color: [f32; 4]) -> Self {
-        let num_verts = mesh.positions.len() / 3;
-        let index_count = mesh.indices.len() as i32;
+    /**
+     * Colours are picked in order of preference from the explicit colour, the vertex colours of
+     * the file, the diffuse colour of each group's material, and finally white.
+     */
+    pub fn from_obj(obj: ObjMesh, color: Option<[f32; 4]>) -> Self {
+        let num_verts = obj.vertices.len() / 3;
+        let index_count = obj.indices.len() as i32;
+        let material = obj.groups.iter()
+            .find_map(|g| g.material.as_ref().and_then(|name| obj.material(name)))
+            .cloned();
+
+        let colors = match color {
+            Some(color) => generate_color_vec(color, num_verts),
+            None if !obj.colors.is_empty() => obj.colors,
+            None => {
+                let mut colors = generate_color_vec([1.0, 1.0, 1.0, 1.0], num_verts);
+                for group in &obj.groups {
+                    let material = group.material.as_ref().and_then(|name| obj.material(name));
+                    if let Some(material) = material {
+                        for &index in obj.group_indices(group) {
+                            let i = index as usize * 4;
+                            colors[i..i + 4].copy_from_slice(&material.diffuse_color());
+                        }
+                    }
+                }
+                colors
+            }
+        };
+
+        let bounds = Aabb::from_points(&obj.vertices);
+        let bounding_sphere = BoundingSphere::from_points(&obj.vertices);
+        Mesh {
+            vertices: obj.vertices,
+            normals: obj.normals,
+            texcoords: obj.texcoords,
+            tangents: vec![],
+            indices: obj.indices,
+            colors,
+            index_count,
+            material,
+            bounds,
+            bounding_sphere,
+        }
+    }
+
+    // Wraps the (vertices, indices) tuples made by ShapeGenerator, which come without normals
+    pub fn from_shape(shape: (Vec<f32>, Vec<u32>), color: [f32; 4]) -> Self {
+        let (vertices, indices) = shape;
+        let num_verts = vertices.len() / 3;
+        let index_count = indices.len() as i32;
+        let bounds = Aabb::from_points(&vertices);
+        let bounding_sphere = BoundingSphere::from_points(&vertices);
         Mesh {
-            vertices: mesh.positions,
-            normals: mesh.normals,
-            indices: mesh.indices,
+            vertices,
+            normals: vec![],
+            texcoords: vec![],
+            tangents: vec![],
+            indices,
             colors: generate_color_vec(color, num_verts),
             index_count,
+            material: None,
+            bounds,
+            bounding_sphere,
         }
     }
+
+    /**
+     * Concatenates the meshes, rebasing the indices of each onto the vertices before it.
+     * Other attributes than colours are only kept if every mesh has them, and the first
+     * material wins.
+     */
+    pub fn merge(meshes: Vec<Mesh>) -> Self {
+        let keep_normals = meshes.iter().all(|m| m.normals.len() == m.vertices.len());
+        let keep_texcoords = meshes.iter().all(|m| m.texcoords.len() / 2 == m.vertices.len() / 3);
+        let keep_tangents = meshes.iter().all(|m| m.tangents.len() / 4 == m.vertices.len() / 3);
+        let mut merged = Mesh::from_shape((vec![], vec![]), [1.0, 1.0, 1.0, 1.0]);
+
+        for mesh in meshes {
+            let base = (merged.vertices.len() / 3) as u32;
+            merged.indices.extend(mesh.indices.iter().map(|i| i + base));
+            merged.vertices.extend(mesh.vertices);
+            merged.colors.extend(mesh.colors);
+            if keep_normals {
+                merged.normals.extend(mesh.normals);
+            }
+            if keep_texcoords {
+                merged.texcoords.extend(mesh.texcoords);
+            }
+            if keep_tangents {
+                merged.tangents.extend(mesh.tangents);
+            }
+            if merged.material.is_none() {
+                merged.material = mesh.material;
+            }
+        }
+
+        merged.index_count = merged.indices.len() as i32;
+        merged.update_bounds();
+        merged
+    }
+
+    pub fn set_color(&mut self, color: [f32; 4]) {
+        self.colors = generate_color_vec(color, self.vertices.len() / 3);
+    }
+
+    pub fn update_bounds(&mut self) {
+        self.bounds = Aabb::from_points(&self.vertices);
+        self.bounding_sphere = BoundingSphere::from_points(&self.vertices);
+    }
+
+    pub fn vertex_count(&self) -> usize {
+        self.vertices.len() / 3
+    }
+
+    pub fn position(&self, i: u32) -> glm::Vec3 {
+        let i = i as usize * 3;
+        glm::vec3(self.vertices[i], self.vertices[i + 1], self.vertices[i + 2])
+    }
+
+    /**
+     * Rebuilds every vertex attribute so that new vertex i is a copy of old vertex sources[i].
+     * Used when vertices are split or welded, and leaves the indices for the caller to fix.
+     */
+    pub(crate) fn rebuild_vertices(&mut self, sources: &[u32]) {
+        fn gather(attribute: &[f32], size: usize, sources: &[u32]) -> Vec<f32> {
+            if attribute.is_empty() {
+                return vec![];
+            }
+            sources.iter()
+                .flat_map(|&s| {
+                    let start = s as usize * size;
+                    attribute[start..start + size].iter().cloned()
+                })
+                .collect()
+        }
+
+        self.vertices  = gather(&self.vertices,  3, sources);
+        self.normals   = gather(&self.normals,   3, sources);
+        self.colors    = gather(&self.colors,    4, sources);
+        self.texcoords = gather(&self.texcoords, 2, sources);
+        self.tangents  = gather(&self.tangents,  4, sources);
+    }
 }
 
 // Lunar terrain
 
 pub struct Terrain;
 impl Terrain {
+    // Superseded by the scene file, kept for loading a terrain model on its own
+    #[allow(dead_code)]
     pub fn load(path: &str) -> Mesh {
         println!("Loading terrain model...");
-        let before = std::time::Instant::now();
-        let (models, _materials)
-            = tobj::load_obj(path,
-                &tobj::LoadOptions{
-                    triangulate: true,
-                    single_index: true,
-                    ..Default::default()
-                }
-            ).expect("Failed to load terrain model");
-        let after = std::time::Instant::now();
-        println!("Done in {:.3}ms.", after.duration_since(before).as_micros() as f32 / 1e3);
-
-        if models.len() > 1 || models.len() == 0 {
-            panic!("Please use a model with a single mesh!")
-            // You could try merging the vertices and indices
-            // of the separate meshes into a single mesh.
-            // I'll leave that as an optional exercise. ;)
-        }
+        let (model, report) = Model::load(path, ParseMode::Lenient)
+            .unwrap_or_else(|e| panic!("Failed to load terrain model: {}", e));
+        print!("{}", report);
 
-        let terrain = models[0].to_owned();
-        println!("Loaded {} with {} points and {} triangles.",
-            terrain.name,
-            terrain.mesh.positions.len() /3,
-            terrain.mesh.indices.len() / 3,
-        );
+        // Terrain split into several parts is drawn as one
+        model.merge()
+    }
 
-        Mesh::from(terrain.mesh, [1.0, 1.0, 1.0, 1.0])
+    /**
+     * Generates the terrain from seeded noise, so the same settings always give the same
+     * terrain. The erosion passes are applied in order before the mesh is built.
+     */
+    pub fn generate(
+        width: usize,
+        depth: usize,
+        noise: &NoiseSettings,
+        erosion: &[Erosion],
+        settings: &TerrainSettings,
+    ) -> Mesh {
+        let mut heightmap = Heightmap::from_noise(width, depth, noise);
+        for pass in erosion {
+            heightmap.erode(pass);
+        }
+        let mut mesh = heightmap.to_mesh(settings);
+        mesh.color_by_height(&TERRAIN_COLORS);
+        mesh
     }
 }
 
 
 // Helicopter
 
+// Superseded by the scene file, kept for loading a helicopter model on its own
+#[allow(dead_code)]
 pub struct Helicopter {
     pub body       : Mesh,
     pub door       : Mesh,
@@ -91,34 +238,27 @@ impl Index<usize> for Helicopter {
 }
 
 impl Helicopter {
+    #[allow(dead_code)]
     pub fn load(path: &str) -> Self {
         println!("Loading helicopter model...");
-        let before = std::time::Instant::now();
-        let (models, _materials)
-            = tobj::load_obj(path,
-                &tobj::LoadOptions{
-                    triangulate: true,
-                    single_index: true,
-                    ..Default::default()
-                }
-            ).expect("Failed to load helicopter model");
-        let after = std::time::Instant::now();
-        println!("Done in {:.3}ms!", after.duration_since(before).as_micros() as f32 / 1e3);
-
-        for model in &models {
-            println!("Loaded {} with {} points and {} triangles.", model.name, model.mesh.positions.len() / 3, model.mesh.indices.len() / 3);
-        }
+        let (mut model, report) = Model::load(path, ParseMode::Lenient)
+            .unwrap_or_else(|e| panic!("Failed to load helicopter model: {}", e));
+        print!("{}", report);
 
-        let body_model = models.iter().find(|m| m.name == "Body_body").expect("Incorrect model file!").to_owned();
-        let door_model = models.iter().find(|m| m.name == "Door_door").expect("Incorrect model file!").to_owned();
-        let main_rotor_model = models.iter().find(|m| m.name == "Main_Rotor_main_rotor").expect("Incorrect model file!").to_owned();
-        let tail_rotor_model = models.iter().find(|m| m.name == "Tail_Rotor_tail_rotor").expect("Incorrect model file!").to_owned();
+        // The colours are only used if the model file comes without materials
+        let mut part = |name: &str, color: [f32; 4]| {
+            let mut mesh = model.take(name).expect("Incorrect model file!");
+            if mesh.material.is_none() {
+                mesh.set_color(color);
+            }
+            mesh
+        };
 
         Helicopter {
-            body:       Mesh::from(body_model.mesh,         [0.3, 0.3, 0.3, 1.0]),
-            door:       Mesh::from(door_model.mesh,         [0.1, 0.1, 0.3, 1.0]),
-            main_rotor: Mesh::from(main_rotor_model.mesh,   [0.3, 0.1, 0.1, 1.0]),
-            tail_rotor: Mesh::from(tail_rotor_model.mesh,   [0.1, 0.3, 0.1, 1.0]),
+            body:       part("Body_body",             [0.3, 0.3, 0.3, 1.0]),
+            door:       part("Door_door",             [0.1, 0.1, 0.3, 1.0]),
+            main_rotor: part("Main_Rotor_main_rotor", [0.3, 0.1, 0.1, 1.0]),
+            tail_rotor: part("Tail_Rotor_tail_rotor", [0.1, 0.3, 0.1, 1.0]),
         }
     }
 }
@@ -0,0 +1,668 @@
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::bounds::{Aabb, BoundingSphere};
+use crate::material::Material;
+use crate::mesh::Mesh;
+use crate::obj_reader::ParseMode;
+
+/*
+ * Layout of a cache file, with every number little-endian:
+ *
+ *   magic "GMSH", version: u32, parse mode: u8 (0 for strict, 1 for lenient)
+ *   source count: u32, then for each file the meshes were read from:
+ *     path: string, u8 (0 if the file was missing), modified: u64 seconds and u32 nanoseconds
+ *     since the Unix epoch
+ *   mesh count: u32
+ *   for each mesh:
+ *     name: string, layout: u32 (bit flags for the optional arrays, see below)
+ *     vertex count: u32, index count: u32
+ *     bounds: min xyz, max xyz, sphere center xyz and radius as f32
+ *     material: u8 (0 if none), then name: string, Ka, Kd, Ks: 3 f32 each, Ns, d: f32,
+ *               u8 (0 if no diffuse texture), then diffuse texture: string
+ *     positions, then normals, colours, texture coordinates and tangents if in the layout,
+ *     as f32 arrays, then the indices as a u32 array
+ *   FNV-1a hash of everything above: u64
+ *
+ * Strings are a u32 byte count followed by UTF-8. The version is bumped whenever the layout
+ * changes, so old caches are rebuilt rather than misread.
+ */
+const MAGIC: &[u8; 4] = b"GMSH";
+const VERSION: u32 = 2;
+
+const HAS_NORMALS: u32 = 1;
+const HAS_COLORS: u32 = 2;
+const HAS_TEXCOORDS: u32 = 4;
+const HAS_TANGENTS: u32 = 8;
+
+#[derive(Debug)]
+pub enum MeshCacheError {
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    Format {
+        path: String,
+        reason: &'static str,
+    },
+    Version {
+        path: String,
+        found: u32,
+    },
+    Checksum {
+        path: String,
+    },
+}
+
+impl fmt::Display for MeshCacheError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MeshCacheError::Io { path, source } => {
+                write!(f, "{}: failed to access mesh cache: {}", path, source)
+            }
+            MeshCacheError::Format { path, reason } => {
+                write!(f, "{}: not a valid mesh cache: {}", path, reason)
+            }
+            MeshCacheError::Version { path, found } => write!(
+                f,
+                "{}: mesh cache has version {}, expected {}",
+                path, found, VERSION
+            ),
+            MeshCacheError::Checksum { path } => {
+                write!(f, "{}: mesh cache checksum does not match", path)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MeshCacheError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MeshCacheError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+// Each file a cache was read from, with when it was last modified, or None if it was missing
+type Sources = Vec<(String, Option<SystemTime>)>;
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// 64 bit FNV-1a, which is plenty to catch truncated or corrupted files
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// The bytes of a file, memory-mapped where possible so opening it costs next to nothing
+enum FileBytes {
+    #[cfg(unix)]
+    Mapped {
+        ptr: *mut libc::c_void,
+        len: usize,
+    },
+    Read(Vec<u8>),
+}
+
+impl FileBytes {
+    fn open(path: &Path) -> std::io::Result<FileBytes> {
+        let mut file = File::open(path)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::AsRawFd;
+            let len = file.metadata()?.len() as usize;
+            // Mapping an empty file fails, and a failed mapping falls back to reading
+            if len > 0 {
+                let ptr = unsafe {
+                    libc::mmap(
+                        std::ptr::null_mut(),
+                        len,
+                        libc::PROT_READ,
+                        libc::MAP_PRIVATE,
+                        file.as_raw_fd(),
+                        0,
+                    )
+                };
+                if ptr != libc::MAP_FAILED {
+                    return Ok(FileBytes::Mapped { ptr, len });
+                }
+            }
+        }
+
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes)?;
+        Ok(FileBytes::Read(bytes))
+    }
+}
+
+impl Deref for FileBytes {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match self {
+            #[cfg(unix)]
+            FileBytes::Mapped { ptr, len } => unsafe {
+                std::slice::from_raw_parts(*ptr as *const u8, *len)
+            },
+            FileBytes::Read(bytes) => bytes,
+        }
+    }
+}
+
+impl Drop for FileBytes {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let FileBytes::Mapped { ptr, len } = self {
+            unsafe {
+                libc::munmap(*ptr, *len);
+            }
+        }
+    }
+}
+
+// Appends values in the cache's byte order
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend(&value.to_le_bytes());
+    }
+
+    fn f32s(&mut self, values: &[f32]) {
+        for value in values {
+            self.bytes.extend(&value.to_le_bytes());
+        }
+    }
+
+    fn u32s(&mut self, values: &[u32]) {
+        for &value in values {
+            self.u32(value);
+        }
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.bytes.extend(value.as_bytes());
+    }
+}
+
+// Reads values in the cache's byte order, failing at the end of the bytes
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], &'static str> {
+        if n > self.bytes.len() {
+            return Err("unexpected end of file");
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, &'static str> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, &'static str> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, &'static str> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    fn f32s(&mut self, n: usize) -> Result<Vec<f32>, &'static str> {
+        Ok(self
+            .take(n.checked_mul(4).ok_or("array too large")?)?
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect())
+    }
+
+    fn f32_array<const N: usize>(&mut self) -> Result<[f32; N], &'static str> {
+        let mut array = [0.; N];
+        array.copy_from_slice(&self.f32s(N)?);
+        Ok(array)
+    }
+
+    fn u32s(&mut self, n: usize) -> Result<Vec<u32>, &'static str> {
+        Ok(self
+            .take(n.checked_mul(4).ok_or("array too large")?)?
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect())
+    }
+
+    fn string(&mut self) -> Result<String, &'static str> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| "string is not UTF-8")
+    }
+}
+
+// Reads and writes named meshes in a compact binary format, to skip parsing on startup
+pub struct MeshCache {}
+
+impl MeshCache {
+    // Where the cache for a source file lives, e.g. resources/helicopter.meshcache
+    pub fn path_for(source_path: &str) -> PathBuf {
+        Path::new(source_path).with_extension("meshcache")
+    }
+
+    /**
+     * Whether the cache exists, was read in the same mode, and every file it was read from is
+     * still as it was then, i.e. the .obj file and its material libraries. A library that was
+     * missing and has turned up since makes the cache stale too.
+     */
+    pub fn is_fresh(cache_path: &Path, mode: ParseMode) -> bool {
+        let bytes = match FileBytes::open(cache_path) {
+            Ok(bytes) => bytes,
+            Err(_) => return false,
+        };
+        if bytes.len() < MAGIC.len() || &bytes[..4] != MAGIC {
+            return false;
+        }
+        let mut r = Reader { bytes: &bytes[4..] };
+        match MeshCache::read_sources(&mut r) {
+            Ok((cached_mode, sources)) => {
+                cached_mode == mode
+                    && sources
+                        .iter()
+                        .all(|(path, modified)| modified_time(Path::new(path)) == *modified)
+            }
+            Err(_) => false,
+        }
+    }
+
+    // The version, parse mode and sources, with the modification times of the sources
+    fn read_sources(r: &mut Reader) -> Result<(ParseMode, Sources), &'static str> {
+        if r.u32()? != VERSION {
+            return Err("wrong version");
+        }
+        let mode = match r.u8()? {
+            0 => ParseMode::Strict,
+            1 => ParseMode::Lenient,
+            _ => return Err("unknown parse mode"),
+        };
+        let count = r.u32()?;
+        let sources = (0..count)
+            .map(|_| {
+                let path = r.string()?;
+                let exists = r.u8()? != 0;
+                let seconds = r.u64()?;
+                let nanoseconds = r.u32()?;
+                let modified = UNIX_EPOCH + Duration::new(seconds, nanoseconds);
+                Ok((path, Some(modified).filter(|_| exists)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((mode, sources))
+    }
+
+    /**
+     * Writes the meshes along with the parse mode and the files they were read from, which
+     * is_fresh checks. The file is written next to the cache and then renamed over it, so a
+     * cache that is being read, maybe memory-mapped, is never changed underneath the reader.
+     */
+    pub fn write(
+        path: &Path,
+        mode: ParseMode,
+        sources: &[String],
+        meshes: &[(&str, &Mesh)],
+    ) -> Result<(), MeshCacheError> {
+        let mut w = Writer { bytes: vec![] };
+        w.bytes.extend(MAGIC);
+        w.u32(VERSION);
+        w.u8(match mode {
+            ParseMode::Strict => 0,
+            ParseMode::Lenient => 1,
+        });
+        w.u32(sources.len() as u32);
+        for source in sources {
+            let modified = modified_time(Path::new(source));
+            let since_epoch = modified
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .unwrap_or_default();
+            w.string(source);
+            w.u8(modified.is_some() as u8);
+            w.u64(since_epoch.as_secs());
+            w.u32(since_epoch.subsec_nanos());
+        }
+        w.u32(meshes.len() as u32);
+
+        for &(name, mesh) in meshes {
+            let n_vertices = mesh.vertex_count();
+            let optional = [
+                (HAS_NORMALS, &mesh.normals, 3),
+                (HAS_COLORS, &mesh.colors, 4),
+                (HAS_TEXCOORDS, &mesh.texcoords, 2),
+                (HAS_TANGENTS, &mesh.tangents, 4),
+            ];
+            // Attributes not matching the vertices are left out rather than written half-way
+            let layout = optional
+                .iter()
+                .filter(|(_, values, size)| !values.is_empty() && values.len() == n_vertices * size)
+                .fold(0, |layout, (flag, _, _)| layout | flag);
+
+            w.string(name);
+            w.u32(layout);
+            w.u32(n_vertices as u32);
+            w.u32(mesh.indices.len() as u32);
+
+            let (aabb, sphere) = (&mesh.bounds, &mesh.bounding_sphere);
+            w.f32s(aabb.min.as_slice());
+            w.f32s(aabb.max.as_slice());
+            w.f32s(sphere.center.as_slice());
+            w.f32s(&[sphere.radius]);
+
+            match &mesh.material {
+                Some(material) => {
+                    w.u8(1);
+                    w.string(&material.name);
+                    w.f32s(&material.ambient);
+                    w.f32s(&material.diffuse);
+                    w.f32s(&material.specular);
+                    w.f32s(&[material.shininess, material.dissolve]);
+                    match &material.diffuse_texture {
+                        Some(texture) => {
+                            w.u8(1);
+                            w.string(texture);
+                        }
+                        None => w.u8(0),
+                    }
+                }
+                None => w.u8(0),
+            }
+
+            w.f32s(&mesh.vertices[..n_vertices * 3]);
+            for (flag, values, _) in &optional {
+                if layout & flag != 0 {
+                    w.f32s(values);
+                }
+            }
+            w.u32s(&mesh.indices);
+        }
+
+        let checksum = fnv1a(&w.bytes);
+        w.bytes.extend(&checksum.to_le_bytes());
+
+        let io_error = |source| MeshCacheError::Io {
+            path: path.to_string_lossy().to_string(),
+            source,
+        };
+        let mut temporary_path = path.as_os_str().to_owned();
+        temporary_path.push(format!(".{}.tmp", std::process::id()));
+        std::fs::write(&temporary_path, &w.bytes).map_err(io_error)?;
+        std::fs::rename(&temporary_path, path).map_err(|source| {
+            let _ = std::fs::remove_file(&temporary_path);
+            io_error(source)
+        })
+    }
+
+    pub fn read(path: &Path) -> Result<Vec<(String, Mesh)>, MeshCacheError> {
+        let path_string = path.to_string_lossy().to_string();
+        let bytes = FileBytes::open(path).map_err(|source| MeshCacheError::Io {
+            path: path_string.clone(),
+            source,
+        })?;
+        let format_error = |reason| MeshCacheError::Format {
+            path: path_string.clone(),
+            reason,
+        };
+
+        if bytes.len() < MAGIC.len() + 4 + 8 || &bytes[..4] != MAGIC {
+            return Err(format_error("missing header"));
+        }
+        let (contents, checksum) = bytes.split_at(bytes.len() - 8);
+        let version = Reader {
+            bytes: &contents[4..],
+        }
+        .u32()
+        .map_err(format_error)?;
+        if version != VERSION {
+            return Err(MeshCacheError::Version {
+                path: path_string,
+                found: version,
+            });
+        }
+        let mut stored_checksum = [0; 8];
+        stored_checksum.copy_from_slice(checksum);
+        if fnv1a(contents) != u64::from_le_bytes(stored_checksum) {
+            return Err(MeshCacheError::Checksum { path: path_string });
+        }
+
+        let mut r = Reader {
+            bytes: &contents[4..],
+        };
+        MeshCache::read_sources(&mut r).map_err(format_error)?;
+
+        let count = r.u32().map_err(format_error)?;
+        let meshes = (0..count)
+            .map(|_| MeshCache::read_mesh(&mut r))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(format_error)?;
+        if !r.bytes.is_empty() {
+            return Err(format_error("trailing data after the meshes"));
+        }
+
+        Ok(meshes)
+    }
+
+    fn read_mesh(r: &mut Reader) -> Result<(String, Mesh), &'static str> {
+        let name = r.string()?;
+        let layout = r.u32()?;
+        let n_vertices = r.u32()? as usize;
+        let n_indices = r.u32()? as usize;
+
+        let [min_x, min_y, min_z, max_x, max_y, max_z, center_x, center_y, center_z, radius] =
+            r.f32_array::<10>()?;
+        let bounds = Aabb {
+            min: glm::vec3(min_x, min_y, min_z),
+            max: glm::vec3(max_x, max_y, max_z),
+        };
+        let bounding_sphere = BoundingSphere {
+            center: glm::vec3(center_x, center_y, center_z),
+            radius,
+        };
+
+        let material = if r.u8()? != 0 {
+            let mut material = Material::new(&r.string()?);
+            material.ambient = r.f32_array()?;
+            material.diffuse = r.f32_array()?;
+            material.specular = r.f32_array()?;
+            let [shininess, dissolve] = r.f32_array()?;
+            material.shininess = shininess;
+            material.dissolve = dissolve;
+            if r.u8()? != 0 {
+                material.diffuse_texture = Some(r.string()?);
+            }
+            Some(material)
+        } else {
+            None
+        };
+
+        let vertices = r.f32s(n_vertices * 3)?;
+        let mut optional = |flag: u32, size: usize| {
+            if layout & flag != 0 {
+                r.f32s(n_vertices * size)
+            } else {
+                Ok(vec![])
+            }
+        };
+        let normals = optional(HAS_NORMALS, 3)?;
+        let colors = optional(HAS_COLORS, 4)?;
+        let texcoords = optional(HAS_TEXCOORDS, 2)?;
+        let tangents = optional(HAS_TANGENTS, 4)?;
+        let indices = r.u32s(n_indices)?;
+        if indices.iter().any(|&i| i as usize >= n_vertices) {
+            return Err("index out of range");
+        }
+
+        let mut mesh = Mesh {
+            vertices,
+            normals,
+            colors,
+            texcoords,
+            tangents,
+            index_count: indices.len() as i32,
+            indices,
+            material,
+            bounds,
+            bounding_sphere,
+        };
+        // Every mesh is expected to have colours
+        if mesh.colors.is_empty() {
+            mesh.set_color([1.0, 1.0, 1.0, 1.0]);
+        }
+
+        Ok((name, mesh))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("gloom_mesh_cache_{}", name))
+    }
+
+    fn textured_quad() -> Mesh {
+        let mut mesh = Mesh::from_shape(
+            (
+                vec![0., 0., 0., 1., 0., 0., 1., 1., 0., 0., 1., 0.],
+                vec![0, 1, 2, 0, 2, 3],
+            ),
+            [0.2, 0.4, 0.6, 1.],
+        );
+        mesh.normals = [0., 0., 1.].repeat(4);
+        mesh.texcoords = vec![0., 0., 1., 0., 1., 1., 0., 1.];
+        let mut material = Material::new("paint");
+        material.diffuse = [0.3, 0.1, 0.1];
+        material.diffuse_texture = Some("paint.png".to_string());
+        mesh.material = Some(material);
+        mesh
+    }
+
+    #[test]
+    fn meshes_read_back_as_written() {
+        let path = temp_path("round_trip.meshcache");
+        let quad = textured_quad();
+        let plain = Mesh::from_shape(
+            (vec![0., 0., 0., 0., 2., 0., 0., 0., 3.], vec![0, 1, 2]),
+            [1., 1., 1., 1.],
+        );
+        MeshCache::write(
+            &path,
+            ParseMode::Strict,
+            &[],
+            &[("quad", &quad), ("plain", &plain)],
+        )
+        .unwrap();
+
+        let meshes = MeshCache::read(&path).unwrap();
+        assert_eq!(meshes.len(), 2);
+        let (name, read) = &meshes[0];
+        assert_eq!(name, "quad");
+        assert_eq!(read.vertices, quad.vertices);
+        assert_eq!(read.normals, quad.normals);
+        assert_eq!(read.colors, quad.colors);
+        assert_eq!(read.texcoords, quad.texcoords);
+        assert!(read.tangents.is_empty());
+        assert_eq!(read.indices, quad.indices);
+        assert_eq!(read.bounds, quad.bounds);
+        assert_eq!(read.bounding_sphere, quad.bounding_sphere);
+        let material = read.material.as_ref().unwrap();
+        assert_eq!(material.name, "paint");
+        assert_eq!(material.diffuse, [0.3, 0.1, 0.1]);
+        assert_eq!(material.diffuse_texture.as_deref(), Some("paint.png"));
+
+        let (name, read) = &meshes[1];
+        assert_eq!(name, "plain");
+        assert!(read.normals.is_empty() && read.material.is_none());
+        assert_eq!(read.indices, [0, 1, 2]);
+    }
+
+    #[test]
+    fn corrupted_caches_are_rejected() {
+        let path = temp_path("corrupted.meshcache");
+        MeshCache::write(&path, ParseMode::Strict, &[], &[("quad", &textured_quad())]).unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(matches!(
+            MeshCache::read(&path),
+            Err(MeshCacheError::Checksum { .. })
+        ));
+
+        bytes.truncate(middle);
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(MeshCache::read(&path).is_err());
+    }
+
+    #[test]
+    fn caches_go_stale_when_a_source_changes_or_the_mode_differs() {
+        let obj_path = temp_path("stale.obj").to_string_lossy().to_string();
+        let mtl_path = temp_path("stale.mtl").to_string_lossy().to_string();
+        let missing_path = temp_path("stale_missing.mtl").to_string_lossy().to_string();
+        let cache_path = temp_path("stale.meshcache");
+        std::fs::write(&obj_path, "v 0 0 0\n").unwrap();
+        std::fs::write(&mtl_path, "newmtl paint\n").unwrap();
+        let _ = std::fs::remove_file(&missing_path);
+
+        let sources = [obj_path, mtl_path.clone(), missing_path.clone()];
+        let quad = textured_quad();
+        MeshCache::write(
+            &cache_path,
+            ParseMode::Lenient,
+            &sources,
+            &[("quad", &quad)],
+        )
+        .unwrap();
+        assert!(MeshCache::is_fresh(&cache_path, ParseMode::Lenient));
+        assert!(!MeshCache::is_fresh(&cache_path, ParseMode::Strict));
+
+        // Only the material library changes
+        let later = SystemTime::now() + Duration::from_secs(60);
+        let mtl = std::fs::File::options()
+            .write(true)
+            .open(&mtl_path)
+            .unwrap();
+        mtl.set_modified(later).unwrap();
+        assert!(!MeshCache::is_fresh(&cache_path, ParseMode::Lenient));
+
+        // A library that was missing turns up
+        MeshCache::write(
+            &cache_path,
+            ParseMode::Lenient,
+            &sources,
+            &[("quad", &quad)],
+        )
+        .unwrap();
+        assert!(MeshCache::is_fresh(&cache_path, ParseMode::Lenient));
+        std::fs::write(&missing_path, "newmtl glass\n").unwrap();
+        assert!(!MeshCache::is_fresh(&cache_path, ParseMode::Lenient));
+    }
+}
@@ -0,0 +1,237 @@
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::mesh::Mesh;
+use crate::mesh_cache::{MeshCache, MeshCacheError};
+use crate::obj_reader::{ObjError, ObjReader, ParseMode};
+
+// One named part of a model, e.g. the main rotor of the helicopter
+pub struct ModelPart {
+    pub name: String,
+    pub mesh: Mesh,
+}
+
+// Every object or group of an OBJ file as its own Mesh, in the order they appear in the file
+pub struct Model {
+    pub parts: Vec<ModelPart>,
+}
+
+pub struct PartSummary {
+    pub name: String,
+    pub points: usize,
+    pub triangles: usize,
+}
+
+// What happened while loading a model, so the caller decides what to print
+pub struct LoadReport {
+    pub path: String,
+    pub parse_time: Duration, // Reading and parsing the file, or reading the cache
+    pub total_time: Duration, // Including splitting it into meshes
+    pub parts: Vec<PartSummary>,
+    pub sources: Vec<String>, // The files read, i.e. the OBJ file and its libraries, or the cache
+    pub warnings: Vec<ObjError>,
+    pub from_cache: bool,
+    pub cache_error: Option<MeshCacheError>, // Why the cache could not be read or written
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.from_cache {
+            writeln!(
+                f,
+                "Loaded {} from cache in {:.3}ms.",
+                self.path,
+                self.total_time.as_micros() as f32 / 1e3,
+            )?;
+        } else {
+            writeln!(
+                f,
+                "Loaded {} in {:.3}ms ({:.3}ms parsing).",
+                self.path,
+                self.total_time.as_micros() as f32 / 1e3,
+                self.parse_time.as_micros() as f32 / 1e3,
+            )?;
+        }
+        for part in &self.parts {
+            writeln!(
+                f,
+                "  {} with {} points and {} triangles.",
+                part.name, part.points, part.triangles
+            )?;
+        }
+        for warning in &self.warnings {
+            writeln!(f, "  Warning: {}", warning)?;
+        }
+        if let Some(error) = &self.cache_error {
+            writeln!(f, "  Warning: {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Model {
+    /**
+     * Like load_obj, but reuses the cache next to the OBJ file if it was read in the same mode
+     * and neither the OBJ file nor its .mtl files have changed since, and otherwise writes one
+     * for next time. Problems with the cache are reported, and never stop the OBJ file from
+     * loading.
+     */
+    pub fn load(path: &str, mode: ParseMode) -> Result<(Model, LoadReport), ObjError> {
+        let before = Instant::now();
+        let cache_path = MeshCache::path_for(path);
+
+        let mut cache_error = None;
+        if MeshCache::is_fresh(&cache_path, mode) {
+            match MeshCache::read(&cache_path) {
+                Ok(meshes) => {
+                    let parts: Vec<ModelPart> = meshes
+                        .into_iter()
+                        .map(|(name, mesh)| ModelPart { name, mesh })
+                        .collect();
+                    let report = LoadReport {
+                        path: path.to_string(),
+                        parse_time: before.elapsed(),
+                        total_time: before.elapsed(),
+                        parts: Model::summarize(&parts),
+                        sources: vec![cache_path.to_string_lossy().to_string()],
+                        warnings: vec![],
+                        from_cache: true,
+                        cache_error: None,
+                    };
+                    return Ok((Model { parts }, report));
+                }
+                Err(e) => cache_error = Some(e),
+            }
+        }
+
+        let (model, mut report) = Model::load_obj(path, mode)?;
+        let meshes: Vec<(&str, &Mesh)> = model
+            .parts
+            .iter()
+            .map(|p| (p.name.as_str(), &p.mesh))
+            .collect();
+        report.cache_error = MeshCache::write(&cache_path, mode, &report.sources, &meshes)
+            .err()
+            .or(cache_error);
+        report.total_time = before.elapsed();
+
+        Ok((model, report))
+    }
+
+    /**
+     * Loads the OBJ file, where in lenient mode unknown directives end up as warnings in the
+     * report. Parts are named after their `o` or `g` directive, and get their colour from the
+     * material.
+     */
+    pub fn load_obj(path: &str, mode: ParseMode) -> Result<(Model, LoadReport), ObjError> {
+        let before = Instant::now();
+        let mut obj = ObjReader::read(path, mode)?;
+        let parse_time = before.elapsed();
+
+        // Groups sharing a name, e.g. one per material, make up a single part
+        let mut names: Vec<String> = vec![];
+        for group in &obj.groups {
+            if !names.contains(&group.name) {
+                names.push(group.name.clone());
+            }
+        }
+
+        let parts: Vec<ModelPart> = names
+            .into_iter()
+            .filter_map(|name| {
+                let mesh = Mesh::from_obj(obj.sub_mesh(&name)?, None);
+                Some(ModelPart { name, mesh })
+            })
+            .collect();
+
+        // Material libraries are looked up next to the OBJ file, as ObjReader does
+        let directory = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+        let sources = std::iter::once(path.to_string())
+            .chain(
+                obj.material_libraries
+                    .iter()
+                    .map(|library| directory.join(library).to_string_lossy().to_string()),
+            )
+            .collect();
+
+        let report = LoadReport {
+            path: path.to_string(),
+            parse_time,
+            total_time: before.elapsed(),
+            parts: Model::summarize(&parts),
+            sources,
+            warnings: std::mem::take(&mut obj.warnings),
+            from_cache: false,
+            cache_error: None,
+        };
+
+        Ok((Model { parts }, report))
+    }
+
+    fn summarize(parts: &[ModelPart]) -> Vec<PartSummary> {
+        parts
+            .iter()
+            .map(|p| PartSummary {
+                name: p.name.clone(),
+                points: p.mesh.vertices.len() / 3,
+                triangles: p.mesh.indices.len() / 3,
+            })
+            .collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Mesh> {
+        self.parts.iter().find(|p| p.name == name).map(|p| &p.mesh)
+    }
+
+    // Removes the part from the model, handing over ownership of its mesh
+    #[allow(dead_code)]
+    pub fn take(&mut self, name: &str) -> Option<Mesh> {
+        let i = self.parts.iter().position(|p| p.name == name)?;
+        Some(self.parts.remove(i).mesh)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().map(|p| p.name.as_str())
+    }
+
+    // All parts as one mesh, with the indices of each part rebased onto the ones before it
+    #[allow(dead_code)]
+    pub fn merge(self) -> Mesh {
+        Mesh::merge(self.parts.into_iter().map(|p| p.mesh).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn two_part_model() -> Model {
+        let path = std::env::temp_dir().join("gloom_model_two_parts.obj");
+        std::fs::write(
+            &path,
+            "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 0 0 1\n\
+             o left\nf 1 2 3\no right\nf 1 2 4\nf 2 3 4\n",
+        )
+        .unwrap();
+        Model::load_obj(path.to_str().unwrap(), ParseMode::Strict)
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn parts_are_taken_by_name_and_merged_with_rebased_indices() {
+        let model = two_part_model();
+        assert_eq!(model.names().collect::<Vec<_>>(), ["left", "right"]);
+        let merged = model.merge();
+        assert_eq!(merged.vertices.len(), (3 + 4) * 3);
+        assert_eq!(merged.indices[..3], [0, 1, 2]);
+        assert!(merged.indices[3..].iter().all(|&i| (3..7).contains(&i)));
+
+        let mut model = two_part_model();
+        let right = model.take("right").unwrap();
+        assert_eq!(right.indices.len(), 6);
+        assert!(model.take("right").is_none());
+        assert_eq!(model.names().collect::<Vec<_>>(), ["left"]);
+    }
+}
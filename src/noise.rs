@@ -0,0 +1,169 @@
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+
+use crate::heightmap::Heightmap;
+
+// How the octaves of noise are combined
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FractalKind {
+    Standard, // Plain fractal Brownian motion, rolling hills
+    Ridged,   // Sharp crests where the noise crosses zero, mountain ranges
+    Billow,   // Rounded lumps with creases between them, dunes and clouds
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct NoiseSettings {
+    pub seed: u64,
+    pub octaves: u32,
+    pub frequency: f32,   // Of the first octave, in cycles per sample
+    pub lacunarity: f32,  // Frequency multiplier between octaves
+    pub persistence: f32, // Amplitude multiplier between octaves
+    pub kind: FractalKind,
+}
+
+impl Default for NoiseSettings {
+    fn default() -> Self {
+        NoiseSettings {
+            seed: 0,
+            octaves: 6,
+            frequency: 1. / 64.,
+            lacunarity: 2.0,
+            persistence: 0.5,
+            kind: FractalKind::Standard,
+        }
+    }
+}
+
+// Ken Perlin's improved gradient noise, with a permutation shuffled by the seed. ChaCha8 is
+// used rather than StdRng, whose algorithm may change between versions of rand, so that a seed
+// gives the same terrain for good.
+pub struct Perlin {
+    permutation: [u8; 512],
+}
+
+impl Perlin {
+    pub fn new(seed: u64) -> Perlin {
+        let mut table: Vec<u8> = (0..=255).collect();
+        table.shuffle(&mut ChaCha8Rng::seed_from_u64(seed));
+
+        // Doubled, so hashing a lattice point never has to wrap
+        let mut permutation = [0; 512];
+        for i in 0..512 {
+            permutation[i] = table[i % 256];
+        }
+        Perlin { permutation }
+    }
+
+    // Roughly between -1 and 1, and 0 at every lattice point
+    pub fn noise(&self, x: f32, y: f32) -> f32 {
+        let fade = |t: f32| t * t * t * (t * (t * 6. - 15.) + 10.);
+        let lerp = |t: f32, a: f32, b: f32| a + t * (b - a);
+        // One of eight directions, picked by the hash of the corner
+        let gradient = |hash: u8, x: f32, y: f32| match hash & 7 {
+            0 => x + y,
+            1 => x - y,
+            2 => -x + y,
+            3 => -x - y,
+            4 => x,
+            5 => -x,
+            6 => y,
+            _ => -y,
+        };
+
+        let (xf, yf) = (x.floor(), y.floor());
+        let (xi, yi) = ((xf as i32 & 255) as usize, (yf as i32 & 255) as usize);
+        let (x, y) = (x - xf, y - yf);
+        let (u, v) = (fade(x), fade(y));
+
+        let p = &self.permutation;
+        let (a, b) = (p[xi] as usize + yi, p[xi + 1] as usize + yi);
+        let result = lerp(
+            v,
+            lerp(u, gradient(p[a], x, y), gradient(p[b], x - 1., y)),
+            lerp(
+                u,
+                gradient(p[a + 1], x, y - 1.),
+                gradient(p[b + 1], x - 1., y - 1.),
+            ),
+        );
+        result * std::f32::consts::SQRT_2
+    }
+
+    // Octaves of noise summed according to the settings, ignoring their seed
+    pub fn fractal(&self, x: f32, y: f32, settings: &NoiseSettings) -> f32 {
+        let mut sum = 0.;
+        let mut amplitude = 1.;
+        let mut frequency = settings.frequency;
+        for octave in 0..settings.octaves {
+            // Offset each octave, so their zeros at the lattice points don't line up
+            let offset = octave as f32 * 17.31;
+            let n = self.noise(x * frequency + offset, y * frequency - offset);
+            sum += amplitude
+                * match settings.kind {
+                    FractalKind::Standard => n,
+                    FractalKind::Ridged => (1. - n.abs()).powi(2),
+                    FractalKind::Billow => 2. * n.abs() - 1.,
+                };
+            amplitude *= settings.persistence;
+            frequency *= settings.lacunarity;
+        }
+        sum
+    }
+}
+
+impl Heightmap {
+    // A heightmap of fractal noise, stretched to fill the range between 0 and 1
+    pub fn from_noise(width: usize, depth: usize, settings: &NoiseSettings) -> Heightmap {
+        let perlin = Perlin::new(settings.seed);
+        let mut heightmap = Heightmap::new(width, depth);
+        for z in 0..depth {
+            for x in 0..width {
+                heightmap.heights[z * width + x] = perlin.fractal(x as f32, z as f32, settings);
+            }
+        }
+        heightmap.normalize();
+        heightmap
+    }
+
+    pub fn normalize(&mut self) {
+        let min = self.heights.iter().cloned().fold(f32::INFINITY, f32::min);
+        let max = self
+            .heights
+            .iter()
+            .cloned()
+            .fold(f32::NEG_INFINITY, f32::max);
+        let range = max - min;
+        if range > 0. {
+            self.heights
+                .iter_mut()
+                .for_each(|h| *h = (*h - min) / range);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn noise_is_the_same_for_a_seed() {
+        let settings = NoiseSettings {
+            seed: 42,
+            octaves: 4,
+            frequency: 0.3,
+            ..NoiseSettings::default()
+        };
+        let map = Heightmap::from_noise(4, 4, &settings);
+        #[rustfmt::skip]
+        let expected = [
+            0.028108358, 0.9317307, 0.88134915, 0.6079729,
+            1.0, 0.9915426, 0.49300587, 0.1962824,
+            0.8810713, 0.7274851, 0.41447175, 0.0,
+            0.21096203, 0.47877964, 0.3774564, 0.10923556,
+        ];
+        for (height, expected) in map.heights.iter().zip(&expected) {
+            assert!((height - expected).abs() < 1e-5, "{:?}", map.heights);
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+
+use crate::mesh::Mesh;
+
+// How much each triangle contributes to the normal of the vertices it touches
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NormalWeighting {
+    Area,  // Large triangles dominate, cheap and fine for evenly tessellated meshes
+    Angle, // The angle of the triangle at the vertex, independent of tessellation
+}
+
+impl Mesh {
+    // Generates smooth normals if the mesh has none, so it does not render black
+    pub fn ensure_normals(&mut self) {
+        if self.normals.len() != self.vertices.len() {
+            self.compute_smooth_normals(NormalWeighting::Area);
+        }
+    }
+
+    // Every vertex gets the weighted average normal of all triangles sharing its position
+    pub fn compute_smooth_normals(&mut self, weighting: NormalWeighting) {
+        self.compute_creased_normals(std::f32::consts::PI, weighting);
+    }
+
+    // Every triangle gets its own three vertices, all with the normal of the triangle
+    pub fn compute_flat_normals(&mut self) {
+        let sources = self.indices.clone();
+        self.rebuild_vertices(&sources);
+        self.indices = (0..sources.len() as u32).collect();
+
+        self.normals = self
+            .indices
+            .chunks(3)
+            .flat_map(|t| std::iter::repeat_n(self.triangle_normal(t), 3))
+            .flat_map(|n| vec![n.x, n.y, n.z])
+            .collect();
+    }
+
+    /**
+     * Smooth normals, except across edges where the triangles meet at a sharper angle than
+     * crease_angle (in radians). Vertices on such edges are split, so each side keeps its own
+     * normal.
+     */
+    pub fn compute_creased_normals(&mut self, crease_angle: f32, weighting: NormalWeighting) {
+        let n_triangles = self.indices.len() / 3;
+        // Some slack, so that a crease angle of pi includes every triangle
+        let cos_crease = crease_angle.cos() - 1e-6;
+
+        // Vertices with identical positions are treated as one, so seams in e.g. texture
+        // coordinates or colours do not show up in the lighting
+        let mut position_ids: HashMap<[u32; 3], usize> = HashMap::new();
+        let mut corners_at_position: Vec<Vec<usize>> = vec![];
+        let mut corner_position = vec![0; self.indices.len()];
+        for (corner, &index) in self.indices.iter().enumerate() {
+            let p = self.position(index);
+            let key = [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()];
+            let id = *position_ids.entry(key).or_insert_with(|| {
+                corners_at_position.push(vec![]);
+                corners_at_position.len() - 1
+            });
+            corners_at_position[id].push(corner);
+            corner_position[corner] = id;
+        }
+
+        // Unit normal of every triangle, and the weight each corner contributes with
+        let mut face_normals = Vec::with_capacity(n_triangles);
+        let mut corner_weights = Vec::with_capacity(self.indices.len());
+        for t in self.indices.chunks(3) {
+            let cross = self.triangle_cross(t);
+            let area = glm::length(&cross);
+            face_normals.push(if area > 0. { cross / area } else { cross });
+
+            for k in 0..3 {
+                corner_weights.push(match weighting {
+                    NormalWeighting::Area => area,
+                    NormalWeighting::Angle => {
+                        let p = self.position(t[k]);
+                        let a = self.position(t[(k + 1) % 3]) - p;
+                        let b = self.position(t[(k + 2) % 3]) - p;
+                        if area > 0. {
+                            glm::angle(&a, &b)
+                        } else {
+                            0.
+                        }
+                    }
+                });
+            }
+        }
+
+        // Each corner averages the triangles around its position that are within the crease
+        // angle of its own triangle
+        let corner_normals: Vec<glm::Vec3> = (0..self.indices.len())
+            .map(|corner| {
+                let own = face_normals[corner / 3];
+                let sum = corners_at_position[corner_position[corner]]
+                    .iter()
+                    .filter(|&&other| glm::dot(&own, &face_normals[other / 3]) >= cos_crease)
+                    .fold(glm::zero::<glm::Vec3>(), |sum, &other| {
+                        sum + face_normals[other / 3] * corner_weights[other]
+                    });
+                if glm::length(&sum) > 0. {
+                    glm::normalize(&sum)
+                } else {
+                    own
+                }
+            })
+            .collect();
+
+        // Split vertices whose corners ended up with different normals
+        let mut sources = vec![];
+        let mut normals = vec![];
+        let mut split: HashMap<(u32, [u32; 3]), u32> = HashMap::new();
+        for (corner, index) in self.indices.iter_mut().enumerate() {
+            let n = corner_normals[corner];
+            let key = (*index, [n.x.to_bits(), n.y.to_bits(), n.z.to_bits()]);
+            *index = *split.entry(key).or_insert_with(|| {
+                sources.push(*index);
+                normals.extend(&[n.x, n.y, n.z]);
+                (sources.len() - 1) as u32
+            });
+        }
+
+        self.rebuild_vertices(&sources);
+        self.normals = normals;
+    }
+
+    // Unit normal of the triangle, or zero for degenerate triangles
+    pub fn triangle_normal(&self, triangle: &[u32]) -> glm::Vec3 {
+        let cross = self.triangle_cross(triangle);
+        let length = glm::length(&cross);
+        if length > 0. {
+            cross / length
+        } else {
+            cross
+        }
+    }
+
+    // Cross product of two triangle edges, with length twice the area of the triangle
+    pub fn triangle_cross(&self, triangle: &[u32]) -> glm::Vec3 {
+        let a = self.position(triangle[0]);
+        let b = self.position(triangle[1]);
+        let c = self.position(triangle[2]);
+        glm::cross(&(b - a), &(c - a))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A unit cube with its 8 corners shared by every face, at index x + 2y + 4z
+    fn cube() -> Mesh {
+        let vertices = (0..8)
+            .flat_map(|i| vec![(i & 1) as f32, ((i >> 1) & 1) as f32, ((i >> 2) & 1) as f32])
+            .collect();
+        #[rustfmt::skip]
+        let faces = [
+            [0, 2, 3, 1], [4, 5, 7, 6], // -z, +z
+            [0, 4, 6, 2], [1, 3, 7, 5], // -x, +x
+            [0, 1, 5, 4], [2, 6, 7, 3], // -y, +y
+        ];
+        let indices = faces
+            .iter()
+            .flat_map(|&[a, b, c, d]| vec![a, b, c, a, c, d])
+            .collect();
+        Mesh::from_shape((vertices, indices), [1., 1., 1., 1.])
+    }
+
+    fn normal(mesh: &Mesh, i: u32) -> glm::Vec3 {
+        let i = i as usize * 3;
+        glm::make_vec3(&mesh.normals[i..i + 3])
+    }
+
+    #[test]
+    fn creases_keep_the_edges_of_a_cube_hard() {
+        let mut mesh = cube();
+        mesh.compute_creased_normals(std::f32::consts::FRAC_PI_3, NormalWeighting::Area);
+
+        // Each corner is split in three, one per face
+        assert_eq!(mesh.vertex_count(), 24);
+        assert_eq!(mesh.indices.len(), 36);
+        for t in mesh.indices.chunks(3) {
+            let face_normal = mesh.triangle_normal(t);
+            assert_eq!(face_normal.iter().filter(|c| c.abs() == 1.).count(), 1);
+            for &i in t {
+                assert!(glm::distance(&normal(&mesh, i), &face_normal) < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn smooth_normals_point_away_from_the_center() {
+        // Angle weighting does not care how each face happens to be split into triangles
+        let mut mesh = cube();
+        mesh.compute_smooth_normals(NormalWeighting::Angle);
+
+        assert_eq!(mesh.vertex_count(), 8);
+        let center = glm::vec3(0.5, 0.5, 0.5);
+        for i in 0..8 {
+            let outwards = glm::normalize(&(mesh.position(i) - center));
+            assert!(glm::distance(&normal(&mesh, i), &outwards) < 1e-5);
+        }
+    }
+
+    #[test]
+    fn flat_normals_give_every_triangle_its_own_vertices() {
+        let mut mesh = cube();
+        mesh.compute_flat_normals();
+
+        assert_eq!(mesh.vertex_count(), 36);
+        for t in mesh.indices.chunks(3) {
+            for &i in t {
+                assert_eq!(normal(&mesh, i), mesh.triangle_normal(t));
+            }
+        }
+    }
+}
@@ -1,10 +1,21 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::path::Path;
 
+use crate::material::{Material, MtlReader};
+
 pub struct ObjReader {}
 
 pub enum ObjEntry {
     Vertex,
+    TextureCoordinate,
+    Normal,
     Face,
+    Object,
+    Group,
+    UseMaterial,
+    Smoothing,
+    MaterialLibrary,
     Comment,
 }
 
@@ -12,70 +23,909 @@ impl ObjEntry {
     fn from_code(code: &str) -> Result<ObjEntry, String> {
         match code {
             "v" => Ok(ObjEntry::Vertex),
+            "vt" => Ok(ObjEntry::TextureCoordinate),
+            "vn" => Ok(ObjEntry::Normal),
             "f" => Ok(ObjEntry::Face),
-            "#" => Ok(ObjEntry::Comment),
+            "o" => Ok(ObjEntry::Object),
+            "g" => Ok(ObjEntry::Group),
+            "usemtl" => Ok(ObjEntry::UseMaterial),
+            "s" => Ok(ObjEntry::Smoothing),
+            "mtllib" => Ok(ObjEntry::MaterialLibrary),
+            c if c.starts_with('#') => Ok(ObjEntry::Comment),
             e => Err(e.to_string()),
         }
     }
 }
 
+// Decides what happens to directives the reader does not understand
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ParseMode {
+    Strict,  // Unknown directives are errors
+    Lenient, // Unknown directives are collected as warnings and skipped
+}
+
+// Where in a file something went wrong. Line and column are 1-based.
+#[derive(Clone, Debug)]
+pub struct ObjLocation {
+    pub path: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug)]
+pub enum ObjError {
+    Extension {
+        path: String,
+        expected: &'static str,
+    },
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    Syntax {
+        location: ObjLocation,
+        token: String,
+        expected: &'static str,
+    },
+    UnknownDirective {
+        location: ObjLocation,
+        token: String,
+    },
+    IndexOutOfRange {
+        location: ObjLocation,
+        token: String,
+    },
+}
+
+impl ObjLocation {
+    // Tokens are always slices of the line, so their column can be found from the pointers
+    pub(crate) fn of(path: &str, line_number: usize, line: &str, token: &str) -> ObjLocation {
+        ObjLocation {
+            path: path.to_string(),
+            line: line_number,
+            column: token.as_ptr() as usize - line.as_ptr() as usize + 1,
+        }
+    }
+}
+
+impl fmt::Display for ObjLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.path, self.line, self.column)
+    }
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjError::Extension { path, expected } => {
+                write!(
+                    f,
+                    "{}: expected a file with the .{} extension",
+                    path, expected
+                )
+            }
+            ObjError::Io { path, source } => write!(f, "{}: failed to read file: {}", path, source),
+            ObjError::Syntax {
+                location,
+                token,
+                expected,
+            } if token.is_empty() => {
+                write!(f, "{}: expected {}, found end of line", location, expected)
+            }
+            ObjError::Syntax {
+                location,
+                token,
+                expected,
+            } => write!(f, "{}: expected {}, found `{}`", location, expected, token),
+            ObjError::UnknownDirective { location, token } => {
+                write!(f, "{}: unknown directive `{}`", location, token)
+            }
+            ObjError::IndexOutOfRange { location, token } => {
+                write!(f, "{}: index `{}` is out of range", location, token)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ObjError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ObjError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+// Single-indexed vertex stream, i.e. every index refers to the same position in each attribute
+// array. Texture coordinates, normals and colors are left empty if the file does not use them.
+pub struct ObjMesh {
+    pub vertices: Vec<f32>,
+    pub colors: Vec<f32>, // RGBA, from the common `v x y z r g b` extension
+    pub texcoords: Vec<f32>,
+    pub normals: Vec<f32>,
+    pub indices: Vec<u32>,
+    pub groups: Vec<ObjGroup>,
+    pub material_libraries: Vec<String>, // As given by `mtllib`, relative to the .obj file
+    pub materials: Vec<Material>,        // Only filled in when read from a file
+    pub warnings: Vec<ObjError>,         // Skipped directives when parsed in lenient mode
+}
+
+// A named part of the file, i.e. the faces following an `o` or `g` directive. A new group is
+// started whenever the name or material changes, so one object can span several groups with
+// the same name.
+#[derive(Clone, Debug)]
+pub struct ObjGroup {
+    pub name: String, // Name of the last `g`, or `o` if no group has been given since
+    pub material: Option<String>, // Set by `usemtl`
+    pub index_start: usize, // First index of the group in ObjMesh::indices
+    pub index_count: usize,
+}
+
+impl ObjMesh {
+    pub fn material(&self, name: &str) -> Option<&Material> {
+        self.materials.iter().find(|m| m.name == name)
+    }
+
+    // Every group with the given name, e.g. one per material of a multi-material object
+    pub fn groups_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a ObjGroup> + 'a {
+        self.groups.iter().filter(move |g| g.name == name)
+    }
+
+    pub fn group_indices(&self, group: &ObjGroup) -> &[u32] {
+        &self.indices[group.index_start..group.index_start + group.index_count]
+    }
+
+    // Copies out every group with the given name as a standalone mesh, keeping only the vertices
+    // those groups reference
+    pub fn sub_mesh(&self, name: &str) -> Option<ObjMesh> {
+        let mut sub_mesh = ObjMesh {
+            vertices: vec![],
+            colors: vec![],
+            texcoords: vec![],
+            normals: vec![],
+            indices: vec![],
+            groups: vec![],
+            material_libraries: self.material_libraries.clone(),
+            materials: vec![],
+            warnings: vec![],
+        };
+        let mut remap: HashMap<u32, u32> = HashMap::new();
+
+        for group in self.groups_named(name) {
+            let index_start = sub_mesh.indices.len();
+            for &index in self.group_indices(group) {
+                let new_index = *remap.entry(index).or_insert_with(|| {
+                    let i = index as usize;
+                    sub_mesh
+                        .vertices
+                        .extend_from_slice(&self.vertices[i * 3..i * 3 + 3]);
+                    if !self.colors.is_empty() {
+                        sub_mesh
+                            .colors
+                            .extend_from_slice(&self.colors[i * 4..i * 4 + 4]);
+                    }
+                    if !self.texcoords.is_empty() {
+                        sub_mesh
+                            .texcoords
+                            .extend_from_slice(&self.texcoords[i * 2..i * 2 + 2]);
+                    }
+                    if !self.normals.is_empty() {
+                        sub_mesh
+                            .normals
+                            .extend_from_slice(&self.normals[i * 3..i * 3 + 3]);
+                    }
+                    (sub_mesh.vertices.len() / 3 - 1) as u32
+                });
+                sub_mesh.indices.push(new_index);
+            }
+            sub_mesh.groups.push(ObjGroup {
+                index_start,
+                ..group.clone()
+            });
+        }
+
+        // Only keep the materials that are used by the groups
+        sub_mesh.materials = self
+            .materials
+            .iter()
+            .filter(|m| {
+                sub_mesh
+                    .groups
+                    .iter()
+                    .any(|g| g.material.as_ref() == Some(&m.name))
+            })
+            .cloned()
+            .collect();
+
+        if sub_mesh.groups.is_empty() {
+            None
+        } else {
+            Some(sub_mesh)
+        }
+    }
+}
+
+// A face corner as written in the file, i.e. `v`, `v/vt`, `v//vn` or `v/vt/vn`,
+// resolved to 0-based indices into the raw attribute lists
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct FaceVertex {
+    position: usize,
+    texcoord: Option<usize>,
+    normal: Option<usize>,
+}
+
+// Holds the state needed to turn one file into an ObjMesh, and knows where it is in the file
+// so every error can point at the offending token
+struct ObjParser<'a> {
+    path: &'a str,
+    mode: ParseMode,
+    line_number: usize,
+    line: &'a str,
+
+    // Raw attribute lists, indexed separately by the faces
+    positions: Vec<[f32; 3]>,
+    colors: Vec<[f32; 4]>,
+    texcoords: Vec<[f32; 2]>,
+    normals: Vec<[f32; 3]>,
+
+    mesh: ObjMesh,
+    uses_colors: bool,
+    uses_texcoords: bool,
+    uses_normals: bool,
+    vertex_lookup: HashMap<FaceVertex, u32>,
+}
+
+impl<'a> ObjParser<'a> {
+    fn new(path: &'a str, mode: ParseMode) -> ObjParser<'a> {
+        ObjParser {
+            path,
+            mode,
+            line_number: 0,
+            line: "",
+            positions: vec![],
+            colors: vec![],
+            texcoords: vec![],
+            normals: vec![],
+            mesh: ObjMesh {
+                vertices: vec![],
+                colors: vec![],
+                texcoords: vec![],
+                normals: vec![],
+                indices: vec![],
+                groups: vec![ObjGroup {
+                    name: "default".to_string(),
+                    material: None,
+                    index_start: 0,
+                    index_count: 0,
+                }],
+                material_libraries: vec![],
+                materials: vec![],
+                warnings: vec![],
+            },
+            uses_colors: false,
+            uses_texcoords: false,
+            uses_normals: false,
+            vertex_lookup: HashMap::new(),
+        }
+    }
+
+    fn locate(&self, token: &str) -> ObjLocation {
+        ObjLocation::of(self.path, self.line_number, self.line, token)
+    }
+
+    fn syntax_error(&self, token: &str, expected: &'static str) -> ObjError {
+        ObjError::Syntax {
+            location: self.locate(token),
+            token: token.to_string(),
+            expected,
+        }
+    }
+
+    fn parse_floats(&self, lexemes: &[&str], min: usize, max: usize) -> Result<Vec<f32>, ObjError> {
+        if lexemes.len() - 1 < min {
+            let last = lexemes[lexemes.len() - 1];
+            return Err(self.syntax_error(&last[last.len()..], "another component"));
+        }
+
+        lexemes[1..]
+            .iter()
+            .take(max)
+            .map(|&lex| lex.parse().map_err(|_| self.syntax_error(lex, "a number")))
+            .collect()
+    }
+
+    // OBJ indices are 1-based, while negative indices are relative to the end of the list read
+    // so far
+    fn resolve_index(&self, lex: &str, n_read: usize) -> Result<usize, ObjError> {
+        let index: i64 = lex
+            .parse()
+            .map_err(|_| self.syntax_error(lex, "an integer index"))?;
+        let resolved = if index < 0 {
+            n_read as i64 + index
+        } else {
+            index - 1
+        };
+
+        if resolved < 0 || resolved >= n_read as i64 {
+            Err(ObjError::IndexOutOfRange {
+                location: self.locate(lex),
+                token: lex.to_string(),
+            })
+        } else {
+            Ok(resolved as usize)
+        }
+    }
+
+    fn parse_face_vertex(&self, lex: &str) -> Result<FaceVertex, ObjError> {
+        let mut parts = lex.split('/');
+
+        let position = match parts.next().filter(|p| !p.is_empty()) {
+            Some(p) => self.resolve_index(p, self.positions.len())?,
+            None => return Err(self.syntax_error(lex, "a position index")),
+        };
+        let texcoord = match parts.next().filter(|t| !t.is_empty()) {
+            Some(t) => Some(self.resolve_index(t, self.texcoords.len())?),
+            None => None,
+        };
+        let normal = match parts.next().filter(|n| !n.is_empty()) {
+            Some(n) => Some(self.resolve_index(n, self.normals.len())?),
+            None => None,
+        };
+
+        Ok(FaceVertex {
+            position,
+            texcoord,
+            normal,
+        })
+    }
+
+    fn parse_line(&mut self, line_number: usize, line: &'a str) -> Result<(), ObjError> {
+        self.line_number = line_number;
+        self.line = line;
+
+        let lexemes: Vec<&str> = line.split_whitespace().collect();
+        if lexemes.is_empty() {
+            return Ok(());
+        }
+
+        // Map string code to an entry and append data to corresponding vector
+        match ObjEntry::from_code(lexemes[0]) {
+            Ok(ObjEntry::Vertex) => {
+                // Vertices without a colour are white, in case other vertices have one
+                let v = self.parse_floats(&lexemes, 3, 6)?;
+                self.positions.push([v[0], v[1], v[2]]);
+                if v.len() == 6 {
+                    self.uses_colors = true;
+                    self.colors.push([v[3], v[4], v[5], 1.]);
+                } else {
+                    self.colors.push([1.; 4]);
+                }
+            }
+            Ok(ObjEntry::TextureCoordinate) => {
+                // The optional w-component is dropped
+                let vt = self.parse_floats(&lexemes, 1, 2)?;
+                self.texcoords.push([vt[0], *vt.get(1).unwrap_or(&0.)]);
+            }
+            Ok(ObjEntry::Normal) => {
+                let vn = self.parse_floats(&lexemes, 3, 3)?;
+                self.normals.push([vn[0], vn[1], vn[2]]);
+            }
+            Ok(ObjEntry::Face) => {
+                if lexemes.len() < 4 {
+                    let last = lexemes[lexemes.len() - 1];
+                    return Err(self.syntax_error(&last[last.len()..], "at least three vertices"));
+                }
+
+                let mut corners = vec![];
+                let mut polygon = vec![];
+                for &lex in &lexemes[1..] {
+                    let face_vertex = self.parse_face_vertex(lex)?;
+                    polygon.push(self.positions[face_vertex.position]);
+                    corners.push(self.output_vertex(face_vertex));
+                }
+
+                // Everything is drawn as gl::TRIANGLES, so quads and n-gons are split up here
+                let n_indices = self.mesh.indices.len();
+                for triangle in triangulate(&polygon) {
+                    self.mesh
+                        .indices
+                        .extend(triangle.iter().map(|&corner| corners[corner]));
+                }
+                self.current_group().index_count += self.mesh.indices.len() - n_indices;
+            }
+            Ok(ObjEntry::Object) | Ok(ObjEntry::Group) => {
+                // Names may contain spaces, and groups without a name fall back to "default"
+                let name = match lexemes.len() {
+                    1 => "default".to_string(),
+                    _ => lexemes[1..].join(" "),
+                };
+                let group = self.current_group().clone();
+                self.begin_group(ObjGroup { name, ..group });
+            }
+            Ok(ObjEntry::UseMaterial) => {
+                let material = Some(lexemes[1..].join(" "));
+                let group = self.current_group().clone();
+                self.begin_group(ObjGroup { material, ..group });
+            }
+            Ok(ObjEntry::Smoothing) => {
+                // Only checked, since normals come from the file or are smoothed by crease angle
+                match lexemes.get(1) {
+                    Some(&"off") => (),
+                    Some(&lex) => {
+                        lex.parse::<u32>()
+                            .map_err(|_| self.syntax_error(lex, "a smoothing group or `off`"))?;
+                    }
+                    None => return Err(self.syntax_error(&line[line.len()..], "a smoothing group")),
+                }
+            }
+            Ok(ObjEntry::MaterialLibrary) => {
+                if lexemes.len() < 2 {
+                    return Err(self.syntax_error(&line[line.len()..], "a material library"));
+                }
+                self.mesh
+                    .material_libraries
+                    .extend(lexemes[1..].iter().map(|l| l.to_string()));
+            }
+            Ok(ObjEntry::Comment) => (),
+            Err(_) => {
+                let error = ObjError::UnknownDirective {
+                    location: self.locate(lexemes[0]),
+                    token: lexemes[0].to_string(),
+                };
+                match self.mode {
+                    ParseMode::Strict => return Err(error),
+                    ParseMode::Lenient => self.mesh.warnings.push(error),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn current_group(&mut self) -> &mut ObjGroup {
+        self.mesh.groups.last_mut().unwrap()
+    }
+
+    // Starts a new group at the end of the index list, unless nothing has been added to the
+    // current one yet, in which case it is simply replaced
+    fn begin_group(&mut self, group: ObjGroup) {
+        let index_start = self.mesh.indices.len();
+        let group = ObjGroup {
+            index_start,
+            index_count: 0,
+            ..group
+        };
+
+        let current = self.current_group();
+        if current.index_count == 0 {
+            *current = group;
+        } else {
+            self.mesh.groups.push(group);
+        }
+    }
+
+    // Reuses the output vertex if the same triplet has been seen before
+    fn output_vertex(&mut self, face_vertex: FaceVertex) -> u32 {
+        self.uses_texcoords |= face_vertex.texcoord.is_some();
+        self.uses_normals |= face_vertex.normal.is_some();
+
+        if let Some(&index) = self.vertex_lookup.get(&face_vertex) {
+            return index;
+        }
+
+        let position = self.positions[face_vertex.position];
+        let color = self.colors[face_vertex.position];
+        let texcoord = face_vertex.texcoord.map_or([0.; 2], |t| self.texcoords[t]);
+        let normal = face_vertex.normal.map_or([0.; 3], |n| self.normals[n]);
+
+        let mesh = &mut self.mesh;
+        mesh.vertices.extend(&position);
+        mesh.colors.extend(&color);
+        mesh.texcoords.extend(&texcoord);
+        mesh.normals.extend(&normal);
+
+        let index = (mesh.vertices.len() / 3 - 1) as u32;
+        self.vertex_lookup.insert(face_vertex, index);
+        index
+    }
+
+    fn finish(mut self) -> ObjMesh {
+        if !self.uses_colors {
+            self.mesh.colors.clear();
+        }
+        if !self.uses_texcoords {
+            self.mesh.texcoords.clear();
+        }
+        if !self.uses_normals {
+            self.mesh.normals.clear();
+        }
+        self.mesh.groups.retain(|g| g.index_count > 0);
+        self.mesh
+    }
+}
+
+// Splits a polygon into triangles, returned as indices into the polygon's corners. Convex
+// polygons are fanned out from the first corner, while concave ones are ear clipped in the
+// plane the polygon is projected onto.
+pub(crate) fn triangulate(polygon: &[[f32; 3]]) -> Vec<[usize; 3]> {
+    let n = polygon.len();
+    if n == 3 {
+        return vec![[0, 1, 2]];
+    }
+
+    // Newell's method gives a robust normal even for slightly non-planar polygons
+    let mut normal = [0f32; 3];
+    for i in 0..n {
+        let (a, b) = (polygon[i], polygon[(i + 1) % n]);
+        normal[0] += (a[1] - b[1]) * (a[2] + b[2]);
+        normal[1] += (a[2] - b[2]) * (a[0] + b[0]);
+        normal[2] += (a[0] - b[0]) * (a[1] + b[1]);
+    }
+
+    // Project onto the axis-aligned plane most parallel to the polygon, by dropping the dominant
+    // axis of the normal. Flip the winding if needed so the polygon is counter-clockwise in 2D.
+    // NaN positions compare as equal, so a broken face gets some triangles instead of a panic.
+    let dominant = (0..3)
+        .max_by(|&i, &j| {
+            normal[i]
+                .abs()
+                .partial_cmp(&normal[j].abs())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+        .unwrap();
+    let (u, v) = ((dominant + 1) % 3, (dominant + 2) % 3);
+    let winding = if normal[dominant] < 0. { -1. } else { 1. };
+    let points: Vec<[f32; 2]> = polygon.iter().map(|p| [p[u], p[v] * winding]).collect();
+
+    let cross = |o: [f32; 2], a: [f32; 2], b: [f32; 2]| {
+        (a[0] - o[0]) * (b[1] - o[1]) - (a[1] - o[1]) * (b[0] - o[0])
+    };
+
+    let is_convex =
+        (0..n).all(|i| cross(points[i], points[(i + 1) % n], points[(i + 2) % n]) >= 0.);
+    if is_convex {
+        return (1..n - 1).map(|i| [0, i, i + 1]).collect();
+    }
+
+    // Ear clipping: repeatedly cut off a convex corner whose triangle contains no other corner
+    let mut remaining: Vec<usize> = (0..n).collect();
+    let mut triangles = vec![];
+    while remaining.len() > 3 {
+        let m = remaining.len();
+        let ear = (0..m).find(|&i| {
+            let (a, b, c) = (
+                remaining[(i + m - 1) % m],
+                remaining[i],
+                remaining[(i + 1) % m],
+            );
+            if cross(points[a], points[b], points[c]) <= 0. {
+                return false;
+            }
+            remaining.iter().all(|&p| {
+                p == a
+                    || p == b
+                    || p == c
+                    || cross(points[a], points[b], points[p]) < 0.
+                    || cross(points[b], points[c], points[p]) < 0.
+                    || cross(points[c], points[a], points[p]) < 0.
+            })
+        });
+
+        // Degenerate or self-intersecting polygons may have no proper ear left, so just take one
+        let i = ear.unwrap_or(0);
+        triangles.push([
+            remaining[(i + m - 1) % m],
+            remaining[i],
+            remaining[(i + 1) % m],
+        ]);
+        remaining.remove(i);
+    }
+    triangles.push([remaining[0], remaining[1], remaining[2]]);
+
+    triangles
+}
+
 impl ObjReader {
-    pub fn read(obj_path: &str) -> (Vec<f32>, Vec<u32>) {
+    pub fn read(obj_path: &str, mode: ParseMode) -> Result<ObjMesh, ObjError> {
         // Mainly inspired from shared_generator
         let path = Path::new(obj_path);
 
-        // Attempt to retrieve extension
-        if let Some(extension) = path.extension() {
-            // See if extension is obj
-            match extension.to_str().expect("Failed to read extension.") {
-                "obj" => Ok(()),
-                e => Err(e.to_string()),
+        // See if extension is obj
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("obj") => (),
+            _ => {
+                return Err(ObjError::Extension {
+                    path: obj_path.to_string(),
+                    expected: "obj",
+                })
             }
-            .expect("Failed to parse extension.");
+        }
 
-            // If obj, read rest of file
-            let obj_src = std::fs::read_to_string(path)
-                .expect(&format!("Failed to read shader source. {}", obj_path));
+        // If obj, read rest of file
+        let obj_src = std::fs::read_to_string(path).map_err(|source| ObjError::Io {
+            path: obj_path.to_string(),
+            source,
+        })?;
 
-            // Pass string-data to dedicated parser and return it
-            ObjReader::parse_obj_src(&obj_src)
-        } else {
-            panic!("Failed to read extension of file with path: {}", obj_path);
+        // Pass string-data to dedicated parser
+        let mut mesh = ObjReader::parse_named_src(obj_path, &obj_src, mode)?;
+
+        // Material libraries are looked up next to the .obj file. A missing library only leaves
+        // the mesh uncoloured, so in lenient mode it is reported as a warning.
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        for library in &mesh.material_libraries {
+            let mtl_path = directory.join(library).to_string_lossy().to_string();
+            match MtlReader::read(&mtl_path, mode) {
+                Ok((materials, warnings)) => {
+                    mesh.materials.extend(materials);
+                    mesh.warnings.extend(warnings);
+                }
+                Err(e) if mode == ParseMode::Lenient => mesh.warnings.push(e),
+                Err(e) => return Err(e),
+            }
         }
+
+        Ok(mesh)
+    }
+
+    #[cfg(test)]
+    pub fn parse_obj_src(obj_src: &str, mode: ParseMode) -> Result<ObjMesh, ObjError> {
+        ObjReader::parse_named_src("<source>", obj_src, mode)
     }
 
     /**
      * Assumes winding of faces are consistent (i.e. does not use normals).
+     * Each unique position/texcoord/normal triplet becomes one output vertex, and the returned
+     * indices are 0-based triangles ready for create_vao.
      */
-    pub fn parse_obj_src(obj_src: &str) -> (Vec<f32>, Vec<u32>) {
-        let mut verticies = vec![];
-        let mut indicies = vec![];
+    fn parse_named_src(path: &str, obj_src: &str, mode: ParseMode) -> Result<ObjMesh, ObjError> {
+        let mut parser = ObjParser::new(path, mode);
 
         // Iterate over each entry
-        for line in obj_src.lines() {
-            let lexemes: Vec<&str> = line.split_whitespace().collect();
-
-            // Map string code to either vertex or face and append data to corresponding vector
-            match ObjEntry::from_code(&lexemes[0]) {
-                Ok(ObjEntry::Vertex) => verticies.append(
-                    &mut lexemes[1..]
-                        .iter()
-                        .map(|&lex| lex.parse().unwrap())
-                        .collect(),
-                ),
-                Ok(ObjEntry::Face) => indicies.append(
-                    &mut lexemes[1..]
-                        .iter()
-                        .map(|&lex| lex.parse().unwrap())
-                        .collect(),
-                ),
-                Ok(ObjEntry::Comment) => (),
-                Err(s) => {
-                    eprintln!("Unknown entry: {}", s)
-                }
+        for (i, line) in obj_src.lines().enumerate() {
+            parser.parse_line(i + 1, line)?;
+        }
+
+        Ok(parser.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::MtlReader;
+
+    const UNKNOWN_DIRECTIVE: &str = "v 0 0 0\nv 1 0 0\nv 0 1 0\n  foo 1 2\nf 1 2 3\n";
+
+    #[test]
+    fn strict_rejects_unknown_directives() {
+        match ObjReader::parse_obj_src(UNKNOWN_DIRECTIVE, ParseMode::Strict) {
+            Err(ObjError::UnknownDirective { location, token }) => {
+                assert_eq!(token, "foo");
+                assert_eq!((location.line, location.column), (4, 3));
             }
+            other => panic!("expected an unknown directive, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn lenient_warns_about_unknown_directives() {
+        let mesh = ObjReader::parse_obj_src(UNKNOWN_DIRECTIVE, ParseMode::Lenient).unwrap();
+        assert_eq!(mesh.indices, vec![0, 1, 2]);
+        assert_eq!(mesh.warnings.len(), 1);
+        assert_eq!(
+            mesh.warnings[0].to_string(),
+            "<source>:4:3: unknown directive `foo`"
+        );
+    }
+
+    #[test]
+    fn both_modes_reject_syntax_errors() {
+        for &mode in &[ParseMode::Strict, ParseMode::Lenient] {
+            let result = ObjReader::parse_obj_src("v 0 x 0\n", mode);
+            assert!(matches!(result, Err(ObjError::Syntax { .. })));
+        }
+    }
+
+    #[test]
+    fn strict_rejects_unknown_material_directives() {
+        let mtl_src = "newmtl red\nKd 1 0 0\nPr 0.5\n";
+        assert!(matches!(
+            MtlReader::parse_mtl_src(mtl_src, ParseMode::Strict),
+            Err(ObjError::UnknownDirective { .. })
+        ));
+        let materials = MtlReader::parse_mtl_src(mtl_src, ParseMode::Lenient).unwrap();
+        assert_eq!(materials[0].diffuse, [1., 0., 0.]);
+    }
+
+    #[test]
+    fn faces_with_texcoords_and_normals_are_de_indexed() {
+        let src = "v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvt 1 0\nvt 0 1\nvn 0 0 1\n\
+                   f 1/1/1 2/2/1 3/3/1\nf 1/1/1 3/3/1 2/3/1\n";
+        let mesh = ObjReader::parse_obj_src(src, ParseMode::Strict).unwrap();
+
+        // The repeated triplets share a vertex, while `2/3/1` needs a new one
+        assert_eq!(mesh.indices, vec![0, 1, 2, 0, 2, 3]);
+        assert_eq!(mesh.vertices.len(), 4 * 3);
+        assert_eq!(&mesh.vertices[9..], &[1., 0., 0.]);
+        assert_eq!(mesh.texcoords, vec![0., 0., 1., 0., 0., 1., 0., 1.]);
+        assert_eq!(mesh.normals, [0., 0., 1.].repeat(4));
+        assert!(mesh.colors.is_empty());
+    }
+
+    #[test]
+    fn faces_without_texcoords_leave_them_empty() {
+        let src = "v 0 0 0\nv 1 0 0\nv 0 1 0\nvn 0 0 1\nf 1//1 2//1 3//1\n";
+        let mesh = ObjReader::parse_obj_src(src, ParseMode::Strict).unwrap();
+        assert_eq!(mesh.indices, vec![0, 1, 2]);
+        assert!(mesh.texcoords.is_empty());
+        assert_eq!(mesh.normals, [0., 0., 1.].repeat(3));
+    }
+
+    #[test]
+    fn negative_indices_count_back_from_the_last_element() {
+        let src = "v 9 9 9\nv 0 0 0\nv 1 0 0\nv 0 1 0\nvn 0 0 1\nf -3//-1 -2//-1 -1//-1\n";
+        let mesh = ObjReader::parse_obj_src(src, ParseMode::Strict).unwrap();
+        assert_eq!(mesh.vertices, vec![0., 0., 0., 1., 0., 0., 0., 1., 0.]);
+
+        // Relative to what has been read so far, not to the whole file
+        let src = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf -3 -2 -1\nv 5 5 5\nf -4 -3 -1\n";
+        let mesh = ObjReader::parse_obj_src(src, ParseMode::Strict).unwrap();
+        assert_eq!(mesh.indices, vec![0, 1, 2, 0, 1, 3]);
+        assert_eq!(&mesh.vertices[9..], &[5., 5., 5.]);
+    }
+
+    #[test]
+    fn indices_out_of_range_are_rejected() {
+        for face in &["f 1 2 4", "f 0 1 2", "f -4 1 2", "f 1/2 2 3", "f 1//1 2 3"] {
+            let src = format!("v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\n{}\n", face);
+            assert!(
+                matches!(
+                    ObjReader::parse_obj_src(&src, ParseMode::Lenient),
+                    Err(ObjError::IndexOutOfRange { .. })
+                ),
+                "{}",
+                face
+            );
+        }
+    }
+
+    #[test]
+    fn triangulate_survives_nan_positions() {
+        let quad = [[0., 0., 0.], [1., 0., 0.], [f32::NAN, 1., 0.], [0., 1., 0.]];
+        let triangles = triangulate(&quad);
+        assert_eq!(triangles.len(), 2);
+        assert!(triangles.iter().flatten().all(|&i| i < quad.len()));
+
+        let mesh = ObjReader::parse_obj_src(
+            "v 0 0 0\nv 1 0 0\nv nan 1 0\nv 0 1 0\nf 1 2 3 4\n",
+            ParseMode::Strict,
+        )
+        .unwrap();
+        assert_eq!(mesh.indices.len(), 6);
+    }
+
+    // Twice the signed area of each triangle in the xy-plane
+    fn doubled_areas(polygon: &[[f32; 3]], triangles: &[[usize; 3]]) -> Vec<f32> {
+        triangles
+            .iter()
+            .map(|&[a, b, c]| {
+                let (a, b, c) = (polygon[a], polygon[b], polygon[c]);
+                (b[0] - a[0]) * (c[1] - a[1]) - (b[1] - a[1]) * (c[0] - a[0])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn convex_faces_are_fanned_from_the_first_corner() {
+        let src = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv -1 0.5 0\nf 1 2 3 4\nf 1 2 3 4 5\n";
+        let mesh = ObjReader::parse_obj_src(src, ParseMode::Strict).unwrap();
+        #[rustfmt::skip]
+        assert_eq!(mesh.indices, vec![
+            0, 1, 2,  0, 2, 3,
+            0, 1, 2,  0, 2, 3,  0, 3, 4,
+        ]);
+    }
+
+    #[test]
+    fn concave_faces_are_ear_clipped() {
+        // A chevron with a flat top and the notch at corner 1, so a fan from corner 0 would cover
+        // the notch. Every rotation of the corners has to work, not just the ones that happen to
+        // fan correctly.
+        #[rustfmt::skip]
+        let chevron = [
+            [-1., 0., 0.], [0., 1., 0.], [1., 0., 0.], [0.5, 3., 0.], [-0.5, 3., 0.],
+        ];
+        for start in 0..chevron.len() {
+            let polygon: Vec<[f32; 3]> = chevron
+                .iter()
+                .cycle()
+                .skip(start)
+                .take(5)
+                .cloned()
+                .collect();
+            let triangles = triangulate(&polygon);
+            assert_eq!(triangles.len(), 3);
+
+            // Every triangle keeps the polygon's winding, and together they cover its area
+            let areas = doubled_areas(&polygon, &triangles);
+            assert!(areas.iter().all(|&a| a > 0.), "{:?}", triangles);
+            assert!((areas.iter().sum::<f32>() - 7.).abs() < 1e-5);
         }
+    }
+
+    #[test]
+    fn clockwise_and_tilted_faces_keep_their_winding() {
+        // The same chevron, in the xz-plane and wound the other way
+        #[rustfmt::skip]
+        let chevron = [
+            [-1., 0., 0.], [-0.5, 0., 3.], [0.5, 0., 3.], [1., 0., 0.], [0., 0., 1.],
+        ];
+        let triangles = triangulate(&chevron);
+        assert_eq!(triangles.len(), 3);
 
-        (verticies, indicies)
+        let flat: Vec<[f32; 3]> = chevron.iter().map(|p| [p[0], p[2], 0.]).collect();
+        let areas = doubled_areas(&flat, &triangles);
+        assert!(areas.iter().all(|&a| a < 0.), "{:?}", triangles);
+        assert!((areas.iter().sum::<f32>() + 7.).abs() < 1e-5);
+    }
+
+    const TWO_PARTS: &str = "\
+v 0 0 0\nv 1 0 0\nv 0 1 0\nv 1 1 0\nv 2 2 2\n\
+o Body\nusemtl red\nf 1 2 3\ns 1\nusemtl blue\nf 2 4 3\n\
+g Door\nf 4 5 3\ns off\n\
+g Body\nusemtl red\nf 1 3 5\n";
+
+    #[test]
+    fn groups_are_split_by_name_and_material() {
+        let mesh = ObjReader::parse_obj_src(TWO_PARTS, ParseMode::Strict).unwrap();
+        let groups: Vec<(&str, Option<&str>, usize, usize)> = mesh
+            .groups
+            .iter()
+            .map(|g| {
+                let material = g.material.as_deref();
+                (g.name.as_str(), material, g.index_start, g.index_count)
+            })
+            .collect();
+
+        // `s` does not start a group, and a group keeps the material of the one before it
+        #[rustfmt::skip]
+        assert_eq!(groups, vec![
+            ("Body", Some("red"),  0, 3),
+            ("Body", Some("blue"), 3, 3),
+            ("Door", Some("blue"), 6, 3),
+            ("Body", Some("red"),  9, 3),
+        ]);
+        assert_eq!(mesh.groups_named("Body").count(), 3);
+        assert_eq!(mesh.group_indices(&mesh.groups[2]), &[3, 4, 2]);
+    }
+
+    #[test]
+    fn sub_meshes_only_keep_the_vertices_they_use() {
+        let mesh = ObjReader::parse_obj_src(TWO_PARTS, ParseMode::Strict).unwrap();
+
+        let door = mesh.sub_mesh("Door").unwrap();
+        assert_eq!(door.indices, vec![0, 1, 2]);
+        assert_eq!(door.vertices, vec![1., 1., 0., 2., 2., 2., 0., 1., 0.]);
+        assert_eq!(door.groups[0].index_start, 0);
+
+        // Every Body group, rebased onto the vertices of the sub-mesh
+        let body = mesh.sub_mesh("Body").unwrap();
+        assert_eq!(body.indices, vec![0, 1, 2, 1, 3, 2, 0, 2, 4]);
+        assert_eq!(body.vertices.len(), 5 * 3);
+        let starts: Vec<usize> = body.groups.iter().map(|g| g.index_start).collect();
+        assert_eq!(starts, vec![0, 3, 6]);
+
+        assert!(mesh.sub_mesh("Rotor").is_none());
+    }
+
+    #[test]
+    fn smoothing_groups_are_checked() {
+        let src = "v 0 0 0\nv 1 0 0\nv 0 1 0\ns 1\ns off\nf 1 2 3\n";
+        assert!(ObjReader::parse_obj_src(src, ParseMode::Strict).is_ok());
+        for bad in &["s\n", "s on\n"] {
+            let result = ObjReader::parse_obj_src(bad, ParseMode::Lenient);
+            assert!(matches!(result, Err(ObjError::Syntax { .. })), "{}", bad);
+        }
     }
 }
@@ -0,0 +1,172 @@
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::material::Material;
+use crate::mesh::Mesh;
+
+// Counterpart to ObjReader, so generated geometry can be inspected in e.g. Blender
+pub struct ObjWriter {}
+
+impl ObjWriter {
+    /**
+     * Writes the mesh to the given .obj path. If the mesh has a material, it is written to a
+     * .mtl file with the same name next to it.
+     */
+    pub fn write(mesh: &Mesh, obj_path: &str) -> std::io::Result<()> {
+        let path = Path::new(obj_path);
+        let name = path
+            .file_stem()
+            .map_or("mesh".to_string(), |s| s.to_string_lossy().to_string());
+
+        let mtl_library = match &mesh.material {
+            Some(material) => {
+                let mtl_path = path.with_extension("mtl");
+                std::fs::write(&mtl_path, ObjWriter::write_mtl_src(material))?;
+                mtl_path
+                    .file_name()
+                    .map(|f| f.to_string_lossy().to_string())
+            }
+            None => None,
+        };
+
+        std::fs::write(
+            path,
+            ObjWriter::write_obj_src(mesh, &name, mtl_library.as_deref()),
+        )
+    }
+
+    /**
+     * Vertex colors are written with the `v x y z r g b` extension, dropping the alpha channel.
+     * Indices are written 1-based, with texture coordinates and normals sharing the position
+     * index.
+     */
+    pub fn write_obj_src(mesh: &Mesh, name: &str, mtl_library: Option<&str>) -> String {
+        let mut obj_src = String::new();
+        let n_vertices = mesh.vertices.len() / 3;
+        let has_colors = mesh.colors.len() == n_vertices * 4;
+        let has_normals = mesh.normals.len() == n_vertices * 3;
+        let has_texcoords = mesh.texcoords.len() == n_vertices * 2;
+
+        // Writing to a String cannot fail, hence the unwraps
+        writeln!(obj_src, "# Written by gloom-rs").unwrap();
+        if let Some(library) = mtl_library {
+            writeln!(obj_src, "mtllib {}", library).unwrap();
+        }
+        writeln!(obj_src, "o {}", name).unwrap();
+
+        for i in 0..n_vertices {
+            let v = &mesh.vertices[i * 3..i * 3 + 3];
+            if has_colors {
+                let c = &mesh.colors[i * 4..i * 4 + 3];
+                writeln!(
+                    obj_src,
+                    "v {} {} {} {} {} {}",
+                    v[0], v[1], v[2], c[0], c[1], c[2]
+                )
+                .unwrap();
+            } else {
+                writeln!(obj_src, "v {} {} {}", v[0], v[1], v[2]).unwrap();
+            }
+        }
+
+        if has_texcoords {
+            for t in mesh.texcoords.chunks(2) {
+                writeln!(obj_src, "vt {} {}", t[0], t[1]).unwrap();
+            }
+        }
+
+        if has_normals {
+            for n in mesh.normals.chunks(3) {
+                writeln!(obj_src, "vn {} {} {}", n[0], n[1], n[2]).unwrap();
+            }
+        }
+
+        if let Some(material) = &mesh.material {
+            writeln!(obj_src, "usemtl {}", material.name).unwrap();
+        }
+
+        for triangle in mesh.indices.chunks(3) {
+            obj_src.push('f');
+            for &index in triangle {
+                match (has_texcoords, has_normals) {
+                    (true, true) => write!(obj_src, " {0}/{0}/{0}", index + 1),
+                    (true, false) => write!(obj_src, " {0}/{0}", index + 1),
+                    (false, true) => write!(obj_src, " {0}//{0}", index + 1),
+                    (false, false) => write!(obj_src, " {}", index + 1),
+                }
+                .unwrap();
+            }
+            obj_src.push('\n');
+        }
+
+        obj_src
+    }
+
+    pub fn write_mtl_src(material: &Material) -> String {
+        let mut mtl_src = String::new();
+        let [ar, ag, ab] = material.ambient;
+        let [dr, dg, db] = material.diffuse;
+        let [sr, sg, sb] = material.specular;
+
+        writeln!(mtl_src, "# Written by gloom-rs").unwrap();
+        writeln!(mtl_src, "newmtl {}", material.name).unwrap();
+        writeln!(mtl_src, "Ka {} {} {}", ar, ag, ab).unwrap();
+        writeln!(mtl_src, "Kd {} {} {}", dr, dg, db).unwrap();
+        writeln!(mtl_src, "Ks {} {} {}", sr, sg, sb).unwrap();
+        writeln!(mtl_src, "Ns {}", material.shininess).unwrap();
+        writeln!(mtl_src, "d {}", material.dissolve).unwrap();
+        if let Some(texture) = &material.diffuse_texture {
+            writeln!(mtl_src, "map_Kd {}", texture).unwrap();
+        }
+
+        mtl_src
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::MtlReader;
+    use crate::obj_reader::{ObjReader, ParseMode};
+
+    #[test]
+    fn written_mesh_reads_back_the_same() {
+        let vertices = vec![0., 0., 0., 1., 0., 0., 1., 1., 0., 0., 1., 0.5];
+        let mut mesh = Mesh::from_shape((vertices, vec![0, 1, 2, 0, 2, 3]), [1.; 4]);
+        mesh.normals = vec![0., 0., 1., 0., 0., 1., 0., 0.6, 0.8, 0., -0.6, 0.8];
+        mesh.colors = vec![
+            1., 0., 0., 1., 0., 1., 0., 1., 0., 0., 1., 1., 0.25, 0.5, 0.75, 1.,
+        ];
+        let mut material = Material::new("paint");
+        material.diffuse = [0.8, 0.1, 0.1];
+        material.specular = [0.5; 3];
+        material.shininess = 32.;
+        material.dissolve = 0.75;
+        material.diffuse_texture = Some("paint.png".to_string());
+        mesh.material = Some(material.clone());
+
+        let obj_src = ObjWriter::write_obj_src(&mesh, "quad", Some("quad.mtl"));
+        let obj = ObjReader::parse_obj_src(&obj_src, ParseMode::Strict).unwrap();
+        assert_eq!(obj.vertices, mesh.vertices);
+        assert_eq!(obj.normals, mesh.normals);
+        assert_eq!(obj.colors, mesh.colors);
+        assert!(obj.texcoords.is_empty());
+        assert_eq!(obj.indices, mesh.indices);
+        assert_eq!(obj.material_libraries, vec!["quad.mtl".to_string()]);
+        assert_eq!(obj.groups.len(), 1);
+        assert_eq!(obj.groups[0].name, "quad");
+        assert_eq!(obj.groups[0].material.as_deref(), Some("paint"));
+
+        let mtl_src = ObjWriter::write_mtl_src(&material);
+        let materials = MtlReader::parse_mtl_src(&mtl_src, ParseMode::Strict).unwrap();
+        assert_eq!(materials.len(), 1);
+        let read = &materials[0];
+        assert_eq!(read.name, material.name);
+        assert_eq!(read.ambient, material.ambient);
+        assert_eq!(read.diffuse, material.diffuse);
+        assert_eq!(read.specular, material.specular);
+        assert_eq!(read.shininess, material.shininess);
+        assert_eq!(read.dissolve, material.dissolve);
+        assert_eq!(read.diffuse_texture, material.diffuse_texture);
+    }
+}
@@ -0,0 +1,236 @@
+use crate::bounds::Aabb;
+use crate::bvh::Bvh;
+use crate::scene_graph::{NodeId, SceneGraph};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Ray {
+    pub origin: glm::Vec3,
+    pub direction: glm::Vec3, // Normalized
+}
+
+// Where a ray first hit a mesh in the scene
+#[derive(Clone, Copy, Debug)]
+pub struct PickHit {
+    pub node: NodeId,
+    // Index into the mesh's triangles, i.e. its indices / 3
+    pub triangle: usize,
+    // Along the ray, in world units
+    pub distance: f32,
+    // Weights of the triangle's three corners at the hit
+    pub barycentric: glm::Vec3,
+    // In the world
+    pub point: glm::Vec3,
+}
+
+impl Ray {
+    pub fn new(origin: glm::Vec3, direction: glm::Vec3) -> Ray {
+        Ray {
+            origin,
+            direction: glm::normalize(&direction),
+        }
+    }
+
+    /**
+     * The ray through a pixel of the window, from the near plane away from the camera. x and y
+     * are in pixels from the top left corner, like the cursor position of window events.
+     */
+    pub fn from_screen(
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+        view_projection: &glm::Mat4,
+    ) -> Ray {
+        let ndc_x = 2. * x / width - 1.;
+        let ndc_y = 1. - 2. * y / height;
+        let inverse = glm::inverse(view_projection);
+        let unproject = |z: f32| {
+            let p = inverse * glm::vec4(ndc_x, ndc_y, z, 1.);
+            glm::vec3(p.x, p.y, p.z) / p.w
+        };
+        let near = unproject(-1.);
+        Ray::new(near, unproject(1.) - near)
+    }
+
+    pub fn at(&self, distance: f32) -> glm::Vec3 {
+        self.origin + self.direction * distance
+    }
+
+    // The same ray in another space, where its direction is no longer normalized
+    fn transformed(&self, transform: &glm::Mat4) -> (glm::Vec3, glm::Vec3) {
+        let o = transform * glm::vec4(self.origin.x, self.origin.y, self.origin.z, 1.);
+        let d = transform * glm::vec4(self.direction.x, self.direction.y, self.direction.z, 0.);
+        (glm::vec3(o.x, o.y, o.z), glm::vec3(d.x, d.y, d.z))
+    }
+
+    // Distance to where the ray enters the box, or zero if it starts inside
+    pub fn intersect_aabb(&self, aabb: &Aabb) -> Option<f32> {
+        intersect_aabb(&self.origin, &self.direction, aabb)
+    }
+
+    /**
+     * The closest hit on the meshes of the nodes below from, searched through the bounding
+     * volume hierarchy given by bvh_of for each node. Subtrees whose world bounds the ray
+     * misses, or only reaches past a closer hit, are skipped, and so are the meshes of nodes
+     * whose local bounds it misses. Needs the transforms and world bounds to be up to date.
+     */
+    pub fn cast<'a, F>(&self, graph: &SceneGraph, from: NodeId, bvh_of: F) -> Option<PickHit>
+    where
+        F: Fn(NodeId) -> Option<&'a Bvh>,
+    {
+        let mut closest: Option<PickHit> = None;
+        let mut pending = vec![from];
+        while let Some(id) = pending.pop() {
+            let node = &graph[id];
+            let reach = closest.map_or(f32::INFINITY, |hit| hit.distance);
+            match self.intersect_aabb(&node.world_bounds) {
+                Some(distance) if distance <= reach => {}
+                _ => continue,
+            }
+            pending.extend(node.children());
+
+            if let Some(bvh) = bvh_of(id) {
+                let world = graph.world_transform(id);
+                if let Some(hit) = self.cast_bvh(id, node.local_bounds, bvh, &world, reach) {
+                    closest = Some(hit);
+                }
+            }
+        }
+        closest
+    }
+
+    /**
+     * Tested in the mesh's own coordinates, so only the ray is transformed. Its direction is
+     * transformed along with it, so distances along it are still world distances.
+     */
+    fn cast_bvh(
+        &self,
+        node: NodeId,
+        bounds: Option<Aabb>,
+        bvh: &Bvh,
+        world: &glm::Mat4,
+        reach: f32,
+    ) -> Option<PickHit> {
+        let (origin, direction) = self.transformed(&glm::inverse(world));
+        if bounds.is_some_and(|bounds| {
+            intersect_aabb(&origin, &direction, &bounds).is_none_or(|distance| distance > reach)
+        }) {
+            return None;
+        }
+        let hit = bvh.cast_ray(&origin, &direction, reach)?;
+        Some(PickHit {
+            node,
+            triangle: hit.triangle,
+            distance: hit.distance,
+            barycentric: hit.barycentric,
+            point: self.at(hit.distance),
+        })
+    }
+}
+
+// Slab test. The direction need not be normalized, and the distance is in units of it.
+pub fn intersect_aabb(origin: &glm::Vec3, direction: &glm::Vec3, aabb: &Aabb) -> Option<f32> {
+    if aabb.is_empty() {
+        return None;
+    }
+    let (mut near, mut far) = (0f32, f32::INFINITY);
+    for i in 0..3 {
+        if direction[i] == 0. {
+            if origin[i] < aabb.min[i] || origin[i] > aabb.max[i] {
+                return None;
+            }
+            continue;
+        }
+        let (t0, t1) = (
+            (aabb.min[i] - origin[i]) / direction[i],
+            (aabb.max[i] - origin[i]) / direction[i],
+        );
+        near = near.max(t0.min(t1));
+        far = far.min(t0.max(t1));
+    }
+    if near <= far {
+        Some(near)
+    } else {
+        None
+    }
+}
+
+// Möller-Trumbore. The barycentric weights are of a, b and c in that order.
+pub fn intersect_triangle(
+    origin: &glm::Vec3,
+    direction: &glm::Vec3,
+    a: &glm::Vec3,
+    b: &glm::Vec3,
+    c: &glm::Vec3,
+) -> Option<(f32, glm::Vec3)> {
+    let (edge1, edge2) = (b - a, c - a);
+    let p = glm::cross(direction, &edge2);
+    let det = glm::dot(&edge1, &p);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let to_origin = origin - a;
+    let u = glm::dot(&to_origin, &p) / det;
+    if !(0. ..=1.).contains(&u) {
+        return None;
+    }
+    let q = glm::cross(&to_origin, &edge1);
+    let v = glm::dot(direction, &q) / det;
+    if v < 0. || u + v > 1. {
+        return None;
+    }
+    let distance = glm::dot(&edge2, &q) / det;
+    if distance < 0. {
+        return None;
+    }
+    Some((distance, glm::vec3(1. - u - v, u, v)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::Mesh;
+    use crate::scene_graph::SceneNode;
+
+    // A 2x2 square on the XZ plane, centred on the origin
+    fn square() -> Mesh {
+        let vertices = vec![-1., 0., -1., 1., 0., -1., 1., 0., 1., -1., 0., 1.];
+        Mesh::from_shape((vertices, vec![0, 1, 2, 0, 2, 3]), [1.; 4])
+    }
+
+    #[test]
+    fn rays_hit_the_closest_mesh_within_its_node_bounds() {
+        let mesh = square();
+        let bvh = Bvh::from_mesh(&mesh);
+        let mut graph = SceneGraph::new();
+        let root = graph.root();
+        let mut upper = SceneNode::from_mesh(0, &mesh);
+        upper.position = glm::vec3(0., 2., 0.);
+        let upper = graph.add_child(root, upper);
+        let mut lower = SceneNode::from_mesh(0, &mesh);
+        lower.position = glm::vec3(0., -2., 0.);
+        let lower = graph.add_child(upper, lower);
+        graph.update_transforms();
+        graph.update_world_bounds();
+
+        let ray = Ray::new(glm::vec3(0.5, 10., 0.), glm::vec3(0., -1., 0.));
+        let hit = ray.cast(&graph, root, |_| Some(&bvh)).unwrap();
+        assert_eq!(hit.node, upper);
+        assert!((hit.distance - 8.).abs() < 1e-5);
+        assert!((hit.point - glm::vec3(0.5, 2., 0.)).norm() < 1e-5);
+        let [a, b, c] = bvh.corners(hit.triangle);
+        let local = a * hit.barycentric.x + b * hit.barycentric.y + c * hit.barycentric.z;
+        assert!((local - glm::vec3(0.5, 0., 0.)).norm() < 1e-5);
+
+        // Bounds that leave out where the ray crosses the upper square let it through, even
+        // though the world bounds of the node take in the square below it
+        graph[upper].local_bounds = Some(Aabb {
+            min: glm::vec3(-1., 0., -1.),
+            max: glm::vec3(0., 0., 1.),
+        });
+        graph.update_world_bounds();
+        let hit = ray.cast(&graph, root, |_| Some(&bvh)).unwrap();
+        assert_eq!(hit.node, lower);
+        assert!((hit.distance - 10.).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,512 @@
+use std::fmt;
+use std::path::Path;
+
+use crate::mesh::Mesh;
+use crate::obj_reader::{triangulate, ObjLocation};
+
+// Reads Stanford .ply files, as written by most 3D scanners, in ASCII or binary
+pub struct PlyReader {}
+
+#[derive(Debug)]
+pub enum PlyError {
+    Extension {
+        path: String,
+    },
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    Syntax {
+        location: ObjLocation,
+        token: String,
+        expected: &'static str,
+    },
+    Unsupported {
+        path: String,
+        what: String,
+    },
+    Truncated {
+        path: String,
+        element: String,
+    },
+    IndexOutOfRange {
+        path: String,
+        face: usize,
+        index: f64,
+    },
+}
+
+impl fmt::Display for PlyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlyError::Extension { path } => {
+                write!(f, "{}: expected a file with the .ply extension", path)
+            }
+            PlyError::Io { path, source } => write!(f, "{}: failed to read file: {}", path, source),
+            PlyError::Syntax {
+                location,
+                token,
+                expected,
+            } if token.is_empty() => {
+                write!(f, "{}: expected {}, found end of line", location, expected)
+            }
+            PlyError::Syntax {
+                location,
+                token,
+                expected,
+            } => write!(f, "{}: expected {}, found `{}`", location, expected, token),
+            PlyError::Unsupported { path, what } => write!(f, "{}: unsupported {}", path, what),
+            PlyError::Truncated { path, element } => write!(
+                f,
+                "{}: file ended in the middle of the {} elements",
+                path, element
+            ),
+            PlyError::IndexOutOfRange { path, face, index } => write!(
+                f,
+                "{}: face {} refers to missing vertex {}",
+                path, face, index
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PlyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PlyError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+#[derive(Clone, Copy)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn from_name(name: &str) -> Option<Scalar> {
+        match name {
+            "char" | "int8" => Some(Scalar::I8),
+            "uchar" | "uint8" => Some(Scalar::U8),
+            "short" | "int16" => Some(Scalar::I16),
+            "ushort" | "uint16" => Some(Scalar::U16),
+            "int" | "int32" => Some(Scalar::I32),
+            "uint" | "uint32" => Some(Scalar::U32),
+            "float" | "float32" => Some(Scalar::F32),
+            "double" | "float64" => Some(Scalar::F64),
+            _ => None,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+
+    // What a colour channel of this type is divided by to end up in 0..1
+    fn color_scale(self) -> f64 {
+        match self {
+            Scalar::U8 | Scalar::I8 => 255.,
+            Scalar::U16 | Scalar::I16 => 65535.,
+            Scalar::U32 | Scalar::I32 => u32::MAX as f64,
+            Scalar::F32 | Scalar::F64 => 1.,
+        }
+    }
+}
+
+enum PropertyKind {
+    Scalar(Scalar),
+    List { count: Scalar, item: Scalar },
+}
+
+struct Property {
+    name: String,
+    kind: PropertyKind,
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+// What the header says about the body that follows it
+struct Header {
+    format: Format,
+    elements: Vec<Element>,
+    body_offset: usize, // In bytes, from the start of the file
+    body_line: usize,   // Line number of the first body line, for errors in ASCII files
+}
+
+impl Element {
+    fn property(&self, names: &[&str]) -> Option<usize> {
+        self.properties
+            .iter()
+            .position(|p| names.contains(&p.name.as_str()))
+    }
+}
+
+// Yields the values of the body one at a time, whatever the format
+struct Body<'a> {
+    path: &'a str,
+    format: Format,
+    bytes: &'a [u8],
+    // Only used for ASCII, which is parsed a line at a time
+    first_line: usize, // Line numbers continue from the header
+    line_number: usize,
+    line: &'a str,
+    tokens: std::str::SplitWhitespace<'a>,
+    lines: std::iter::Enumerate<std::str::Lines<'a>>,
+}
+
+impl<'a> Body<'a> {
+    fn value(&mut self, scalar: Scalar, element: &str) -> Result<f64, PlyError> {
+        let path = self.path;
+        let truncated = || PlyError::Truncated {
+            path: path.to_string(),
+            element: element.to_string(),
+        };
+
+        if self.format == Format::Ascii {
+            let token = loop {
+                if let Some(token) = self.tokens.next() {
+                    break token;
+                }
+                let (i, line) = self.lines.next().ok_or_else(truncated)?;
+                self.line_number = self.first_line + i;
+                self.line = line;
+                self.tokens = line.split_whitespace();
+            };
+            return token.parse::<f64>().map_err(|_| PlyError::Syntax {
+                location: ObjLocation::of(self.path, self.line_number, self.line, token),
+                token: token.to_string(),
+                expected: "a number",
+            });
+        }
+
+        let size = scalar.size();
+        if self.bytes.len() < size {
+            return Err(truncated());
+        }
+        let mut b = [0u8; 8];
+        b[..size].copy_from_slice(&self.bytes[..size]);
+        if self.format == Format::BigEndian {
+            b[..size].reverse();
+        }
+        self.bytes = &self.bytes[size..];
+
+        Ok(match scalar {
+            Scalar::I8 => b[0] as i8 as f64,
+            Scalar::U8 => b[0] as f64,
+            Scalar::I16 => i16::from_le_bytes([b[0], b[1]]) as f64,
+            Scalar::U16 => u16::from_le_bytes([b[0], b[1]]) as f64,
+            Scalar::I32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Scalar::U32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Scalar::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Scalar::F64 => f64::from_le_bytes(b),
+        })
+    }
+
+    // The values of every property of one element, a single one for scalars and all items of lists
+    fn element(&mut self, element: &Element) -> Result<Vec<Vec<f64>>, PlyError> {
+        element
+            .properties
+            .iter()
+            .map(|property| match property.kind {
+                PropertyKind::Scalar(scalar) => Ok(vec![self.value(scalar, &element.name)?]),
+                PropertyKind::List { count, item } => {
+                    let n = self.value(count, &element.name)? as usize;
+                    (0..n).map(|_| self.value(item, &element.name)).collect()
+                }
+            })
+            .collect()
+    }
+}
+
+impl PlyReader {
+    pub fn read(ply_path: &str) -> Result<Mesh, PlyError> {
+        let path = Path::new(ply_path);
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(e) if e.eq_ignore_ascii_case("ply") => (),
+            _ => {
+                return Err(PlyError::Extension {
+                    path: ply_path.to_string(),
+                })
+            }
+        }
+
+        let ply_bytes = std::fs::read(path).map_err(|source| PlyError::Io {
+            path: ply_path.to_string(),
+            source,
+        })?;
+        PlyReader::parse_named_bytes(ply_path, &ply_bytes)
+    }
+
+    #[cfg(test)]
+    pub fn parse_ply_bytes(ply_bytes: &[u8]) -> Result<Mesh, PlyError> {
+        PlyReader::parse_named_bytes("<source>", ply_bytes)
+    }
+
+    /**
+     * Vertices need x, y and z, and may have nx, ny, nz normals, red, green, blue and alpha
+     * colours, and u, v (or s, t) texture coordinates. Faces are triangulated, and elements
+     * other than vertices and faces are skipped.
+     */
+    fn parse_named_bytes(path: &str, ply_bytes: &[u8]) -> Result<Mesh, PlyError> {
+        let Header {
+            format,
+            elements,
+            body_offset,
+            body_line,
+        } = PlyReader::parse_header(path, ply_bytes)?;
+
+        // The body starts on the line after the header, which is only text in ASCII files
+        let body_bytes = &ply_bytes[body_offset..];
+        let text = if format == Format::Ascii {
+            std::str::from_utf8(body_bytes).map_err(|_| PlyError::Unsupported {
+                path: path.to_string(),
+                what: "non-text data in an ASCII file".to_string(),
+            })?
+        } else {
+            ""
+        };
+        let mut body = Body {
+            path,
+            format,
+            bytes: body_bytes,
+            first_line: body_line,
+            line_number: body_line,
+            line: "",
+            tokens: "".split_whitespace(),
+            lines: text.lines().enumerate(),
+        };
+
+        let mut vertices = vec![];
+        let mut normals = vec![];
+        let mut colors = vec![];
+        let mut texcoords = vec![];
+        let mut faces: Vec<Vec<f64>> = vec![];
+
+        for element in &elements {
+            let attributes = match element.name.as_str() {
+                "vertex" => {
+                    let find = |names: &[&[&str]]| -> Option<Vec<usize>> {
+                        names.iter().map(|n| element.property(n)).collect()
+                    };
+                    let position =
+                        find(&[&["x"], &["y"], &["z"]]).ok_or_else(|| PlyError::Unsupported {
+                            path: path.to_string(),
+                            what: "vertices without x, y and z".to_string(),
+                        })?;
+                    let normal = find(&[&["nx"], &["ny"], &["nz"]]);
+                    let color = find(&[&["red"], &["green"], &["blue"]]);
+                    let alpha = element.property(&["alpha"]);
+                    let texcoord = find(&[&["u", "s", "texture_u"], &["v", "t", "texture_v"]]);
+                    Some((position, normal, color, alpha, texcoord))
+                }
+                _ => None,
+            };
+
+            for _ in 0..element.count {
+                let values = body.element(element)?;
+                let scalar = |i: usize| values[i].first().cloned().unwrap_or(0.);
+
+                if let Some((position, normal, color, alpha, texcoord)) = &attributes {
+                    vertices.extend(position.iter().map(|&i| scalar(i) as f32));
+                    if let Some(normal) = normal {
+                        normals.extend(normal.iter().map(|&i| scalar(i) as f32));
+                    }
+                    if let Some(color) = color {
+                        let channel = |i: usize| match element.properties[i].kind {
+                            PropertyKind::Scalar(s) => (scalar(i) / s.color_scale()) as f32,
+                            PropertyKind::List { .. } => 1.,
+                        };
+                        colors.extend(color.iter().map(|&i| channel(i)));
+                        colors.push(alpha.map_or(1., channel));
+                    }
+                    if let Some(texcoord) = texcoord {
+                        texcoords.extend(texcoord.iter().map(|&i| scalar(i) as f32));
+                    }
+                } else if element.name == "face" {
+                    if let Some(i) = element.property(&["vertex_indices", "vertex_index"]) {
+                        faces.push(values[i].clone());
+                    }
+                }
+            }
+        }
+
+        let n_vertices = vertices.len() / 3;
+        let mut indices = vec![];
+        for (face, corners) in faces.iter().enumerate() {
+            if corners.len() < 3 {
+                continue;
+            }
+            let corners: Vec<u32> = corners
+                .iter()
+                .map(|&index| {
+                    // NaN would otherwise be cast to vertex 0
+                    if !index.is_finite() || index < 0. || index as usize >= n_vertices {
+                        Err(PlyError::IndexOutOfRange {
+                            path: path.to_string(),
+                            face,
+                            index,
+                        })
+                    } else {
+                        Ok(index as u32)
+                    }
+                })
+                .collect::<Result<_, _>>()?;
+            let polygon: Vec<[f32; 3]> = corners
+                .iter()
+                .map(|&i| {
+                    let i = i as usize * 3;
+                    [vertices[i], vertices[i + 1], vertices[i + 2]]
+                })
+                .collect();
+            for triangle in triangulate(&polygon) {
+                indices.extend(triangle.iter().map(|&k| corners[k]));
+            }
+        }
+
+        let mut mesh = Mesh::from_shape((vertices, indices), [1.0, 1.0, 1.0, 1.0]);
+        mesh.normals = normals;
+        mesh.texcoords = texcoords;
+        if !colors.is_empty() {
+            mesh.colors = colors;
+        }
+        Ok(mesh)
+    }
+
+    fn parse_header(path: &str, ply_bytes: &[u8]) -> Result<Header, PlyError> {
+        let mut format = None;
+        let mut elements: Vec<Element> = vec![];
+        let mut offset = 0;
+        let mut line_number = 0;
+
+        loop {
+            line_number += 1;
+            let rest = &ply_bytes[offset..];
+            let end =
+                rest.iter()
+                    .position(|&b| b == b'\n')
+                    .ok_or_else(|| PlyError::Unsupported {
+                        path: path.to_string(),
+                        what: "header without end_header".to_string(),
+                    })?;
+            offset += end + 1;
+            let line = std::str::from_utf8(&rest[..end])
+                .map_err(|_| PlyError::Unsupported {
+                    path: path.to_string(),
+                    what: "non-text header".to_string(),
+                })?
+                .trim_end_matches('\r');
+
+            let syntax_error = |token: &str, expected| PlyError::Syntax {
+                location: ObjLocation::of(path, line_number, line, token),
+                token: token.to_string(),
+                expected,
+            };
+            // Missing tokens are reported at the end of the line
+            let end_of_line = &line[line.len()..];
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            let token = |i: usize| tokens.get(i).cloned().unwrap_or(end_of_line);
+
+            if line_number == 1 {
+                if line != "ply" {
+                    return Err(syntax_error(line, "`ply`"));
+                }
+                continue;
+            }
+
+            match token(0) {
+                "format" => {
+                    format = Some(match token(1) {
+                        "ascii" => Format::Ascii,
+                        "binary_little_endian" => Format::LittleEndian,
+                        "binary_big_endian" => Format::BigEndian,
+                        t => return Err(syntax_error(t, "ascii or binary format")),
+                    });
+                }
+                "element" => {
+                    let count = token(2)
+                        .parse()
+                        .map_err(|_| syntax_error(token(2), "element count"))?;
+                    elements.push(Element {
+                        name: token(1).to_string(),
+                        count,
+                        properties: vec![],
+                    });
+                }
+                "property" => {
+                    let scalar =
+                        |t: &str| Scalar::from_name(t).ok_or_else(|| syntax_error(t, "type"));
+                    let (kind, name) = if token(1) == "list" {
+                        let kind = PropertyKind::List {
+                            count: scalar(token(2))?,
+                            item: scalar(token(3))?,
+                        };
+                        (kind, token(4))
+                    } else {
+                        (PropertyKind::Scalar(scalar(token(1))?), token(2))
+                    };
+                    let element = elements
+                        .last_mut()
+                        .ok_or_else(|| syntax_error(token(0), "element before property"))?;
+                    element.properties.push(Property {
+                        name: name.to_string(),
+                        kind,
+                    });
+                }
+                "end_header" => {
+                    let format = format.ok_or_else(|| syntax_error(token(0), "format"))?;
+                    return Ok(Header {
+                        format,
+                        elements,
+                        body_offset: offset,
+                        body_line: line_number + 1,
+                    });
+                }
+                "comment" | "obj_info" | "" => (),
+                t => return Err(syntax_error(t, "header keyword")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nan_face_index_is_out_of_range() {
+        let ply = "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\nproperty float y\n\
+                   property float z\nelement face 1\nproperty list uchar float vertex_indices\n\
+                   end_header\n0 0 0\n1 0 0\n0 1 0\n3 1 2 nan\n";
+        match PlyReader::parse_ply_bytes(ply.as_bytes()) {
+            Err(PlyError::IndexOutOfRange { face, index, .. }) => {
+                assert_eq!(face, 0);
+                assert!(index.is_nan());
+            }
+            other => panic!("expected an out of range index, got {:?}", other.err()),
+        }
+    }
+}
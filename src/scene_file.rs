@@ -0,0 +1,1547 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::Path;
+
+use crate::erosion::{Erosion, HydraulicErosion, ThermalErosion};
+use crate::gltf::{GltfError, GltfInstance, GltfReader, GltfScene};
+use crate::heightmap::{Heightmap, TerrainSettings, TERRAIN_COLORS};
+use crate::json::{Json, JsonError};
+use crate::material::Material;
+use crate::mesh::{Mesh, Terrain};
+use crate::model::Model;
+use crate::noise::{FractalKind, NoiseSettings};
+use crate::obj_reader::ParseMode;
+use crate::ply_reader::PlyReader;
+use crate::scene_graph::{NodeId, SceneGraph, SceneNode};
+use crate::stl_reader::StlReader;
+use crate::toolbox;
+
+/*
+ * A scene described in JSON, so it can be changed without recompiling. Every member is
+ * optional, and left out when saving if it has its default value:
+ *
+ *   {
+ *     "shaders": ["./shaders/simple.vert", "./shaders/sunlight.frag"],
+ *     "camera": { "position": [0, 0, 0], "pitch": 0, "yaw": 0,
+ *                 "fovy": 20, "near": 1, "far": 1000 },
+ *     "lights": [{ "name": "sun", "direction": [0.8, -0.5, 0.6], "color": [1, 1, 1] }],
+ *     "materials": [
+ *       { "name": "paint", "ambient": [0, 0, 0], "diffuse": [0.3, 0.3, 0.3],
+ *         "specular": [0.5, 0.5, 0.5], "shininess": 32, "dissolve": 1 }
+ *     ],
+ *     "meshes": [
+ *       { "name": "body", "path": "./resources/helicopter.obj", "part": "Body_body",
+ *         "material": "paint", "color": [0.3, 0.3, 0.3, 1], "lods": true,
+ *         "flat_shading": false },
+ *       { "name": "hills", "heightmap": { "path": "./resources/hills.png",
+ *                                         "spacing": 2, "scale": 60, "skirt": 5 } },
+ *       { "name": "moon", "noise": { "width": 256, "depth": 256, "seed": 42, "octaves": 6,
+ *                                    "frequency": 0.015625, "lacunarity": 2, "persistence": 0.5,
+ *                                    "kind": "ridged", "spacing": 2, "scale": 60, "skirt": 5,
+ *                                    "erosion": [{ "type": "hydraulic", "seed": 7,
+ *                                                  "droplets": 50000 },
+ *                                                { "type": "thermal", "iterations": 50 }] } }
+ *     ],
+ *     "models": [
+ *       { "name": "helicopter", "path": "./resources/helicopter.gltf",
+ *         "meshes": { "body": "body" } }
+ *     ],
+ *     "nodes": [
+ *       { "name": "heli_0", "tags": ["helicopter"], "mesh": "body", "model": "helicopter",
+ *         "position": [0, 0, 0], "rotation": [0, 0, 0], "scale": [1, 1, 1],
+ *         "reference_point": [0, 0, 0],
+ *         "animations": [{ "type": "heading", "offset": 0 }, { "type": "clip", "name": "fly" }],
+ *         "children": [] }
+ *     ]
+ *   }
+ *
+ * Meshes are read from OBJ, PLY, STL or glTF files, with part picking one object or group of
+ * an OBJ file, or one mesh of a glTF file, and color used unless the mesh has a material. A
+ * material named from materials replaces the one of the file, and colours the mesh with its
+ * diffuse colour, with dissolve as alpha. Left out settings of materials get those of
+ * Material::new. Flat shading replaces the normals of the file with those of the triangles, for
+ * a faceted look.
+ * Meshes can also be terrain built from a grayscale heightmap image, with spacing between the
+ * samples, scale as the height of white and a skirt hanging below the edges, coloured by
+ * height unless given a color. Terrain can also be generated from fractal noise of the given
+ * size in samples, with kind being standard, ridged or billow, weathered by the erosion passes
+ * in order. Left out settings of noise and erosion passes get the defaults of NoiseSettings,
+ * HydraulicErosion and ThermalErosion.
+ *
+ * Models are glTF files brought in for their node hierarchy and animations, e.g. a rig putting
+ * the rotors of a helicopter where they turn. A node with a model gets the nodes of the file
+ * below it, each drawing the scene mesh its name is mapped to in meshes, and its clip
+ * animations play the animations of the file with that name.
+ */
+
+#[derive(Debug)]
+pub enum SceneFileError {
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    Json {
+        path: String,
+        source: JsonError,
+    },
+    Invalid {
+        path: String,
+        reason: String,
+    },
+    Mesh {
+        path: String, // Of the mesh file
+        reason: String,
+    },
+    Model {
+        path: String, // Of the glTF file
+        reason: String,
+    },
+}
+
+impl fmt::Display for SceneFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneFileError::Io { path, source } => {
+                write!(f, "{}: failed to access scene file: {}", path, source)
+            }
+            SceneFileError::Json { path, source } => write!(f, "{}:{}", path, source),
+            SceneFileError::Invalid { path, reason } => {
+                write!(f, "{}: invalid scene: {}", path, reason)
+            }
+            SceneFileError::Mesh { path, reason } => {
+                write!(f, "{}: failed to load mesh: {}", path, reason)
+            }
+            SceneFileError::Model { path, reason } => {
+                write!(f, "{}: failed to load model: {}", path, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SceneFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SceneFileError::Io { source, .. } => Some(source),
+            SceneFileError::Json { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+// Where the scene is seen from, as used by the camera controls in main.rs
+#[derive(Clone, Debug)]
+pub struct CameraDescription {
+    pub position: glm::Vec3,
+    pub pitch: f32,
+    pub yaw: f32,
+    pub fovy: f32,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl Default for CameraDescription {
+    fn default() -> Self {
+        CameraDescription {
+            position: glm::zero(),
+            pitch: 0.,
+            yaw: 0.,
+            fovy: 20.,
+            near: 1.,
+            far: 1000.,
+        }
+    }
+}
+
+// A light infinitely far away, shining along its direction
+#[derive(Clone, Debug)]
+pub struct LightDescription {
+    pub name: String,
+    pub direction: glm::Vec3,
+    pub color: glm::Vec3,
+}
+
+// Where the vertices of a mesh come from
+#[derive(Clone, Debug)]
+pub enum MeshSource {
+    File {
+        path: String,
+        part: Option<String>, // One OBJ object or group, or glTF mesh, rather than all
+    },
+    Heightmap {
+        path: String,
+        terrain: TerrainSettings,
+    },
+    Noise {
+        width: usize,
+        depth: usize,
+        noise: NoiseSettings,
+        erosion: Vec<Erosion>,
+        terrain: TerrainSettings,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct MeshDescription {
+    pub name: String, // What nodes refer to it by
+    pub source: MeshSource,
+    pub material: Option<String>, // Replaces the material of the file, by name
+    pub color: Option<[f32; 4]>,  // Used unless the mesh has a material
+    pub lods: bool,               // Whether coarser versions should be drawn from afar
+    pub flat_shading: bool,       // Whether each triangle is lit as flat, whatever the file says
+}
+
+#[derive(Clone, Debug)]
+pub struct ModelDescription {
+    pub name: String, // What nodes refer to it by
+    pub path: String,
+    pub meshes: Vec<(String, String)>, // The scene mesh drawn by each named node of the file
+}
+
+#[derive(Clone, Debug)]
+pub enum NodeAnimation {
+    Spin { speed: glm::Vec3 }, // Euler angles per second
+    Heading { offset: f32 },   // toolbox::simple_heading_animation, ahead by offset seconds
+    Clip { name: String },     // An animation of the node's model, looping
+}
+
+impl NodeAnimation {
+    // Clips move the nodes of the model rather than this one, see SceneInstance::animate
+    pub fn apply(&self, node: &mut SceneNode, time: f32) {
+        match *self {
+            NodeAnimation::Spin { speed } => node.rotation = speed * time,
+            NodeAnimation::Heading { offset } => {
+                let heading = toolbox::simple_heading_animation(time + offset);
+                node.position.x = heading.x;
+                node.position.z = heading.z;
+                node.rotation = glm::vec3(heading.pitch, heading.yaw, heading.roll);
+            }
+            NodeAnimation::Clip { .. } => (),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NodeDescription {
+    pub name: String,
+    pub tags: Vec<String>,
+    pub mesh: Option<String>,
+    pub model: Option<String>,
+    pub position: glm::Vec3,
+    pub rotation: glm::Vec3,
+    pub scale: glm::Vec3,
+    pub reference_point: glm::Vec3,
+    pub animations: Vec<NodeAnimation>,
+    pub children: Vec<NodeDescription>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SceneFile {
+    pub shaders: Vec<String>, // Attached in order, see ShaderBuilder::attach_file
+    pub camera: CameraDescription,
+    pub lights: Vec<LightDescription>,
+    pub materials: Vec<Material>, // Those meshes refer to by name, without textures
+    pub meshes: Vec<MeshDescription>,
+    pub models: Vec<ModelDescription>,
+    pub nodes: Vec<NodeDescription>, // Those directly below the node the scene is added to
+}
+
+// What instantiate added to the graph, for animating it and saving it back
+pub struct SceneInstance {
+    pub meshes: HashMap<NodeId, usize>, // The mesh each node draws, as an index into meshes
+    pub models: HashMap<NodeId, (usize, GltfInstance)>, // The model below each node, by index
+    pub animations: Vec<(NodeId, NodeAnimation)>,
+}
+
+impl SceneInstance {
+    // Nodes removed from the graph since are skipped. Models are as given by load_models.
+    pub fn animate(&self, graph: &mut SceneGraph, models: &[GltfScene], time: f32) {
+        for (id, animation) in &self.animations {
+            if let NodeAnimation::Clip { name } = animation {
+                let clip = self.models.get(id).and_then(|(model, instance)| {
+                    Some((models[*model].animation(name)?, instance))
+                });
+                if let Some((clip, instance)) = clip {
+                    clip.apply(time, instance, graph);
+                }
+            } else if let Some(node) = graph.get_mut(*id) {
+                animation.apply(node, time);
+            }
+        }
+    }
+
+    // Whether the node was made from a model, rather than described in the scene file
+    fn is_model_node(&self, id: NodeId) -> bool {
+        self.models
+            .values()
+            .any(|(_, instance)| instance.nodes.contains(&Some(id)))
+    }
+}
+
+impl SceneFile {
+    pub fn read(scene_path: &str) -> Result<SceneFile, SceneFileError> {
+        let src = std::fs::read_to_string(scene_path).map_err(|source| SceneFileError::Io {
+            path: scene_path.to_string(),
+            source,
+        })?;
+        SceneFile::parse_named(scene_path, &src)
+    }
+
+    #[cfg(test)]
+    pub fn parse(src: &str) -> Result<SceneFile, SceneFileError> {
+        SceneFile::parse_named("<source>", src)
+    }
+
+    fn parse_named(path: &str, src: &str) -> Result<SceneFile, SceneFileError> {
+        let json = Json::parse(src).map_err(|source| SceneFileError::Json {
+            path: path.to_string(),
+            source,
+        })?;
+        let parser = Parser { path };
+        let scene = parser.scene(&json)?;
+
+        // Checked here, so instantiate can't come across a mesh or model that isn't there, nor
+        // load_meshes a material
+        for mesh in &scene.meshes {
+            match &mesh.material {
+                Some(material) if scene.material_index(material).is_none() => {
+                    return Err(parser.invalid(format!(
+                        "mesh {} refers to a missing material {}",
+                        mesh.name, material
+                    )))
+                }
+                _ => (),
+            }
+        }
+        for model in &scene.models {
+            if let Some((_, mesh)) = model
+                .meshes
+                .iter()
+                .find(|(_, m)| scene.mesh_index(m).is_none())
+            {
+                return Err(parser.invalid(format!(
+                    "model {} refers to a missing mesh {}",
+                    model.name, mesh
+                )));
+            }
+        }
+        let mut pending: Vec<&NodeDescription> = scene.nodes.iter().collect();
+        while let Some(node) = pending.pop() {
+            if let Some(mesh) = &node.mesh {
+                if scene.mesh_index(mesh).is_none() {
+                    return Err(parser.invalid(format!(
+                        "node {} refers to a missing mesh {}",
+                        node.name, mesh
+                    )));
+                }
+            }
+            match &node.model {
+                Some(model) if scene.model_index(model).is_none() => {
+                    return Err(parser.invalid(format!(
+                        "node {} refers to a missing model {}",
+                        node.name, model
+                    )))
+                }
+                None if node
+                    .animations
+                    .iter()
+                    .any(|a| matches!(a, NodeAnimation::Clip { .. })) =>
+                {
+                    return Err(parser.invalid(format!(
+                        "node {} has a clip animation, but no model",
+                        node.name
+                    )))
+                }
+                _ => (),
+            }
+            pending.extend(&node.children);
+        }
+        Ok(scene)
+    }
+
+    pub fn write(&self, scene_path: &str) -> Result<(), SceneFileError> {
+        std::fs::write(scene_path, self.to_json().pretty() + "\n").map_err(|source| {
+            SceneFileError::Io {
+                path: scene_path.to_string(),
+                source,
+            }
+        })
+    }
+
+    pub fn mesh_index(&self, name: &str) -> Option<usize> {
+        self.meshes.iter().position(|mesh| mesh.name == name)
+    }
+
+    pub fn material_index(&self, name: &str) -> Option<usize> {
+        self.materials
+            .iter()
+            .position(|material| material.name == name)
+    }
+
+    pub fn model_index(&self, name: &str) -> Option<usize> {
+        self.models.iter().position(|model| model.name == name)
+    }
+
+    /**
+     * Loads every mesh, in the order of meshes. Each OBJ file is only read once, however many
+     * of its parts are used, and through the mesh cache like Model::load. OBJ files are read
+     * strictly, so a broken asset stops the scene from loading instead of drawing wrong.
+     */
+    pub fn load_meshes(&self) -> Result<Vec<Mesh>, SceneFileError> {
+        let mut files = MeshFiles {
+            models: HashMap::new(),
+            gltf_scenes: HashMap::new(),
+        };
+        let mut meshes = vec![];
+        for description in &self.meshes {
+            let mut mesh = match &description.source {
+                MeshSource::File { path, part } => files.load(path, part.as_deref())?,
+                MeshSource::Heightmap { path, terrain } => {
+                    let heightmap = Heightmap::load(path).map_err(|e| SceneFileError::Mesh {
+                        path: path.clone(),
+                        reason: e.to_string(),
+                    })?;
+                    println!(
+                        "Loaded {} with {}x{} samples.",
+                        path, heightmap.width, heightmap.depth
+                    );
+                    let mut mesh = heightmap.to_mesh(terrain);
+                    mesh.color_by_height(&TERRAIN_COLORS);
+                    mesh
+                }
+                MeshSource::Noise {
+                    width,
+                    depth,
+                    noise,
+                    erosion,
+                    terrain,
+                } => {
+                    println!("Generating {}x{} terrain from noise...", width, depth);
+                    Terrain::generate(*width, *depth, noise, erosion, terrain)
+                }
+            };
+
+            let material = description
+                .material
+                .as_ref()
+                .and_then(|name| self.material_index(name));
+            if let Some(material) = material {
+                let material = &self.materials[material];
+                mesh.set_color(material.diffuse_color());
+                mesh.material = Some(material.clone());
+            }
+            if let (Some(color), None) = (description.color, &mesh.material) {
+                mesh.set_color(color);
+            }
+            if description.flat_shading {
+                mesh.compute_flat_normals();
+            }
+            meshes.push(mesh);
+        }
+        Ok(meshes)
+    }
+
+    /**
+     * Reads every model, in the order of models, and checks that the nodes and clips the scene
+     * refers to are in them.
+     */
+    pub fn load_models(&self) -> Result<Vec<GltfScene>, SceneFileError> {
+        let models = self
+            .models
+            .iter()
+            .map(|model| {
+                read_gltf(&model.path).map_err(|e| SceneFileError::Model {
+                    path: model.path.clone(),
+                    reason: e.to_string(),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for (description, model) in self.models.iter().zip(&models) {
+            let missing = description
+                .meshes
+                .iter()
+                .find(|(node, _)| !model.nodes.iter().any(|n| &n.name == node));
+            if let Some((node, _)) = missing {
+                return Err(SceneFileError::Model {
+                    path: description.path.clone(),
+                    reason: format!("the file has no node named {}", node),
+                });
+            }
+        }
+
+        let mut pending: Vec<&NodeDescription> = self.nodes.iter().collect();
+        while let Some(node) = pending.pop() {
+            if let Some(model) = node.model.as_ref().and_then(|m| self.model_index(m)) {
+                for animation in &node.animations {
+                    match animation {
+                        NodeAnimation::Clip { name } if models[model].animation(name).is_none() => {
+                            return Err(SceneFileError::Model {
+                                path: self.models[model].path.clone(),
+                                reason: format!("the file has no animation named {}", name),
+                            })
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            pending.extend(&node.children);
+        }
+        Ok(models)
+    }
+
+    /**
+     * Adds the nodes below parent, parents before their children. Nodes drawing a mesh are
+     * created by make_node, given the index of the mesh, so the caller decides how meshes are
+     * uploaded and which levels of detail they get. Everything else is set from the file, and
+     * the nodes of models, as given by load_models, are added below the nodes using them.
+     */
+    pub fn instantiate<F>(
+        &self,
+        graph: &mut SceneGraph,
+        parent: NodeId,
+        models: &[GltfScene],
+        mut make_node: F,
+    ) -> SceneInstance
+    where
+        F: FnMut(usize) -> SceneNode,
+    {
+        let mut instance = SceneInstance {
+            meshes: HashMap::new(),
+            models: HashMap::new(),
+            animations: vec![],
+        };
+
+        let mut pending: Vec<(&NodeDescription, NodeId)> =
+            self.nodes.iter().rev().map(|node| (node, parent)).collect();
+        while let Some((description, parent)) = pending.pop() {
+            let mesh = description
+                .mesh
+                .as_ref()
+                .and_then(|name| self.mesh_index(name));
+            let mut node = match mesh {
+                Some(mesh) => make_node(mesh),
+                None => SceneNode::new(),
+            };
+            node.name = description.name.clone();
+            node.tags = description.tags.clone();
+            node.position = description.position;
+            node.rotation = description.rotation;
+            node.scale = description.scale;
+            node.reference_point = description.reference_point;
+
+            let id = graph.add_child(parent, node);
+            if let Some(mesh) = mesh {
+                instance.meshes.insert(id, mesh);
+            }
+            if let Some(model) = description.model.as_ref().and_then(|m| self.model_index(m)) {
+                let mesh_of = |node: &str| {
+                    let meshes = &self.models[model].meshes;
+                    let (_, mesh) = meshes.iter().find(|(n, _)| n == node)?;
+                    self.mesh_index(mesh)
+                };
+                let model_instance =
+                    models[model].instantiate(graph, id, |gltf_node| {
+                        match mesh_of(&gltf_node.name) {
+                            Some(mesh) => make_node(mesh),
+                            None => SceneNode::new(),
+                        }
+                    });
+                for (gltf_node, &node_id) in models[model].nodes.iter().zip(&model_instance.nodes) {
+                    if let (Some(mesh), Some(node_id)) = (mesh_of(&gltf_node.name), node_id) {
+                        instance.meshes.insert(node_id, mesh);
+                    }
+                }
+                instance.models.insert(id, (model, model_instance));
+            }
+            for animation in &description.animations {
+                instance.animations.push((id, animation.clone()));
+            }
+            pending.extend(description.children.iter().rev().map(|child| (child, id)));
+        }
+        instance
+    }
+
+    /**
+     * Replaces the nodes with those below parent in the graph as they are now, e.g. before
+     * writing the scene back out. Nodes added since instantiate are kept, but without a mesh,
+     * as there is no telling where theirs came from. The nodes of models are left out, as they
+     * are made from the model again.
+     */
+    pub fn capture(&mut self, graph: &SceneGraph, parent: NodeId, instance: &SceneInstance) {
+        self.nodes = self.capture_children(graph, parent, instance);
+    }
+
+    fn capture_children(
+        &self,
+        graph: &SceneGraph,
+        id: NodeId,
+        instance: &SceneInstance,
+    ) -> Vec<NodeDescription> {
+        graph[id]
+            .children()
+            .iter()
+            .filter(|&&child| !instance.is_model_node(child))
+            .map(|&child| self.capture_node(graph, child, instance))
+            .collect()
+    }
+
+    fn capture_node(
+        &self,
+        graph: &SceneGraph,
+        id: NodeId,
+        instance: &SceneInstance,
+    ) -> NodeDescription {
+        let node = &graph[id];
+        NodeDescription {
+            name: node.name.clone(),
+            tags: node.tags.clone(),
+            mesh: instance
+                .meshes
+                .get(&id)
+                .map(|&mesh| self.meshes[mesh].name.clone()),
+            model: instance
+                .models
+                .get(&id)
+                .map(|&(model, _)| self.models[model].name.clone()),
+            position: node.position,
+            rotation: node.rotation,
+            scale: node.scale,
+            reference_point: node.reference_point,
+            animations: instance
+                .animations
+                .iter()
+                .filter(|(node, _)| *node == id)
+                .map(|(_, animation)| animation.clone())
+                .collect(),
+            children: self.capture_children(graph, id, instance),
+        }
+    }
+
+    pub fn to_json(&self) -> Json {
+        let mut members = vec![];
+        if !self.shaders.is_empty() {
+            let shaders = self
+                .shaders
+                .iter()
+                .map(|s| Json::from(s.as_str()))
+                .collect();
+            members.push(("shaders".to_string(), Json::Array(shaders)));
+        }
+
+        let camera = &self.camera;
+        let camera_json = Json::Object(vec![
+            (
+                "position".to_string(),
+                Json::from(camera.position.as_slice()),
+            ),
+            ("pitch".to_string(), Json::from(camera.pitch)),
+            ("yaw".to_string(), Json::from(camera.yaw)),
+            ("fovy".to_string(), Json::from(camera.fovy)),
+            ("near".to_string(), Json::from(camera.near)),
+            ("far".to_string(), Json::from(camera.far)),
+        ]);
+        members.push(("camera".to_string(), camera_json));
+
+        if !self.lights.is_empty() {
+            let lights = self.lights.iter().map(|light| {
+                Json::Object(vec![
+                    ("name".to_string(), Json::from(light.name.as_str())),
+                    (
+                        "direction".to_string(),
+                        Json::from(light.direction.as_slice()),
+                    ),
+                    ("color".to_string(), Json::from(light.color.as_slice())),
+                ])
+            });
+            members.push(("lights".to_string(), Json::Array(lights.collect())));
+        }
+
+        if !self.materials.is_empty() {
+            let materials = self.materials.iter().map(|material| {
+                Json::Object(vec![
+                    ("name".to_string(), Json::from(material.name.as_str())),
+                    ("ambient".to_string(), Json::from(&material.ambient[..])),
+                    ("diffuse".to_string(), Json::from(&material.diffuse[..])),
+                    ("specular".to_string(), Json::from(&material.specular[..])),
+                    ("shininess".to_string(), Json::from(material.shininess)),
+                    ("dissolve".to_string(), Json::from(material.dissolve)),
+                ])
+            });
+            members.push(("materials".to_string(), Json::Array(materials.collect())));
+        }
+
+        let meshes = self.meshes.iter().map(|mesh| {
+            let mut members = vec![("name".to_string(), Json::from(mesh.name.as_str()))];
+            match &mesh.source {
+                MeshSource::File { path, part } => {
+                    members.push(("path".to_string(), Json::from(path.as_str())));
+                    if let Some(part) = part {
+                        members.push(("part".to_string(), Json::from(part.as_str())));
+                    }
+                }
+                MeshSource::Heightmap { path, terrain } => {
+                    let mut heightmap = vec![("path".to_string(), Json::from(path.as_str()))];
+                    heightmap.extend(terrain_to_json(terrain));
+                    members.push(("heightmap".to_string(), Json::Object(heightmap)));
+                }
+                MeshSource::Noise {
+                    width,
+                    depth,
+                    noise,
+                    erosion,
+                    terrain,
+                } => {
+                    let mut members_of_noise = vec![
+                        ("width".to_string(), Json::from(*width)),
+                        ("depth".to_string(), Json::from(*depth)),
+                        ("seed".to_string(), Json::from(noise.seed as usize)),
+                        ("octaves".to_string(), Json::from(noise.octaves as usize)),
+                        ("frequency".to_string(), Json::from(noise.frequency)),
+                        ("lacunarity".to_string(), Json::from(noise.lacunarity)),
+                        ("persistence".to_string(), Json::from(noise.persistence)),
+                        (
+                            "kind".to_string(),
+                            Json::from(fractal_kind_name(noise.kind)),
+                        ),
+                    ];
+                    members_of_noise.extend(terrain_to_json(terrain));
+                    let passes = erosion.iter().map(erosion_to_json).collect();
+                    members_of_noise.push(("erosion".to_string(), Json::Array(passes)));
+                    members.push(("noise".to_string(), Json::Object(members_of_noise)));
+                }
+            }
+            if let Some(material) = &mesh.material {
+                members.push(("material".to_string(), Json::from(material.as_str())));
+            }
+            if let Some(color) = &mesh.color {
+                members.push(("color".to_string(), Json::from(&color[..])));
+            }
+            if mesh.lods {
+                members.push(("lods".to_string(), Json::from(true)));
+            }
+            if mesh.flat_shading {
+                members.push(("flat_shading".to_string(), Json::from(true)));
+            }
+            Json::Object(members)
+        });
+        members.push(("meshes".to_string(), Json::Array(meshes.collect())));
+
+        if !self.models.is_empty() {
+            let models = self.models.iter().map(|model| {
+                let meshes = model
+                    .meshes
+                    .iter()
+                    .map(|(node, mesh)| (node.clone(), Json::from(mesh.as_str())))
+                    .collect();
+                Json::Object(vec![
+                    ("name".to_string(), Json::from(model.name.as_str())),
+                    ("path".to_string(), Json::from(model.path.as_str())),
+                    ("meshes".to_string(), Json::Object(meshes)),
+                ])
+            });
+            members.push(("models".to_string(), Json::Array(models.collect())));
+        }
+
+        let nodes = self.nodes.iter().map(node_to_json).collect();
+        members.push(("nodes".to_string(), Json::Array(nodes)));
+        Json::Object(members)
+    }
+}
+
+fn terrain_to_json(terrain: &TerrainSettings) -> Vec<(String, Json)> {
+    vec![
+        ("spacing".to_string(), Json::from(terrain.spacing)),
+        ("scale".to_string(), Json::from(terrain.vertical_scale)),
+        ("skirt".to_string(), Json::from(terrain.skirt_depth)),
+    ]
+}
+
+fn erosion_to_json(erosion: &Erosion) -> Json {
+    match erosion {
+        Erosion::Hydraulic(hydraulic) => Json::Object(vec![
+            ("type".to_string(), Json::from("hydraulic")),
+            ("seed".to_string(), Json::from(hydraulic.seed as usize)),
+            (
+                "droplets".to_string(),
+                Json::from(hydraulic.droplets as usize),
+            ),
+            (
+                "max_steps".to_string(),
+                Json::from(hydraulic.max_steps as usize),
+            ),
+            ("erosion".to_string(), Json::from(hydraulic.erosion)),
+            ("deposition".to_string(), Json::from(hydraulic.deposition)),
+            ("evaporation".to_string(), Json::from(hydraulic.evaporation)),
+        ]),
+        Erosion::Thermal(thermal) => Json::Object(vec![
+            ("type".to_string(), Json::from("thermal")),
+            (
+                "iterations".to_string(),
+                Json::from(thermal.iterations as usize),
+            ),
+            ("talus".to_string(), Json::from(thermal.talus)),
+            ("rate".to_string(), Json::from(thermal.rate)),
+        ]),
+    }
+}
+
+// The first whole number a double cannot hold exactly along with its neighbours, 2^53
+const MAX_EXACT_COUNT: f64 = 9_007_199_254_740_992.;
+
+const FRACTAL_KINDS: [(FractalKind, &str); 3] = [
+    (FractalKind::Standard, "standard"),
+    (FractalKind::Ridged, "ridged"),
+    (FractalKind::Billow, "billow"),
+];
+
+fn fractal_kind_name(kind: FractalKind) -> &'static str {
+    FRACTAL_KINDS.iter().find(|(k, _)| *k == kind).unwrap().1
+}
+
+fn node_to_json(node: &NodeDescription) -> Json {
+    let mut members = vec![("name".to_string(), Json::from(node.name.as_str()))];
+    let mut add = |key: &str, value: Json| members.push((key.to_string(), value));
+
+    if !node.tags.is_empty() {
+        add(
+            "tags",
+            Json::Array(node.tags.iter().map(|t| Json::from(t.as_str())).collect()),
+        );
+    }
+    if let Some(mesh) = &node.mesh {
+        add("mesh", Json::from(mesh.as_str()));
+    }
+    if let Some(model) = &node.model {
+        add("model", Json::from(model.as_str()));
+    }
+    let vectors = [
+        ("position", node.position, glm::zero()),
+        ("rotation", node.rotation, glm::zero()),
+        ("scale", node.scale, glm::vec3(1., 1., 1.)),
+        ("reference_point", node.reference_point, glm::zero()),
+    ];
+    for (key, value, default) in &vectors {
+        if value != default {
+            add(key, Json::from(value.as_slice()));
+        }
+    }
+    if !node.animations.is_empty() {
+        let animations = node.animations.iter().map(|animation| match animation {
+            NodeAnimation::Spin { speed } => Json::Object(vec![
+                ("type".to_string(), Json::from("spin")),
+                ("speed".to_string(), Json::from(speed.as_slice())),
+            ]),
+            NodeAnimation::Heading { offset } => Json::Object(vec![
+                ("type".to_string(), Json::from("heading")),
+                ("offset".to_string(), Json::from(*offset)),
+            ]),
+            NodeAnimation::Clip { name } => Json::Object(vec![
+                ("type".to_string(), Json::from("clip")),
+                ("name".to_string(), Json::from(name.as_str())),
+            ]),
+        });
+        add("animations", Json::Array(animations.collect()));
+    }
+    if !node.children.is_empty() {
+        add(
+            "children",
+            Json::Array(node.children.iter().map(node_to_json).collect()),
+        );
+    }
+    Json::Object(members)
+}
+
+// Reads the parts of a scene, reporting where in it anything is wrong
+struct Parser<'a> {
+    path: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn invalid(&self, reason: String) -> SceneFileError {
+        SceneFileError::Invalid {
+            path: self.path.to_string(),
+            reason,
+        }
+    }
+
+    // None if missing, and an error if there but not n numbers
+    fn numbers(
+        &self,
+        json: &Json,
+        n: usize,
+        what: &str,
+    ) -> Result<Option<Vec<f32>>, SceneFileError> {
+        if json.is_null() {
+            return Ok(None);
+        }
+        match json.as_f32s().filter(|numbers| numbers.len() == n) {
+            Some(numbers) => Ok(Some(numbers)),
+            None => Err(self.invalid(format!("{} must be {} numbers", what, n))),
+        }
+    }
+
+    fn number(&self, json: &Json, default: f32, what: &str) -> Result<f32, SceneFileError> {
+        if json.is_null() {
+            return Ok(default);
+        }
+        json.as_f32()
+            .ok_or_else(|| self.invalid(format!("{} must be a number", what)))
+    }
+
+    fn vec3(
+        &self,
+        json: &Json,
+        default: glm::Vec3,
+        what: &str,
+    ) -> Result<glm::Vec3, SceneFileError> {
+        Ok(self
+            .numbers(json, 3, what)?
+            .map_or(default, |v| glm::vec3(v[0], v[1], v[2])))
+    }
+
+    fn string(&self, json: &Json, what: &str) -> Result<Option<String>, SceneFileError> {
+        if json.is_null() {
+            return Ok(None);
+        }
+        match json.as_str() {
+            Some(s) => Ok(Some(s.to_string())),
+            None => Err(self.invalid(format!("{} must be a string", what))),
+        }
+    }
+
+    fn strings(&self, json: &Json, what: &str) -> Result<Vec<String>, SceneFileError> {
+        json.members()
+            .iter()
+            .map(|item| {
+                item.as_str()
+                    .map(|s| s.to_string())
+                    .ok_or_else(|| self.invalid(format!("{} must be strings", what)))
+            })
+            .collect()
+    }
+
+    // Spacing, scale and skirt of a terrain, defaulting to those of TerrainSettings
+    fn terrain(&self, json: &Json, what: &str) -> Result<TerrainSettings, SceneFileError> {
+        let default = TerrainSettings::default();
+        let what = |member: &str| format!("{} of {}", member, what);
+        Ok(TerrainSettings {
+            spacing: self.number(&json["spacing"], default.spacing, &what("spacing"))?,
+            vertical_scale: self.number(&json["scale"], default.vertical_scale, &what("scale"))?,
+            skirt_depth: self.number(&json["skirt"], default.skirt_depth, &what("skirt"))?,
+        })
+    }
+
+    /**
+     * A whole number, such as a count or a seed. JSON numbers are read as doubles, so anything
+     * from 2^53 up may already have been rounded, and is rejected rather than silently changing
+     * e.g. which terrain a seed gives.
+     */
+    fn count(&self, json: &Json, default: usize, what: &str) -> Result<usize, SceneFileError> {
+        if json.is_null() {
+            return Ok(default);
+        }
+        if json.as_f64().is_some_and(|n| n >= MAX_EXACT_COUNT) {
+            return Err(self.invalid(format!("{} must be below 2^53", what)));
+        }
+        json.as_usize()
+            .ok_or_else(|| self.invalid(format!("{} must be a whole number", what)))
+    }
+
+    fn count_u32(&self, json: &Json, default: u32, what: &str) -> Result<u32, SceneFileError> {
+        let count = self.count(json, default as usize, what)?;
+        u32::try_from(count)
+            .map_err(|_| self.invalid(format!("{} must be at most {}", what, u32::MAX)))
+    }
+
+    fn noise(&self, json: &Json, what: &str) -> Result<MeshSource, SceneFileError> {
+        let what = |member: &str| format!("{} of {}", member, what);
+        let default = NoiseSettings::default();
+        let kind = match self.string(&json["kind"], &what("kind"))? {
+            Some(name) => FRACTAL_KINDS
+                .iter()
+                .find(|(_, n)| *n == name)
+                .map(|&(kind, _)| kind)
+                .ok_or_else(|| {
+                    self.invalid(format!(
+                        "{} must be standard, ridged or billow",
+                        what("kind")
+                    ))
+                })?,
+            None => default.kind,
+        };
+        let noise = NoiseSettings {
+            seed: self.count(&json["seed"], default.seed as usize, &what("seed"))? as u64,
+            octaves: self.count_u32(&json["octaves"], default.octaves, &what("octaves"))?,
+            frequency: self.number(&json["frequency"], default.frequency, &what("frequency"))?,
+            lacunarity: self.number(
+                &json["lacunarity"],
+                default.lacunarity,
+                &what("lacunarity"),
+            )?,
+            persistence: self.number(
+                &json["persistence"],
+                default.persistence,
+                &what("persistence"),
+            )?,
+            kind,
+        };
+
+        let erosion = json["erosion"]
+            .members()
+            .iter()
+            .map(|pass| match pass["type"].as_str() {
+                Some("hydraulic") => {
+                    let what = |member: &str| what(&format!("hydraulic erosion {}", member));
+                    let default = HydraulicErosion::default();
+                    Ok(Erosion::Hydraulic(HydraulicErosion {
+                        seed: self.count(&pass["seed"], default.seed as usize, &what("seed"))?
+                            as u64,
+                        droplets: self.count_u32(
+                            &pass["droplets"],
+                            default.droplets,
+                            &what("droplets"),
+                        )?,
+                        max_steps: self.count_u32(
+                            &pass["max_steps"],
+                            default.max_steps,
+                            &what("max_steps"),
+                        )?,
+                        erosion: self.number(
+                            &pass["erosion"],
+                            default.erosion,
+                            &what("erosion"),
+                        )?,
+                        deposition: self.number(
+                            &pass["deposition"],
+                            default.deposition,
+                            &what("deposition"),
+                        )?,
+                        evaporation: self.number(
+                            &pass["evaporation"],
+                            default.evaporation,
+                            &what("evaporation"),
+                        )?,
+                        ..default
+                    }))
+                }
+                Some("thermal") => {
+                    let what = |member: &str| what(&format!("thermal erosion {}", member));
+                    let default = ThermalErosion::default();
+                    Ok(Erosion::Thermal(ThermalErosion {
+                        iterations: self.count_u32(
+                            &pass["iterations"],
+                            default.iterations,
+                            &what("iterations"),
+                        )?,
+                        talus: self.number(&pass["talus"], default.talus, &what("talus"))?,
+                        rate: self.number(&pass["rate"], default.rate, &what("rate"))?,
+                    }))
+                }
+                _ => Err(self.invalid(format!(
+                    "{} must be hydraulic or thermal",
+                    what("erosion type")
+                ))),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(MeshSource::Noise {
+            width: self.count(&json["width"], 256, &what("width"))?,
+            depth: self.count(&json["depth"], 256, &what("depth"))?,
+            noise,
+            erosion,
+            terrain: self.terrain(json, &what("terrain"))?,
+        })
+    }
+
+    fn scene(&self, json: &Json) -> Result<SceneFile, SceneFileError> {
+        let camera_json = &json["camera"];
+        let default = CameraDescription::default();
+        let camera = CameraDescription {
+            position: self.vec3(
+                &camera_json["position"],
+                default.position,
+                "camera position",
+            )?,
+            pitch: self.number(&camera_json["pitch"], default.pitch, "camera pitch")?,
+            yaw: self.number(&camera_json["yaw"], default.yaw, "camera yaw")?,
+            fovy: self.number(&camera_json["fovy"], default.fovy, "camera fovy")?,
+            near: self.number(&camera_json["near"], default.near, "camera near")?,
+            far: self.number(&camera_json["far"], default.far, "camera far")?,
+        };
+
+        let lights = json["lights"]
+            .members()
+            .iter()
+            .enumerate()
+            .map(|(i, light)| {
+                let what = |member: &str| format!("{} of light {}", member, i);
+                Ok(LightDescription {
+                    name: self
+                        .string(&light["name"], &what("name"))?
+                        .unwrap_or_default(),
+                    direction: self.vec3(
+                        &light["direction"],
+                        glm::vec3(0., -1., 0.),
+                        &what("direction"),
+                    )?,
+                    color: self.vec3(&light["color"], glm::vec3(1., 1., 1.), &what("color"))?,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let materials = json["materials"]
+            .members()
+            .iter()
+            .enumerate()
+            .map(|(i, material)| {
+                let what = |member: &str| format!("{} of material {}", member, i);
+                let name = self
+                    .string(&material["name"], &what("name"))?
+                    .ok_or_else(|| self.invalid(format!("material {} has no name", i)))?;
+                let default = Material::new(&name);
+                let rgb = |member: &str, default: [f32; 3]| {
+                    let color = self.vec3(&material[member], default.into(), &what(member))?;
+                    Ok::<_, SceneFileError>([color.x, color.y, color.z])
+                };
+                Ok(Material {
+                    ambient: rgb("ambient", default.ambient)?,
+                    diffuse: rgb("diffuse", default.diffuse)?,
+                    specular: rgb("specular", default.specular)?,
+                    shininess: self.number(
+                        &material["shininess"],
+                        default.shininess,
+                        &what("shininess"),
+                    )?,
+                    dissolve: self.number(
+                        &material["dissolve"],
+                        default.dissolve,
+                        &what("dissolve"),
+                    )?,
+                    ..default
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let meshes = json["meshes"]
+            .members()
+            .iter()
+            .enumerate()
+            .map(|(i, mesh)| {
+                let what = |member: &str| format!("{} of mesh {}", member, i);
+                let heightmap = &mesh["heightmap"];
+                let noise = &mesh["noise"];
+                let source = if !noise.is_null() {
+                    self.noise(noise, &what("noise"))?
+                } else if !heightmap.is_null() {
+                    MeshSource::Heightmap {
+                        path: self
+                            .string(&heightmap["path"], &what("heightmap path"))?
+                            .ok_or_else(|| {
+                                self.invalid(format!("mesh {} has a heightmap without a path", i))
+                            })?,
+                        terrain: self.terrain(heightmap, &what("heightmap"))?,
+                    }
+                } else {
+                    MeshSource::File {
+                        path: self
+                            .string(&mesh["path"], &what("path"))?
+                            .ok_or_else(|| self.invalid(format!("mesh {} has no path", i)))?,
+                        part: self.string(&mesh["part"], &what("part"))?,
+                    }
+                };
+                Ok(MeshDescription {
+                    name: self
+                        .string(&mesh["name"], &what("name"))?
+                        .ok_or_else(|| self.invalid(format!("mesh {} has no name", i)))?,
+                    source,
+                    material: self.string(&mesh["material"], &what("material"))?,
+                    color: self
+                        .numbers(&mesh["color"], 4, &what("color"))?
+                        .map(|c| [c[0], c[1], c[2], c[3]]),
+                    lods: mesh["lods"].as_bool().unwrap_or(false),
+                    flat_shading: mesh["flat_shading"].as_bool().unwrap_or(false),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let models = json["models"]
+            .members()
+            .iter()
+            .enumerate()
+            .map(|(i, model)| {
+                let what = |member: &str| format!("{} of model {}", member, i);
+                let meshes = model["meshes"]
+                    .entries()
+                    .iter()
+                    .map(|(node, mesh)| match mesh.as_str() {
+                        Some(mesh) => Ok((node.clone(), mesh.to_string())),
+                        None => Err(self.invalid(format!("{} must be strings", what("meshes")))),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(ModelDescription {
+                    name: self
+                        .string(&model["name"], &what("name"))?
+                        .ok_or_else(|| self.invalid(format!("model {} has no name", i)))?,
+                    path: self
+                        .string(&model["path"], &what("path"))?
+                        .ok_or_else(|| self.invalid(format!("model {} has no path", i)))?,
+                    meshes,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let nodes = json["nodes"]
+            .members()
+            .iter()
+            .map(|node| self.node(node, ""))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(SceneFile {
+            shaders: self.strings(&json["shaders"], "shaders")?,
+            camera,
+            lights,
+            materials,
+            meshes,
+            models,
+            nodes,
+        })
+    }
+
+    // Nodes are named by their path in errors, e.g. "node terrain/heli_0: ..."
+    fn node(&self, json: &Json, parent_path: &str) -> Result<NodeDescription, SceneFileError> {
+        let name = self.string(&json["name"], "node name")?.unwrap_or_default();
+        let path = if parent_path.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", parent_path, name)
+        };
+        let what = |member: &str| format!("{} of node {}", member, path);
+
+        let animations = json["animations"]
+            .members()
+            .iter()
+            .map(|animation| match animation["type"].as_str() {
+                Some("spin") => Ok(NodeAnimation::Spin {
+                    speed: self.vec3(&animation["speed"], glm::zero(), &what("spin speed"))?,
+                }),
+                Some("heading") => Ok(NodeAnimation::Heading {
+                    offset: self.number(&animation["offset"], 0., &what("heading offset"))?,
+                }),
+                Some("clip") => Ok(NodeAnimation::Clip {
+                    name: self
+                        .string(&animation["name"], &what("clip name"))?
+                        .ok_or_else(|| {
+                            self.invalid(format!("node {} has a clip without a name", path))
+                        })?,
+                }),
+                _ => Err(self.invalid(format!(
+                    "node {} has an animation whose type is not spin, heading or clip",
+                    path
+                ))),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let children = json["children"]
+            .members()
+            .iter()
+            .map(|child| self.node(child, &path))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(NodeDescription {
+            tags: self.strings(&json["tags"], &what("tags"))?,
+            mesh: self.string(&json["mesh"], &what("mesh"))?,
+            model: self.string(&json["model"], &what("model"))?,
+            position: self.vec3(&json["position"], glm::zero(), &what("position"))?,
+            rotation: self.vec3(&json["rotation"], glm::zero(), &what("rotation"))?,
+            scale: self.vec3(&json["scale"], glm::vec3(1., 1., 1.), &what("scale"))?,
+            reference_point: self.vec3(
+                &json["reference_point"],
+                glm::zero(),
+                &what("reference_point"),
+            )?,
+            animations,
+            children,
+            name,
+        })
+    }
+}
+
+// The mesh files read so far, so each is only read once however many of its parts are used
+struct MeshFiles<'a> {
+    models: HashMap<&'a str, Model>,
+    gltf_scenes: HashMap<&'a str, GltfScene>,
+}
+
+impl<'a> MeshFiles<'a> {
+    fn load(&mut self, path: &'a str, part: Option<&str>) -> Result<Mesh, SceneFileError> {
+        let mesh_error = |reason: String| SceneFileError::Mesh {
+            path: path.to_string(),
+            reason,
+        };
+
+        let extension = Path::new(path).extension().and_then(|e| e.to_str());
+        match extension.map(|e| e.to_ascii_lowercase()).as_deref() {
+            Some("obj") => {
+                if !self.models.contains_key(path) {
+                    let (model, report) = Model::load(path, ParseMode::Strict)
+                        .map_err(|e| mesh_error(e.to_string()))?;
+                    print!("{}", report);
+                    self.models.insert(path, model);
+                }
+                let model = &self.models[path];
+                match part {
+                    Some(part) => model.get(part).cloned().ok_or_else(|| {
+                        let names: Vec<&str> = model.names().collect();
+                        mesh_error(format!(
+                            "the model has no part named {}, only {}",
+                            part,
+                            names.join(", ")
+                        ))
+                    }),
+                    None => Ok(Mesh::merge(
+                        model.parts.iter().map(|p| p.mesh.clone()).collect(),
+                    )),
+                }
+            }
+            Some("gltf") | Some("glb") => {
+                if !self.gltf_scenes.contains_key(path) {
+                    let gltf_scene = read_gltf(path).map_err(|e| mesh_error(e.to_string()))?;
+                    self.gltf_scenes.insert(path, gltf_scene);
+                }
+                let gltf_scene = &self.gltf_scenes[path];
+                match part {
+                    Some(part) => gltf_scene
+                        .mesh_index(part)
+                        .map(|i| gltf_scene.meshes[i].clone())
+                        .ok_or_else(|| mesh_error(format!("the file has no mesh named {}", part))),
+                    None => Ok(Mesh::merge(gltf_scene.meshes.clone())),
+                }
+            }
+            Some("ply") => PlyReader::read(path).map_err(|e| mesh_error(e.to_string())),
+            Some("stl") => StlReader::read(path).map_err(|e| mesh_error(e.to_string())),
+            _ => Err(mesh_error(
+                "expected an .obj, .ply, .stl, .gltf or .glb file".to_string(),
+            )),
+        }
+    }
+}
+
+// Prints what was read and skipped, like the load report of Model::load
+fn read_gltf(path: &str) -> Result<GltfScene, GltfError> {
+    let gltf_scene = GltfReader::read(path)?;
+    println!("Loaded {}.", path);
+    for warning in &gltf_scene.warnings {
+        println!("  Warning: {}", warning);
+    }
+    Ok(gltf_scene)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn helicopters_are_rigged_by_their_model() {
+        let mut scene = SceneFile::read("./resources/scene.json").unwrap();
+        let models = scene.load_models().unwrap();
+        let mut graph = SceneGraph::new();
+        let root = graph.root();
+        let instance = scene.instantiate(&mut graph, root, &models, |_| SceneNode::new());
+
+        let tail_rotor = graph
+            .find("terrain/heli_0/body/tail_rotor_pivot/tail_rotor")
+            .unwrap();
+        assert_eq!(
+            instance.meshes[&tail_rotor],
+            scene.mesh_index("heli_tail_rotor").unwrap()
+        );
+
+        // A quarter turn of the rotors in, the main rotor has turned 90 degrees about Y, and the
+        // tail rotor has turned about its hub rather than the origin of the helicopter
+        instance.animate(&mut graph, &models, std::f32::consts::PI / 20.);
+        graph.update_transforms();
+        let main_rotor = graph.find("terrain/heli_0/body/main_rotor").unwrap();
+        let main_turn = glm::vec3(0., std::f32::consts::FRAC_PI_2, 0.);
+        assert!(glm::distance(&graph[main_rotor].rotation, &main_turn) < 1e-3);
+
+        let heli = graph.find("terrain/heli_0").unwrap();
+        let hub = glm::vec4(0.35, 2.3, 10.4, 1.);
+        let hub_on_rotor = graph.world_transform(tail_rotor) * hub;
+        let hub_on_heli = graph.world_transform(heli) * hub;
+        assert!(glm::distance(&hub_on_rotor, &hub_on_heli) < 1e-4);
+
+        // The nodes of the model are made again when loading, so they are not saved
+        scene.capture(&graph, root, &instance);
+        let saved = SceneFile::parse(&scene.to_json().pretty()).unwrap();
+        let heli = &saved.nodes[0].children[0];
+        assert_eq!(heli.model.as_deref(), Some("helicopter"));
+        assert!(heli.children.is_empty());
+        assert!(matches!(&heli.animations[1], NodeAnimation::Clip { name } if name == "rotors"));
+        assert_eq!(saved.models[0].meshes, scene.models[0].meshes);
+    }
+
+    #[test]
+    fn heightmap_meshes_are_built_from_images() {
+        let path = std::env::temp_dir().join("gloom_scene_file_heightmap.png");
+        let image = image::GrayImage::from_fn(3, 3, |x, z| {
+            image::Luma([if (x, z) == (1, 1) { 255 } else { 0 }])
+        });
+        image.save(&path).unwrap();
+
+        let src = format!(
+            r#"{{ "meshes": [{{ "name": "hill", "heightmap": {{ "path": "{}",
+                 "spacing": 2, "scale": 10, "skirt": 1 }} }}] }}"#,
+            path.display()
+        );
+        let scene = SceneFile::parse(&src).unwrap();
+        let meshes = scene.load_meshes().unwrap();
+        assert_eq!(meshes[0].bounds.min, glm::vec3(-2., -1., -2.));
+        assert_eq!(meshes[0].bounds.max, glm::vec3(2., 10., 2.));
+
+        let saved = SceneFile::parse(&scene.to_json().pretty()).unwrap();
+        match &saved.meshes[0].source {
+            MeshSource::Heightmap {
+                path: saved_path,
+                terrain,
+            } => {
+                assert_eq!(saved_path, &path.display().to_string());
+                assert_eq!(terrain.spacing, 2.);
+                assert_eq!(terrain.vertical_scale, 10.);
+                assert_eq!(terrain.skirt_depth, 1.);
+            }
+            source => panic!("expected a heightmap, got {:?}", source),
+        }
+    }
+
+    #[test]
+    fn the_noise_scene_only_swaps_the_terrain() {
+        let scene = SceneFile::read("./resources/scene.json").unwrap();
+        let noise_scene = SceneFile::read("./resources/scene_noise.json").unwrap();
+        assert!(matches!(scene.meshes[0].source, MeshSource::File { .. }));
+        assert!(matches!(
+            noise_scene.meshes[0].source,
+            MeshSource::Noise { .. }
+        ));
+        let names = |scene: &SceneFile| -> Vec<String> {
+            scene.meshes.iter().map(|mesh| mesh.name.clone()).collect()
+        };
+        assert_eq!(names(&scene), names(&noise_scene));
+        assert_eq!(scene.nodes.len(), noise_scene.nodes.len());
+    }
+
+    #[test]
+    fn noise_meshes_are_generated_and_saved() {
+        let src = r#"{ "meshes": [{ "name": "hills", "noise": {
+                 "width": 8, "depth": 8, "seed": 3, "kind": "billow", "scale": 10,
+                 "erosion": [{ "type": "hydraulic", "droplets": 100 },
+                             { "type": "thermal", "iterations": 2 }] } }] }"#;
+        let scene = SceneFile::parse(src).unwrap();
+        let meshes = scene.load_meshes().unwrap();
+        assert_eq!(meshes[0].bounds.min.x, -3.5);
+        assert_eq!(meshes[0].bounds.max.z, 3.5);
+
+        let saved = scene.to_json().pretty();
+        match &SceneFile::parse(&saved).unwrap().meshes[0].source {
+            MeshSource::Noise {
+                width,
+                noise,
+                erosion,
+                terrain,
+                ..
+            } => {
+                assert_eq!(*width, 8);
+                assert_eq!(noise.seed, 3);
+                assert_eq!(noise.kind, FractalKind::Billow);
+                assert_eq!(terrain.vertical_scale, 10.);
+                match erosion.as_slice() {
+                    [Erosion::Hydraulic(hydraulic), Erosion::Thermal(thermal)] => {
+                        assert_eq!(hydraulic.droplets, 100);
+                        assert_eq!(thermal.iterations, 2);
+                    }
+                    passes => panic!("expected hydraulic then thermal, got {:?}", passes),
+                }
+            }
+            source => panic!("expected noise, got {:?}", source),
+        }
+
+        let error = SceneFile::parse(&src.replace("billow", "wavy")).unwrap_err();
+        assert!(
+            error.to_string().contains("kind of noise of mesh 0"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn counts_and_seeds_must_fit_exactly() {
+        let scene = |noise: &str| {
+            let src = format!(
+                r#"{{ "meshes": [{{ "name": "hills", "noise": {} }}] }}"#,
+                noise
+            );
+            SceneFile::parse(&src)
+        };
+        let seed = |scene: SceneFile| match &scene.meshes[0].source {
+            MeshSource::Noise { noise, .. } => noise.seed,
+            source => panic!("expected noise, got {:?}", source),
+        };
+
+        // The largest seed every double can tell apart from its neighbours
+        let largest = (1u64 << 53) - 1;
+        let src = format!(r#"{{ "seed": {} }}"#, largest);
+        assert_eq!(seed(scene(&src).unwrap()), largest);
+
+        // 2^53 + 1 reads as 2^53, so neither can be trusted
+        for noise in &[
+            r#"{ "seed": 9007199254740992 }"#,
+            r#"{ "seed": 9007199254740993 }"#,
+            r#"{ "seed": 1e300 }"#,
+            r#"{ "erosion": [{ "type": "hydraulic", "seed": 18446744073709551615 }] }"#,
+        ] {
+            let error = scene(noise).unwrap_err().to_string();
+            assert!(error.ends_with("must be below 2^53"), "{}", error);
+        }
+
+        for noise in &[
+            r#"{ "octaves": 4294967296 }"#,
+            r#"{ "erosion": [{ "type": "hydraulic", "droplets": 4294967296 }] }"#,
+            r#"{ "erosion": [{ "type": "hydraulic", "max_steps": 4294967296 }] }"#,
+            r#"{ "erosion": [{ "type": "thermal", "iterations": 4294967296 }] }"#,
+        ] {
+            let error = scene(noise).unwrap_err().to_string();
+            assert!(error.ends_with("must be at most 4294967295"), "{}", error);
+        }
+        assert!(scene(r#"{ "octaves": 4294967295, "width": 0 }"#).is_ok());
+    }
+
+    #[test]
+    fn meshes_take_materials_from_the_table_by_name() {
+        let src = r#"{
+            "materials": [{ "name": "paint", "diffuse": [0.5, 0.25, 0], "dissolve": 0.5,
+                            "shininess": 32 }],
+            "meshes": [{ "name": "flat", "noise": { "width": 2, "depth": 2 },
+                         "material": "paint", "color": [1, 1, 1, 1] }]
+        }"#;
+        let scene = SceneFile::parse(src).unwrap();
+        let meshes = scene.load_meshes().unwrap();
+        assert_eq!(&meshes[0].colors[..4], &[0.5, 0.25, 0., 0.5]);
+        let material = meshes[0].material.as_ref().unwrap();
+        assert_eq!(material.shininess, 32.);
+        assert_eq!(material.specular, Material::new("paint").specular);
+
+        let saved = SceneFile::parse(&scene.to_json().pretty()).unwrap();
+        assert_eq!(saved.meshes[0].material.as_deref(), Some("paint"));
+        assert_eq!(saved.materials[0].diffuse, [0.5, 0.25, 0.]);
+        assert_eq!(saved.materials[0].dissolve, 0.5);
+
+        let error =
+            SceneFile::parse(&src.replace(r#""material": "paint""#, r#""material": "rust""#))
+                .unwrap_err();
+        assert!(
+            error.to_string().contains("missing material rust"),
+            "{}",
+            error
+        );
+    }
+}
@@ -1,22 +1,36 @@
 extern crate nalgebra_glm as glm;
 
-use std::mem::ManuallyDrop;
-use std::pin::Pin;
-
-// Used to create an unholy abomination upon which you should not cast your gaze. This ended up
-// being a necessity due to wanting to keep the code written by students as "straight forward" as
-// possible. It is very very double plus ungood Rust, and intentionally leaks memory like a sieve.
-// But it works, and you're more than welcome to pretend it doesn't exist! In case you're curious
-// about how it works: It allocates memory on the heap (Box), promises to prevent it from being
-// moved or deallocated until dropped (Pin) and finally prevents the compiler from dropping it
-// automatically at all (ManuallyDrop).
-// ...
-// If that sounds like a janky solution, it's because it is!
-// Prettier, Rustier and better solutions were tried numerous times, but were all found wanting of
-// having what I arbitrarily decided to be the required level of "simplicity of use".
-pub type Node = ManuallyDrop<Pin<Box<SceneNode>>>;
+use std::fmt;
+use std::ops::{Index, IndexMut};
+
+use crate::bounds::Aabb;
+use crate::mesh::Mesh;
+
+// A handle to a node in a SceneGraph. Handles to removed nodes are never reused, so using one
+// afterwards is caught instead of reaching whichever node took its place.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct NodeId {
+    index      : u32,
+    generation : u32,
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "node {} (generation {})", self.index, self.generation)
+    }
+}
+
+// A coarser version of what a node draws, used once the node looks smaller than max_screen_size
+pub struct Lod {
+    pub vao_id          : u32,
+    pub index_count     : i32,
+    pub max_screen_size : f32,
+}
 
 pub struct SceneNode {
+    pub name : String,                 // What I'm called in paths, e.g. "main_rotor"
+    pub tags : Vec<String>,            // What kind of node I am, e.g. "rotor"
+
     pub position        : glm::Vec3,   // Where I should be in relation to my parent
     pub rotation        : glm::Vec3,   // How I should be rotated, around the X, the Y and the Z axes
     pub scale           : glm::Vec3,   // How I should be scaled
@@ -25,44 +39,129 @@ pub struct SceneNode {
     pub vao_id      : u32,             // What I should draw
     pub index_count : i32,             // How much of it there is to draw
 
-    pub children: Vec<*mut SceneNode>, // Those I command
+    pub local_bounds : Option<Aabb>,   // How large what I draw is, in my own coordinates
+    pub world_bounds : Aabb,           // How large I and those I command are, in the world,
+                                       // unbounded if any of us draws without local bounds
+
+    pub lods : Vec<Lod>,               // What I draw when far away, from most to least detailed
+
+    parent   : Option<NodeId>,         // Who commands me, kept up to date by the SceneGraph
+    children : Vec<NodeId>,            // Those I command
+
+    local_matrix : glm::Mat4,          // Cached by the SceneGraph, see update_transforms
+    world_matrix : glm::Mat4,
+    dirty        : bool,               // If I may have changed since my matrices were cached
 }
 
 impl SceneNode {
 
-    pub fn new() -> Node {
-        ManuallyDrop::new(Pin::new(Box::new(SceneNode {
-            position        : glm::zero(),
-            rotation        : glm::zero(),
-            scale           : glm::vec3(1.0, 1.0, 1.0),
-            reference_point : glm::zero(),
-            vao_id          : 0,
-            index_count     : -1,
-            children        : vec![],
-        })))
+    pub fn new() -> SceneNode {
+        SceneNode::from_vao(0, -1)
     }
 
-    pub fn from_vao(vao_id: u32, index_count: i32) -> Node {
-        ManuallyDrop::new(Pin::new(Box::new(SceneNode {
+    pub fn from_vao(vao_id: u32, index_count: i32) -> SceneNode {
+        SceneNode {
+            name            : String::new(),
+            tags            : vec![],
             position        : glm::zero(),
             rotation        : glm::zero(),
             scale           : glm::vec3(1.0, 1.0, 1.0),
             reference_point : glm::zero(),
             vao_id,
             index_count,
-            children: vec![],
-        })))
+            local_bounds    : None,
+            world_bounds    : Aabb::empty(),
+            lods            : vec![],
+            parent          : None,
+            children        : vec![],
+            local_matrix    : glm::identity(),
+            world_matrix    : glm::identity(),
+            dirty           : true,
+        }
     }
 
-    pub fn add_child(&mut self, child: &SceneNode) {
-        self.children.push(child as *const SceneNode as *mut SceneNode)
+    // Like from_vao, but also knows the size of what it draws
+    pub fn from_mesh(vao_id: u32, mesh: &Mesh) -> SceneNode {
+        let mut node = SceneNode::from_vao(vao_id, mesh.indices.len() as i32);
+        node.local_bounds = Some(mesh.bounds);
+        node
+    }
+
+    #[allow(dead_code)]
+    pub fn with_name(mut self, name: &str) -> SceneNode {
+        self.name = name.to_string();
+        self
     }
 
     #[allow(dead_code)]
-    pub fn get_child(& mut self, index: usize) -> & mut SceneNode {
-        unsafe {
-            &mut (*self.children[index])
+    pub fn with_tag(mut self, tag: &str) -> SceneNode {
+        self.tags.push(tag.to_string());
+        self
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    // Levels should be added from most to least detailed, e.g. from a LodChain
+    pub fn add_lod(&mut self, vao_id: u32, index_count: i32, max_screen_size: f32) {
+        self.lods.push(Lod { vao_id, index_count, max_screen_size });
+    }
+
+    /**
+     * Fraction of the screen height covered by the bounding sphere of what I draw, given my
+     * transform to the world and the camera. Infinite when the camera is inside the sphere.
+     */
+    pub fn screen_size(&self, transform: &glm::Mat4, view_projection: &glm::Mat4) -> f32 {
+        let sphere = match &self.local_bounds {
+            Some(local_bounds) => local_bounds.transformed(transform).bounding_sphere(),
+            None => return 0.,
+        };
+        let center = view_projection * glm::vec4(sphere.center.x, sphere.center.y,
+                                                 sphere.center.z, 1.);
+        if center.w <= sphere.radius {
+            return f32::INFINITY;
         }
+
+        // The vertical focal length, as the view part of the matrix only rotates
+        let focal_length = glm::length(&glm::vec3(
+            view_projection[(1, 0)],
+            view_projection[(1, 1)],
+            view_projection[(1, 2)],
+        ));
+        sphere.radius * focal_length / center.w
+    }
+
+    // The VAO and index count to draw at the given screen size
+    pub fn lod_for(&self, screen_size: f32) -> (u32, i32) {
+        self.lods
+            .iter()
+            .rev()
+            .find(|lod| screen_size < lod.max_screen_size)
+            .map_or((self.vao_id, self.index_count), |lod| (lod.vao_id, lod.index_count))
+    }
+
+    /**
+     * Translation, and rotation and scale about the reference point, relative to my parent.
+     * Scale is applied first, then rotation, then translation.
+     */
+    pub fn local_transform(&self) -> glm::Mat4 {
+        glm::translation(&self.position)
+            * glm::translation(&self.reference_point)
+            * glm::rotation(self.rotation.x, &glm::vec3(1.0, 0.0, 0.0))
+            * glm::rotation(self.rotation.y, &glm::vec3(0.0, 1.0, 0.0))
+            * glm::rotation(self.rotation.z, &glm::vec3(0.0, 0.0, 1.0))
+            * glm::scaling(&self.scale)
+            * glm::translation(&-self.reference_point)
+    }
+
+    #[allow(dead_code)]
+    pub fn parent(&self) -> Option<NodeId> {
+        self.parent
+    }
+
+    pub fn children(&self) -> &[NodeId] {
+        &self.children
     }
 
     #[allow(dead_code)]
@@ -74,6 +173,8 @@ impl SceneNode {
     pub fn print(&self) {
         println!(
 "SceneNode {{
+    Name:      {}
+    Tags:      [{}]
     VAO:       {}
     Indices:   {}
     Children:  {}
@@ -81,6 +182,8 @@ impl SceneNode {
     Rotation:  [{:.2}, {:.2}, {:.2}]
     Reference: [{:.2}, {:.2}, {:.2}]
 }}",
+            self.name,
+            self.tags.join(", "),
             self.vao_id,
             self.index_count,
             self.children.len(),
@@ -99,20 +202,586 @@ impl SceneNode {
 }
 
 
-// You can also use square brackets to access the children of a SceneNode
-use std::ops::{Index, IndexMut};
-impl Index<usize> for SceneNode {
-    type Output = SceneNode;
-    fn index(&self, index: usize) -> &SceneNode {
-        unsafe {
-            & *(self.children[index] as *const SceneNode)
+#[derive(Debug)]
+pub enum SceneGraphError {
+    Removed { node: NodeId },                 // The handle outlived its node
+    Root,                                     // The root can't be moved or removed
+    Cycle { node: NodeId, parent: NodeId },   // The new parent is below the node
+}
+
+impl fmt::Display for SceneGraphError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneGraphError::Removed { node } => {
+                write!(f, "{} has been removed from the scene graph", node)
+            }
+            SceneGraphError::Root => {
+                write!(f, "the root of the scene graph can't be moved or removed")
+            }
+            SceneGraphError::Cycle { node, parent } => {
+                write!(f, "{} can't be moved below {}, which is below it", node, parent)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SceneGraphError {}
+
+struct Slot {
+    generation : u32,
+    node       : Option<SceneNode>,
+}
+
+/**
+ * Owns every node of the scene, which refer to each other by NodeId. Nodes are added below a
+ * parent, starting from the root, and removing a node removes everything below it too.
+ * Indexing with a handle gives the node, e.g. graph[helicopter].rotation.y = 1.0.
+ */
+pub struct SceneGraph {
+    slots : Vec<Slot>,
+    free  : Vec<u32>,   // Slots of removed nodes, ready to be reused
+    root  : NodeId,
+}
+
+impl SceneGraph {
+
+    pub fn new() -> SceneGraph {
+        let root = NodeId { index: 0, generation: 0 };
+        SceneGraph {
+            slots : vec![Slot { generation: 0, node: Some(SceneNode::new()) }],
+            free  : vec![],
+            root,
+        }
+    }
+
+    pub fn root(&self) -> NodeId {
+        self.root
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    pub fn contains(&self, id: NodeId) -> bool {
+        self.get(id).is_some()
+    }
+
+    pub fn get(&self, id: NodeId) -> Option<&SceneNode> {
+        self.slots
+            .get(id.index as usize)
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.node.as_ref())
+    }
+
+    // Marks the node as changed, so its cached transforms are recomputed
+    pub fn get_mut(&mut self, id: NodeId) -> Option<&mut SceneNode> {
+        let node = self.get_mut_untouched(id)?;
+        node.dirty = true;
+        Some(node)
+    }
+
+    fn get_mut_untouched(&mut self, id: NodeId) -> Option<&mut SceneNode> {
+        self.slots
+            .get_mut(id.index as usize)
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.node.as_mut())
+    }
+
+    // Panics if the parent has been removed, like indexing does
+    pub fn add_child(&mut self, parent: NodeId, mut node: SceneNode) -> NodeId {
+        assert!(self.contains(parent), "{}", SceneGraphError::Removed { node: parent });
+        node.parent = Some(parent);
+        node.children.clear();
+        node.dirty = true;
+
+        let id = match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.node = Some(node);
+                NodeId { index, generation: slot.generation }
+            }
+            None => {
+                self.slots.push(Slot { generation: 0, node: Some(node) });
+                NodeId { index: self.slots.len() as u32 - 1, generation: 0 }
+            }
+        };
+        self.get_mut_untouched(parent).unwrap().children.push(id);
+        id
+    }
+
+    // Removes the node and everything below it, returning how many nodes were removed
+    #[allow(dead_code)]
+    pub fn remove(&mut self, id: NodeId) -> Result<usize, SceneGraphError> {
+        if id == self.root {
+            return Err(SceneGraphError::Root);
+        }
+        let parent = self.get(id).ok_or(SceneGraphError::Removed { node: id })?.parent;
+        if let Some(parent) = parent {
+            self.get_mut_untouched(parent).unwrap().children.retain(|&child| child != id);
+        }
+
+        let mut removed = 0;
+        let mut pending = vec![id];
+        while let Some(id) = pending.pop() {
+            let slot = &mut self.slots[id.index as usize];
+            if let Some(node) = slot.node.take() {
+                pending.extend(node.children);
+                slot.generation += 1;
+                self.free.push(id.index);
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    // Moves the node, and everything below it, to the end of the new parent's children
+    #[allow(dead_code)]
+    pub fn reparent(&mut self, id: NodeId, new_parent: NodeId) -> Result<(), SceneGraphError> {
+        if id == self.root {
+            return Err(SceneGraphError::Root);
+        }
+        for node in [id, new_parent] {
+            if !self.contains(node) {
+                return Err(SceneGraphError::Removed { node });
+            }
+        }
+        if self.ancestors(new_parent).any(|ancestor| ancestor == id) {
+            return Err(SceneGraphError::Cycle { node: id, parent: new_parent });
+        }
+
+        if let Some(old_parent) = self[id].parent {
+            self.get_mut_untouched(old_parent).unwrap().children.retain(|&child| child != id);
+        }
+        // Marked as changed, as it now ends up somewhere else in the world
+        self[id].parent = Some(new_parent);
+        self.get_mut_untouched(new_parent).unwrap().children.push(id);
+        Ok(())
+    }
+
+    // The node itself, then its parent, and so on up to the root
+    pub fn ancestors(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        std::iter::successors(self.get(id).map(|_| id), move |&node| self[node].parent)
+    }
+
+    // The node and everything below it, each parent before its children
+    pub fn depth_first(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        let mut pending: Vec<NodeId> = self.get(id).map(|_| id).into_iter().collect();
+        std::iter::from_fn(move || {
+            let next = pending.pop()?;
+            pending.extend(self[next].children.iter().rev());
+            Some(next)
+        })
+    }
+
+    // The first child with the given name
+    pub fn child(&self, parent: NodeId, name: &str) -> Option<NodeId> {
+        self.get(parent)?.children.iter().copied().find(|&child| self[child].name == name)
+    }
+
+    // The node at a path of names from the root, e.g. "terrain/heli_2/main_rotor"
+    #[allow(dead_code)]
+    pub fn find(&self, path: &str) -> Option<NodeId> {
+        self.find_below(self.root, path)
+    }
+
+    // Like find, but with the path starting below the given node
+    pub fn find_below(&self, id: NodeId, path: &str) -> Option<NodeId> {
+        path.split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(id, |node, name| self.child(node, name))
+    }
+
+    // The names from below the root down to the node, joined like the paths find takes
+    pub fn path(&self, id: NodeId) -> String {
+        let mut names: Vec<&str> = self.ancestors(id)
+            .filter(|&node| node != self.root)
+            .map(|node| self[node].name.as_str())
+            .collect();
+        names.reverse();
+        names.join("/")
+    }
+
+    // Every node whose path matches the pattern, parents before their children. Within a name,
+    // * matches any run of characters and ? any single one, and a ** name matches any number of
+    // names, e.g. "terrain/heli_*/main_rotor" or "**/*_rotor".
+    #[allow(dead_code)]
+    pub fn query(&self, pattern: &str) -> Vec<NodeId> {
+        let pattern: Vec<&str> = pattern.split('/').filter(|name| !name.is_empty()).collect();
+        self.depth_first(self.root)
+            .filter(|&node| node != self.root)
+            .filter(|&node| {
+                let mut names: Vec<&str> = self.ancestors(node)
+                    .filter(|&ancestor| ancestor != self.root)
+                    .map(|ancestor| self[ancestor].name.as_str())
+                    .collect();
+                names.reverse();
+                path_matches(&pattern, &names)
+            })
+            .collect()
+    }
+
+    // Every node with the tag, parents before their children
+    pub fn tagged<'a>(&'a self, tag: &'a str) -> impl Iterator<Item = NodeId> + 'a {
+        self.depth_first(self.root).filter(move |&node| self[node].has_tag(tag))
+    }
+
+    // Dumps the whole tree, one node per line, indented below its parent
+    #[allow(dead_code)]
+    pub fn print(&self) {
+        print!("{}", self);
+    }
+
+    // Every node in the graph, in no particular order
+    #[allow(dead_code)]
+    pub fn iter(&self) -> impl Iterator<Item = (NodeId, &SceneNode)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            let id = NodeId { index: index as u32, generation: slot.generation };
+            slot.node.as_ref().map(|node| (id, node))
+        })
+    }
+
+    /**
+     * Recomputes the cached local matrices of the nodes that changed since the last call, and
+     * the world matrices of those and everything below them. Should be called each frame after
+     * animating, before drawing.
+     */
+    pub fn update_transforms(&mut self) {
+        self.update_transforms_below(self.root, &glm::identity(), false);
+    }
+
+    fn update_transforms_below(
+        &mut self,
+        id: NodeId,
+        parent_world: &glm::Mat4,
+        parent_changed: bool,
+    ) {
+        let node = self.get_mut_untouched(id).unwrap();
+        let changed = node.dirty || parent_changed;
+        if node.dirty {
+            node.local_matrix = node.local_transform();
+            node.dirty = false;
         }
+        if changed {
+            node.world_matrix = parent_world * node.local_matrix;
+        }
+
+        let world = node.world_matrix;
+        for i in 0..self[id].children.len() {
+            let child = self[id].children[i];
+            self.update_transforms_below(child, &world, changed);
+        }
+    }
+
+    /**
+     * The transform from the node's coordinates to the world's. Cached by update_transforms,
+     * but always up to date, as the path from the root is recomputed if anything on it changed.
+     */
+    pub fn world_transform(&self, id: NodeId) -> glm::Mat4 {
+        let node = &self[id];
+        if !self.ancestors(id).any(|ancestor| self[ancestor].dirty) {
+            return node.world_matrix;
+        }
+
+        let parent_world = node.parent
+            .map_or(glm::identity(), |parent| self.world_transform(parent));
+        let local = if node.dirty { node.local_transform() } else { node.local_matrix };
+        parent_world * local
+    }
+
+    /**
+     * Recomputes the world bounds of every node, from the world matrices of the last
+     * update_transforms. Should be called after it, before the bounds are used.
+     */
+    pub fn update_world_bounds(&mut self) {
+        self.update_world_bounds_below(self.root);
     }
+
+    fn update_world_bounds_below(&mut self, id: NodeId) -> Aabb {
+        let node = &self[id];
+        let mut bounds = match &node.local_bounds {
+            Some(local_bounds) => local_bounds.transformed(&node.world_matrix),
+            // Something of unknown size is drawn, so it could be anywhere
+            None if node.vao_id != 0 => Aabb::infinite(),
+            None => Aabb::empty(),
+        };
+        for i in 0..self[id].children.len() {
+            let child = self[id].children[i];
+            bounds = bounds.union(&self.update_world_bounds_below(child));
+        }
+
+        self.get_mut_untouched(id).unwrap().world_bounds = bounds;
+        bounds
+    }
+
 }
-impl IndexMut<usize> for SceneNode {
-    fn index_mut(&mut self, index: usize) -> &mut SceneNode {
-        unsafe {
-            &mut (*self.children[index])
+
+
+// Whether the names match the pattern names, where a ** pattern name matches any number of names
+fn path_matches(pattern: &[&str], names: &[&str]) -> bool {
+    match (pattern.first(), names.first()) {
+        (Some(&"**"), _) => {
+            path_matches(&pattern[1..], names)
+                || (!names.is_empty() && path_matches(pattern, &names[1..]))
         }
+        (Some(p), Some(name)) => glob_matches(p, name) && path_matches(&pattern[1..], &names[1..]),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+// Whether the name matches the pattern, where * matches any run of characters and ? any one
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where the last * was, and where in the name it is tried up to, to backtrack to
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // Let the * swallow one more character
+            star = Some((star_p, star_n + 1));
+            p = star_p + 1;
+            n = star_n + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+impl fmt::Display for SceneGraph {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for node in self.depth_first(self.root) {
+            let depth = self.ancestors(node).count() - 1;
+            let scene_node = &self[node];
+            let name = if node == self.root { "(root)" } else { scene_node.name.as_str() };
+            write!(f, "{:indent$}{}", "", name, indent = depth * 2)?;
+            if !scene_node.tags.is_empty() {
+                write!(f, " [{}]", scene_node.tags.join(", "))?;
+            }
+            if scene_node.vao_id != 0 {
+                write!(f, " (VAO {}, {} indices)", scene_node.vao_id, scene_node.index_count)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+
+// Square brackets give the node of a handle, and panic if it has been removed
+impl Index<NodeId> for SceneGraph {
+    type Output = SceneNode;
+    fn index(&self, id: NodeId) -> &SceneNode {
+        self.get(id).unwrap_or_else(|| panic!("{}", SceneGraphError::Removed { node: id }))
+    }
+}
+impl IndexMut<NodeId> for SceneGraph {
+    fn index_mut(&mut self, id: NodeId) -> &mut SceneNode {
+        self.get_mut(id).unwrap_or_else(|| panic!("{}", SceneGraphError::Removed { node: id }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origin_of(transform: &glm::Mat4) -> glm::Vec3 {
+        transform.column(3).xyz()
+    }
+
+    // A parent with two children, the first of which has a child of its own
+    fn family() -> (SceneGraph, [NodeId; 4]) {
+        let mut graph = SceneGraph::new();
+        let parent = graph.add_child(graph.root(), SceneNode::new());
+        let first = graph.add_child(parent, SceneNode::new());
+        let grandchild = graph.add_child(first, SceneNode::new());
+        let second = graph.add_child(parent, SceneNode::new());
+        (graph, [parent, first, grandchild, second])
+    }
+
+    #[test]
+    fn removing_a_node_removes_everything_below_it() {
+        let (mut graph, [parent, first, grandchild, second]) = family();
+        assert_eq!(graph.len(), 5);
+        assert_eq!(graph.remove(first).unwrap(), 2);
+        assert_eq!(graph.len(), 3);
+        assert!(!graph.contains(first) && !graph.contains(grandchild));
+        assert_eq!(graph[parent].children(), [second]);
+        assert_eq!(graph.iter().count(), 3);
+        assert!(matches!(graph.remove(graph.root()), Err(SceneGraphError::Root)));
+    }
+
+    #[test]
+    fn stale_handles_are_rejected_after_their_slot_is_reused() {
+        let (mut graph, [parent, _, _, second]) = family();
+        graph.remove(second).unwrap();
+        assert!(graph.get(second).is_none());
+        assert!(matches!(graph.remove(second), Err(SceneGraphError::Removed { .. })));
+        assert!(matches!(graph.reparent(second, parent), Err(SceneGraphError::Removed { .. })));
+
+        // The slot is taken again, by a handle of a newer generation
+        let reused = graph.add_child(parent, SceneNode::new());
+        assert_eq!(reused.index, second.index);
+        assert_ne!(reused.generation, second.generation);
+        assert!(graph.contains(reused) && !graph.contains(second));
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            graph[second].position.x = 1.;
+        }));
+        assert!(panicked.is_err());
+    }
+
+    #[test]
+    fn reparenting_moves_the_subtree_but_never_below_itself() {
+        let (mut graph, [parent, first, grandchild, second]) = family();
+        assert!(matches!(
+            graph.reparent(parent, grandchild),
+            Err(SceneGraphError::Cycle { .. })
+        ));
+        assert!(matches!(graph.reparent(first, first), Err(SceneGraphError::Cycle { .. })));
+        assert!(matches!(graph.reparent(graph.root(), second), Err(SceneGraphError::Root)));
+
+        graph[second].position = glm::vec3(0., 5., 0.);
+        graph.reparent(first, second).unwrap();
+        assert_eq!(graph[parent].children(), [second]);
+        assert_eq!(graph[second].children(), [first]);
+        assert_eq!(graph[first].parent(), Some(second));
+        let path: Vec<NodeId> = graph.ancestors(grandchild).collect();
+        assert_eq!(path, [grandchild, first, second, parent, graph.root()]);
+
+        // The moved nodes end up where their new parent is
+        graph.update_transforms();
+        assert_eq!(origin_of(&graph[grandchild].world_matrix), glm::vec3(0., 5., 0.));
+    }
+
+    // Two helicopters on the terrain, each with two rotors
+    fn named_scene() -> SceneGraph {
+        let mut graph = SceneGraph::new();
+        let terrain = graph.add_child(graph.root(), SceneNode::from_vao(1, 6).with_name("terrain"));
+        for heli in ["heli_0", "heli_1"] {
+            let node = SceneNode::new().with_name(heli).with_tag("helicopter");
+            let heli = graph.add_child(terrain, node);
+            for rotor in ["main_rotor", "tail_rotor"] {
+                graph.add_child(heli, SceneNode::new().with_name(rotor).with_tag("rotor"));
+            }
+        }
+        graph
+    }
+
+    fn paths(graph: &SceneGraph, nodes: &[NodeId]) -> Vec<String> {
+        nodes.iter().map(|&node| graph.path(node)).collect()
+    }
+
+    #[test]
+    fn nodes_are_found_by_path_and_tag() {
+        let graph = named_scene();
+        let rotor = graph.find("terrain/heli_1/tail_rotor").unwrap();
+        assert_eq!(graph.path(rotor), "terrain/heli_1/tail_rotor");
+        assert_eq!(graph.find("/terrain/heli_1/"), graph.find("terrain/heli_1"));
+        assert!(graph.find("terrain/heli_2").is_none());
+
+        let heli = graph.find("terrain/heli_0").unwrap();
+        assert_eq!(graph.find_below(heli, "main_rotor"), graph.child(heli, "main_rotor"));
+        let tagged: Vec<NodeId> = graph.tagged("helicopter").collect();
+        assert_eq!(paths(&graph, &tagged), ["terrain/heli_0", "terrain/heli_1"]);
+    }
+
+    #[test]
+    fn queries_match_names_with_globs() {
+        let graph = named_scene();
+        let query = |pattern: &str| paths(&graph, &graph.query(pattern));
+
+        assert_eq!(query("terrain/heli_*/main_rotor"),
+                   ["terrain/heli_0/main_rotor", "terrain/heli_1/main_rotor"]);
+        assert_eq!(query("terrain/heli_?/*_rotor").len(), 4);
+        assert_eq!(query("terrain/heli_1/t?il_*"), ["terrain/heli_1/tail_rotor"]);
+        assert_eq!(query("*"), ["terrain"]);
+        assert_eq!(query("terrain/heli_"), Vec::<String>::new());
+        assert_eq!(query("terrain/heli_??"), Vec::<String>::new());
+        assert_eq!(query("terrain/*/*/*"), Vec::<String>::new());
+
+        // ** stands for any number of names, including none
+        assert_eq!(query("**/tail_rotor"),
+                   ["terrain/heli_0/tail_rotor", "terrain/heli_1/tail_rotor"]);
+        assert_eq!(query("**/terrain"), ["terrain"]);
+        assert_eq!(query("terrain/**/heli_0"), ["terrain/heli_0"]);
+        assert_eq!(query("terrain/**").len(), 7);
+        assert_eq!(query("**").len(), 7);
+    }
+
+    #[test]
+    fn printing_shows_the_tree_with_names_and_tags() {
+        let graph = named_scene();
+        let expected = "\
+(root)
+  terrain (VAO 1, 6 indices)
+    heli_0 [helicopter]
+      main_rotor [rotor]
+      tail_rotor [rotor]
+    heli_1 [helicopter]
+      main_rotor [rotor]
+      tail_rotor [rotor]
+";
+        assert_eq!(graph.to_string(), expected);
+    }
+
+    #[test]
+    fn moving_a_parent_moves_its_children() {
+        let mut graph = SceneGraph::new();
+        let mut parent = SceneNode::new();
+        parent.position = glm::vec3(1., 0., 0.);
+        let parent = graph.add_child(graph.root(), parent);
+        let mut child = SceneNode::new();
+        child.position = glm::vec3(0., 2., 0.);
+        let child = graph.add_child(parent, child);
+        graph.update_transforms();
+        assert_eq!(origin_of(&graph[child].world_matrix), glm::vec3(1., 2., 0.));
+
+        // Turning the parent about its reference point and scaling it carries the child along
+        graph[parent].position = glm::vec3(5., 0., 0.);
+        graph[parent].reference_point = glm::vec3(0., 1., 0.);
+        graph[parent].rotation = glm::vec3(0., 0., std::f32::consts::FRAC_PI_2);
+        graph[parent].scale = glm::vec3(3., 3., 3.);
+        let expected = glm::vec3(2., 1., 0.);
+        // Up to date before the caches are, and the same once they are
+        assert!(glm::distance(&origin_of(&graph.world_transform(child)), &expected) < 1e-5);
+        graph.update_transforms();
+        assert!(glm::distance(&origin_of(&graph[child].world_matrix), &expected) < 1e-5);
+    }
+
+    #[test]
+    fn nodes_that_have_not_changed_are_not_recomputed() {
+        let mut graph = SceneGraph::new();
+        let parent = graph.add_child(graph.root(), SceneNode::new());
+        let child = graph.add_child(parent, SceneNode::new());
+        graph.update_transforms();
+
+        // Matrices no transform gives, which only stay if the caches are left alone
+        let stale: glm::Mat4 = glm::scaling(&glm::vec3(7., 7., 7.));
+        for node in [parent, child] {
+            let node = graph.get_mut_untouched(node).unwrap();
+            node.local_matrix = stale;
+            node.world_matrix = stale;
+        }
+        graph.update_transforms();
+        assert_eq!(graph[parent].world_matrix, stale);
+        assert_eq!(graph[child].world_matrix, stale);
+        assert_eq!(graph.world_transform(child), stale);
+
+        // Changing the parent recomputes its matrices, and the child's world matrix from its
+        // cached local one
+        graph[parent].position = glm::vec3(1., 0., 0.);
+        graph.update_transforms();
+        let moved: glm::Mat4 = glm::translation(&glm::vec3(1., 0., 0.));
+        assert_eq!(graph[parent].world_matrix, moved);
+        assert_eq!(graph[child].local_matrix, stale);
+        assert_eq!(graph[child].world_matrix, moved * stale);
     }
 }
@@ -0,0 +1,448 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use crate::mesh::Mesh;
+
+// Sum of squared distances to a set of planes, as a symmetric 4x4 matrix (Garland & Heckbert)
+#[derive(Clone, Copy)]
+struct Quadric(glm::DMat4);
+
+impl Quadric {
+    fn zero() -> Quadric {
+        Quadric(glm::DMat4::zeros())
+    }
+
+    fn from_plane(normal: &glm::DVec3, point: &glm::DVec3) -> Quadric {
+        let plane = glm::vec4(normal.x, normal.y, normal.z, -glm::dot(normal, point));
+        Quadric(plane * plane.transpose())
+    }
+
+    fn add(&self, other: &Quadric) -> Quadric {
+        Quadric(self.0 + other.0)
+    }
+
+    fn error(&self, p: &glm::DVec3) -> f64 {
+        let v = glm::vec4(p.x, p.y, p.z, 1.);
+        glm::dot(&v, &(self.0 * v)).max(0.)
+    }
+
+    // The point with the least error, if the planes pin one down
+    fn optimal(&self) -> Option<glm::DVec3> {
+        let a = glm::mat4_to_mat3(&self.0);
+        let b = glm::vec3(self.0[(0, 3)], self.0[(1, 3)], self.0[(2, 3)]);
+        if a.determinant().abs() < 1e-12 {
+            return None;
+        }
+        a.try_inverse().map(|inverse| -(inverse * b))
+    }
+}
+
+// A candidate edge collapse, ordered so the cheapest comes out of the heap first
+struct Collapse {
+    cost: f64,
+    keep: usize,
+    remove: usize,
+    position: glm::DVec3,
+    stamps: (u32, u32), // Of keep and remove when computed, to spot outdated candidates
+}
+
+impl PartialEq for Collapse {
+    fn eq(&self, other: &Self) -> bool {
+        self.cost == other.cost
+    }
+}
+impl Eq for Collapse {}
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Collapse {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .cost
+            .partial_cmp(&self.cost)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+// Connectivity of the mesh by position, so vertices split along seams collapse together
+struct Simplifier {
+    positions: Vec<glm::DVec3>,
+    quadrics: Vec<Quadric>,
+    locked: Vec<bool>, // On a boundary, so neither moved nor removed
+    stamps: Vec<u32>,  // Bumped every time a position changes
+    collapsed_into: Vec<usize>,
+    triangles: Vec<[usize; 3]>,
+    alive: Vec<bool>,
+    incident: Vec<Vec<usize>>, // Triangles around each position, possibly including dead ones
+}
+
+impl Simplifier {
+    fn new(mesh: &Mesh) -> (Simplifier, Vec<usize>) {
+        let mut ids: HashMap<[u32; 3], usize> = HashMap::new();
+        let mut positions = vec![];
+        let position_of: Vec<usize> = (0..mesh.vertex_count() as u32)
+            .map(|i| {
+                let p = mesh.position(i);
+                *ids.entry([p.x.to_bits(), p.y.to_bits(), p.z.to_bits()])
+                    .or_insert_with(|| {
+                        positions.push(glm::vec3(p.x as f64, p.y as f64, p.z as f64));
+                        positions.len() - 1
+                    })
+            })
+            .collect();
+
+        let n = positions.len();
+        let triangles: Vec<[usize; 3]> = mesh
+            .indices
+            .chunks_exact(3)
+            .map(|t| {
+                [
+                    position_of[t[0] as usize],
+                    position_of[t[1] as usize],
+                    position_of[t[2] as usize],
+                ]
+            })
+            .collect();
+
+        let mut simplifier = Simplifier {
+            positions,
+            quadrics: vec![Quadric::zero(); n],
+            locked: vec![false; n],
+            stamps: vec![0; n],
+            collapsed_into: (0..n).collect(),
+            alive: vec![true; triangles.len()],
+            incident: vec![vec![]; n],
+            triangles,
+        };
+
+        // Edges used by a single triangle are boundaries, and those used by more are not
+        // manifold. Either way, the vertices on them are left alone.
+        let mut edge_use: HashMap<(usize, usize), usize> = HashMap::new();
+        for (i, t) in simplifier.triangles.iter().enumerate() {
+            if t[0] == t[1] || t[1] == t[2] || t[0] == t[2] {
+                simplifier.alive[i] = false;
+                continue;
+            }
+            for k in 0..3 {
+                let (a, b) = (t[k], t[(k + 1) % 3]);
+                *edge_use.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+                simplifier.incident[a].push(i);
+            }
+
+            let normal = simplifier.normal(t);
+            let quadric = Quadric::from_plane(&normal, &simplifier.positions[t[0]]);
+            for &v in t {
+                simplifier.quadrics[v] = simplifier.quadrics[v].add(&quadric);
+            }
+        }
+        for (&(a, b), &count) in &edge_use {
+            if count != 2 {
+                simplifier.locked[a] = true;
+                simplifier.locked[b] = true;
+            }
+        }
+
+        (simplifier, position_of)
+    }
+
+    // Unit normal, or zero for triangles without area
+    fn normal(&self, t: &[usize; 3]) -> glm::DVec3 {
+        let [a, b, c] = [
+            self.positions[t[0]],
+            self.positions[t[1]],
+            self.positions[t[2]],
+        ];
+        let cross = glm::cross(&(b - a), &(c - a));
+        let length = glm::length(&cross);
+        if length > 0. {
+            cross / length
+        } else {
+            cross
+        }
+    }
+
+    fn neighbours(&self, v: usize) -> HashSet<usize> {
+        self.incident[v]
+            .iter()
+            .filter(|&&t| self.alive[t])
+            .flat_map(|&t| self.triangles[t].iter().cloned())
+            .filter(|&other| other != v)
+            .collect()
+    }
+
+    fn candidate(&self, a: usize, b: usize) -> Option<Collapse> {
+        if self.locked[a] && self.locked[b] {
+            return None;
+        }
+        // A locked vertex stays where it is, with the other collapsing onto it
+        let (keep, remove) = if self.locked[b] { (b, a) } else { (a, b) };
+        let quadric = self.quadrics[keep].add(&self.quadrics[remove]);
+        let (p, q) = (self.positions[keep], self.positions[remove]);
+
+        let position = if self.locked[keep] {
+            p
+        } else {
+            match quadric.optimal() {
+                Some(optimal) => optimal,
+                None => *[p, q, (p + q) * 0.5]
+                    .iter()
+                    .min_by(|x, y| {
+                        quadric
+                            .error(x)
+                            .partial_cmp(&quadric.error(y))
+                            .unwrap_or(Ordering::Equal)
+                    })
+                    .unwrap(),
+            }
+        };
+
+        Some(Collapse {
+            cost: quadric.error(&position),
+            keep,
+            remove,
+            position,
+            stamps: (self.stamps[keep], self.stamps[remove]),
+        })
+    }
+
+    /**
+     * Whether the collapse keeps the surface manifold, and does not flip any triangle around
+     * the two vertices.
+     */
+    fn is_valid(&self, collapse: &Collapse) -> bool {
+        let (keep, remove) = (collapse.keep, collapse.remove);
+        let shared_triangles = self.incident[keep]
+            .iter()
+            .filter(|&&t| self.alive[t] && self.triangles[t].contains(&remove))
+            .count();
+        let shared_neighbours = self
+            .neighbours(keep)
+            .intersection(&self.neighbours(remove))
+            .count();
+        if shared_neighbours != shared_triangles {
+            return false;
+        }
+
+        self.incident[keep]
+            .iter()
+            .chain(&self.incident[remove])
+            .filter(|&&t| self.alive[t])
+            .filter(|&&t| {
+                !(self.triangles[t].contains(&keep) && self.triangles[t].contains(&remove))
+            })
+            .all(|&t| {
+                let old_normal = self.normal(&self.triangles[t]);
+                let corners = self.triangles[t].map(|v| {
+                    if v == keep || v == remove {
+                        collapse.position
+                    } else {
+                        self.positions[v]
+                    }
+                });
+                let new_normal = glm::cross(&(corners[1] - corners[0]), &(corners[2] - corners[0]));
+                glm::dot(&old_normal, &new_normal) > 0.
+            })
+    }
+
+    fn apply(&mut self, collapse: &Collapse) -> usize {
+        let (keep, remove) = (collapse.keep, collapse.remove);
+        self.positions[keep] = collapse.position;
+        self.quadrics[keep] = self.quadrics[keep].add(&self.quadrics[remove]);
+        self.collapsed_into[remove] = keep;
+        self.stamps[keep] += 1;
+        self.stamps[remove] += 1;
+
+        let mut removed_triangles = 0;
+        for t in std::mem::take(&mut self.incident[remove]) {
+            if !self.alive[t] {
+                continue;
+            }
+            if self.triangles[t].contains(&keep) {
+                self.alive[t] = false;
+                removed_triangles += 1;
+            } else {
+                for v in self.triangles[t].iter_mut() {
+                    if *v == remove {
+                        *v = keep;
+                    }
+                }
+                self.incident[keep].push(t);
+            }
+        }
+        let alive = &self.alive;
+        self.incident[keep].retain(|&t| alive[t]);
+
+        removed_triangles
+    }
+
+    fn find(&self, mut v: usize) -> usize {
+        while self.collapsed_into[v] != v {
+            v = self.collapsed_into[v];
+        }
+        v
+    }
+}
+
+impl Mesh {
+    /**
+     * Reduces the mesh by collapsing edges in order of quadric error, until it has at most
+     * target_triangles triangles or the next collapse would move the surface by more than
+     * max_error. A max_error of f32::INFINITY disables the error limit, and a target of 0
+     * leaves only the error limit. Boundaries and non-manifold edges are kept as they are.
+     * Vertices keep their attributes when moved, which holds up as long as the error is small.
+     */
+    pub fn simplify(&self, target_triangles: usize, max_error: f32) -> Mesh {
+        let (mut simplifier, position_of) = Simplifier::new(self);
+        let max_cost = (max_error as f64) * (max_error as f64);
+        let mut triangle_count = simplifier.alive.iter().filter(|&&alive| alive).count();
+
+        let mut heap = BinaryHeap::new();
+        for v in 0..simplifier.positions.len() {
+            for n in simplifier.neighbours(v) {
+                if v < n {
+                    heap.extend(simplifier.candidate(v, n));
+                }
+            }
+        }
+
+        while triangle_count > target_triangles {
+            let collapse = match heap.pop() {
+                Some(collapse) => collapse,
+                None => break,
+            };
+            let (keep, remove) = (collapse.keep, collapse.remove);
+            if collapse.stamps != (simplifier.stamps[keep], simplifier.stamps[remove])
+                || simplifier.collapsed_into[keep] != keep
+                || simplifier.collapsed_into[remove] != remove
+            {
+                continue;
+            }
+            if collapse.cost > max_cost {
+                break;
+            }
+            if !simplifier.is_valid(&collapse) {
+                continue;
+            }
+
+            triangle_count -= simplifier.apply(&collapse);
+            for n in simplifier.neighbours(keep) {
+                heap.extend(simplifier.candidate(keep, n));
+            }
+        }
+
+        // Keep the surviving triangles with their original vertices, moved to where their
+        // positions ended up
+        let mut simplified = self.clone();
+        let mut remap: HashMap<u32, u32> = HashMap::new();
+        let mut sources = vec![];
+        simplified.indices = self
+            .indices
+            .chunks_exact(3)
+            .enumerate()
+            .filter(|&(t, _)| simplifier.alive[t])
+            .flat_map(|(_, t)| t.iter().cloned())
+            .map(|i| {
+                *remap.entry(i).or_insert_with(|| {
+                    sources.push(i);
+                    (sources.len() - 1) as u32
+                })
+            })
+            .collect();
+        simplified.rebuild_vertices(&sources);
+        for (i, &source) in sources.iter().enumerate() {
+            let p = simplifier.positions[simplifier.find(position_of[source as usize])];
+            simplified.vertices[i * 3..i * 3 + 3]
+                .copy_from_slice(&[p.x as f32, p.y as f32, p.z as f32]);
+        }
+
+        simplified.index_count = simplified.indices.len() as i32;
+        simplified.update_bounds();
+        simplified
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An n by n grid of unit quads in the xy-plane, lifted to the given height
+    fn grid(n: u32, height: impl Fn(f32, f32) -> f32) -> Mesh {
+        let mut vertices = vec![];
+        for y in 0..=n {
+            for x in 0..=n {
+                let (x, y) = (x as f32, y as f32);
+                vertices.extend(&[x, y, height(x, y)]);
+            }
+        }
+        let mut indices = vec![];
+        for y in 0..n {
+            for x in 0..n {
+                let i = y * (n + 1) + x;
+                indices.extend(&[i, i + 1, i + n + 2, i, i + n + 2, i + n + 1]);
+            }
+        }
+        Mesh::from_shape((vertices, indices), [1., 1., 1., 1.])
+    }
+
+    fn positions(mesh: &Mesh) -> Vec<glm::Vec3> {
+        (0..mesh.vertex_count() as u32)
+            .map(|i| mesh.position(i))
+            .collect()
+    }
+
+    fn area(mesh: &Mesh) -> f32 {
+        mesh.indices
+            .chunks(3)
+            .map(|t| glm::length(&mesh.triangle_cross(t)) / 2.)
+            .sum()
+    }
+
+    #[test]
+    fn simplification_meets_the_triangle_target() {
+        let mesh = grid(8, |_, _| 0.);
+        // 32 locked boundary vertices need at least 30 triangles between them
+        let simplified = mesh.simplify(40, f32::INFINITY);
+
+        assert!(simplified.indices.len() / 3 <= 40);
+        assert_eq!(simplified.index_count as usize, simplified.indices.len());
+        assert!((area(&simplified) - 64.).abs() < 1e-3);
+        assert!(simplified
+            .indices
+            .chunks(3)
+            .all(|t| simplified.triangle_normal(t).z > 0.));
+    }
+
+    #[test]
+    fn boundaries_stay_in_place() {
+        let mesh = grid(8, |_, _| 0.);
+        let simplified = mesh.simplify(0, f32::INFINITY);
+        let on_boundary = |p: &glm::Vec3| p.x == 0. || p.y == 0. || p.x == 8. || p.y == 8.;
+
+        // Every boundary vertex is still there, and nothing has moved onto or off the boundary
+        let before: Vec<glm::Vec3> = positions(&mesh).into_iter().filter(on_boundary).collect();
+        let after = positions(&simplified);
+        assert_eq!(before.len(), 32);
+        for p in &before {
+            assert!(after.contains(p), "{:?} was removed", p);
+        }
+        assert!(after.iter().all(|p| on_boundary(p) || !before.contains(p)));
+        assert!((area(&simplified) - 64.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn the_error_limit_keeps_curved_surfaces() {
+        // Every collapse on a paraboloid moves the surface a little
+        let mesh = grid(6, |x, y| (x - 3.) * (x - 3.) + (y - 3.) * (y - 3.));
+        let kept = mesh.simplify(0, 1e-4);
+        assert_eq!(kept.indices.len(), mesh.indices.len());
+
+        // While a flat one can lose every interior vertex at no cost
+        let flat = grid(6, |_, _| 1.).simplify(0, 1e-4);
+        assert!(flat.indices.len() < mesh.indices.len());
+        assert!(positions(&flat)
+            .iter()
+            .all(|p| p.x == 0. || p.y == 0. || p.x == 6. || p.y == 6.));
+    }
+}
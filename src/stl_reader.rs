@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use crate::mesh::Mesh;
+use crate::normals::NormalWeighting;
+use crate::obj_reader::{triangulate, ObjLocation};
+
+// Edges sharper than this stay sharp when generating normals, which suits most CAD models
+const CREASE_ANGLE: f32 = 30. * std::f32::consts::PI / 180.;
+
+// Reads .stl files, as exported by CAD tools, in ASCII or binary
+pub struct StlReader {}
+
+#[derive(Debug)]
+pub enum StlError {
+    Extension {
+        path: String,
+    },
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    Syntax {
+        location: ObjLocation,
+        token: String,
+        expected: &'static str,
+    },
+    Truncated {
+        path: String,
+        triangles: u32,
+    },
+}
+
+impl fmt::Display for StlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StlError::Extension { path } => {
+                write!(f, "{}: expected a file with the .stl extension", path)
+            }
+            StlError::Io { path, source } => write!(f, "{}: failed to read file: {}", path, source),
+            StlError::Syntax {
+                location,
+                token,
+                expected,
+            } if token.is_empty() => {
+                write!(f, "{}: expected {}, found end of line", location, expected)
+            }
+            StlError::Syntax {
+                location,
+                token,
+                expected,
+            } => write!(f, "{}: expected {}, found `{}`", location, expected, token),
+            StlError::Truncated { path, triangles } => write!(
+                f,
+                "{}: file is too short for the {} triangles it declares",
+                path, triangles
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StlError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StlError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl StlReader {
+    pub fn read(stl_path: &str) -> Result<Mesh, StlError> {
+        let path = Path::new(stl_path);
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(e) if e.eq_ignore_ascii_case("stl") => (),
+            _ => {
+                return Err(StlError::Extension {
+                    path: stl_path.to_string(),
+                })
+            }
+        }
+
+        let stl_bytes = std::fs::read(path).map_err(|source| StlError::Io {
+            path: stl_path.to_string(),
+            source,
+        })?;
+        StlReader::parse_named_bytes(stl_path, &stl_bytes)
+    }
+
+    #[cfg(test)]
+    pub fn parse_stl_bytes(stl_bytes: &[u8]) -> Result<Mesh, StlError> {
+        StlReader::parse_named_bytes("<source>", stl_bytes)
+    }
+
+    /**
+     * STL is a soup of separate triangles, so corners with identical positions are welded
+     * before generating normals. The normals stored in the file are ignored, as exporters often
+     * leave them out or get them wrong, and the winding decides which way triangles face.
+     */
+    fn parse_named_bytes(path: &str, stl_bytes: &[u8]) -> Result<Mesh, StlError> {
+        // Binary files may also start with "solid", so their size is what tells them apart
+        let declared = stl_bytes
+            .get(80..84)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        let is_binary = match declared {
+            Some(n) => 84 + n as usize * 50 == stl_bytes.len() || !stl_bytes.starts_with(b"solid"),
+            None => false,
+        };
+
+        let triangles = if is_binary {
+            StlReader::parse_binary(path, stl_bytes)?
+        } else {
+            StlReader::parse_ascii(path, &String::from_utf8_lossy(stl_bytes))?
+        };
+
+        let mut ids: HashMap<[u32; 3], u32> = HashMap::new();
+        let mut vertices = vec![];
+        let indices: Vec<u32> = triangles
+            .iter()
+            .flatten()
+            .map(|p| {
+                *ids.entry([p[0].to_bits(), p[1].to_bits(), p[2].to_bits()])
+                    .or_insert_with(|| {
+                        vertices.extend(p);
+                        (vertices.len() / 3 - 1) as u32
+                    })
+            })
+            .collect();
+
+        let mut mesh = Mesh::from_shape((vertices, indices), [1.0, 1.0, 1.0, 1.0]);
+        mesh.compute_creased_normals(CREASE_ANGLE, NormalWeighting::Angle);
+        Ok(mesh)
+    }
+
+    // 80 byte header, triangle count, then 50 bytes per triangle: normal, corners, attributes
+    fn parse_binary(path: &str, stl_bytes: &[u8]) -> Result<Vec<[[f32; 3]; 3]>, StlError> {
+        let declared =
+            u32::from_le_bytes([stl_bytes[80], stl_bytes[81], stl_bytes[82], stl_bytes[83]]);
+        let data = &stl_bytes[84..];
+        if data.len() < declared as usize * 50 {
+            return Err(StlError::Truncated {
+                path: path.to_string(),
+                triangles: declared,
+            });
+        }
+
+        let float = |b: &[u8], i: usize| f32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+        Ok(data
+            .chunks_exact(50)
+            .take(declared as usize)
+            .map(|t| {
+                let corner = |k: usize| {
+                    let at = 12 + k * 12;
+                    [float(t, at), float(t, at + 4), float(t, at + 8)]
+                };
+                [corner(0), corner(1), corner(2)]
+            })
+            .collect())
+    }
+
+    // Only the vertices of each loop matter. Loops with more than three are triangulated.
+    fn parse_ascii(path: &str, stl_src: &str) -> Result<Vec<[[f32; 3]; 3]>, StlError> {
+        let mut triangles = vec![];
+        let mut polygon: Vec<[f32; 3]> = vec![];
+        let mut last = (0, "");
+
+        for (i, line) in stl_src.lines().enumerate() {
+            let line_number = i + 1;
+            last = (line_number, line);
+            let syntax_error = |token: &str, expected| StlError::Syntax {
+                location: ObjLocation::of(path, line_number, line, token),
+                token: token.to_string(),
+                expected,
+            };
+
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("vertex") => {
+                    let mut coordinate = || {
+                        let token = tokens.next().unwrap_or(&line[line.len()..]);
+                        token
+                            .parse()
+                            .map_err(|_| syntax_error(token, "a coordinate"))
+                    };
+                    polygon.push([coordinate()?, coordinate()?, coordinate()?]);
+                }
+                Some("endloop") => {
+                    // Loops too short to make a triangle are dropped
+                    if polygon.len() >= 3 {
+                        for t in triangulate(&polygon) {
+                            triangles.push([polygon[t[0]], polygon[t[1]], polygon[t[2]]]);
+                        }
+                    }
+                    polygon.clear();
+                }
+                Some("outer") => polygon.clear(),
+                Some("solid") | Some("facet") | Some("endfacet") | Some("endsolid") | None => (),
+                Some(token) => return Err(syntax_error(token, "an STL keyword")),
+            }
+        }
+
+        if !polygon.is_empty() {
+            let (line_number, line) = last;
+            return Err(StlError::Syntax {
+                location: ObjLocation::of(path, line_number, line, &line[line.len()..]),
+                token: String::new(),
+                expected: "endloop",
+            });
+        }
+
+        Ok(triangles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The 12 triangles of a unit cube, wound to face outwards
+    fn cube() -> Vec<[[f32; 3]; 3]> {
+        let corner = |i: usize| [(i & 1) as f32, ((i >> 1) & 1) as f32, ((i >> 2) & 1) as f32];
+        #[rustfmt::skip]
+        let faces = [
+            [0, 2, 3, 1], [4, 5, 7, 6],
+            [0, 4, 6, 2], [1, 3, 7, 5],
+            [0, 1, 5, 4], [2, 6, 7, 3],
+        ];
+        faces
+            .iter()
+            .flat_map(|&[a, b, c, d]| vec![[a, b, c], [a, c, d]])
+            .map(|t| [corner(t[0]), corner(t[1]), corner(t[2])])
+            .collect()
+    }
+
+    fn to_binary(header: &[u8], triangles: &[[[f32; 3]; 3]]) -> Vec<u8> {
+        let mut bytes = header.to_vec();
+        bytes.resize(80, 0);
+        bytes.extend(&(triangles.len() as u32).to_le_bytes());
+        for t in triangles {
+            // The stored normal is ignored, so it is left at zero
+            bytes.extend(&[0; 12]);
+            for c in t.iter().flatten() {
+                bytes.extend(&c.to_le_bytes());
+            }
+            bytes.extend(&[0; 2]);
+        }
+        bytes
+    }
+
+    fn to_ascii(triangles: &[[[f32; 3]; 3]]) -> String {
+        let mut src = "solid cube\n".to_string();
+        for t in triangles {
+            src += "  facet normal 0 0 0\n    outer loop\n";
+            for c in t {
+                src += &format!("      vertex {} {} {}\n", c[0], c[1], c[2]);
+            }
+            src += "    endloop\n  endfacet\n";
+        }
+        src + "endsolid cube\n"
+    }
+
+    fn corners(mesh: &Mesh) -> Vec<glm::Vec3> {
+        mesh.indices.iter().map(|&i| mesh.position(i)).collect()
+    }
+
+    #[test]
+    fn binary_and_ascii_files_give_the_same_mesh() {
+        let cube = cube();
+        let binary = StlReader::parse_stl_bytes(&to_binary(b"binary cube", &cube)).unwrap();
+        let ascii = StlReader::parse_stl_bytes(to_ascii(&cube).as_bytes()).unwrap();
+
+        // Every triangle survives in order, and the 30 degree crease keeps the cube's edges
+        let expected: Vec<glm::Vec3> = cube.iter().flatten().map(|c| glm::make_vec3(c)).collect();
+        for mesh in &[&binary, &ascii] {
+            assert_eq!(corners(mesh), expected);
+            assert_eq!(mesh.vertex_count(), 24);
+            assert_eq!(mesh.normals.len(), mesh.vertices.len());
+        }
+        assert_eq!(binary.vertices, ascii.vertices);
+        assert_eq!(binary.indices, ascii.indices);
+        assert_eq!(binary.normals, ascii.normals);
+    }
+
+    #[test]
+    fn binary_files_may_start_with_solid() {
+        let cube = cube();
+        let mesh = StlReader::parse_stl_bytes(&to_binary(b"solid, but binary", &cube)).unwrap();
+        assert_eq!(mesh.indices.len(), 36);
+
+        // Without the size matching the count, it is taken as ASCII and fails
+        let mut bytes = to_binary(b"solid, but binary", &cube);
+        bytes.pop();
+        assert!(StlReader::parse_stl_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn truncated_binary_files_are_rejected() {
+        let mut bytes = to_binary(b"", &cube());
+        bytes.truncate(84 + 11 * 50);
+        match StlReader::parse_stl_bytes(&bytes) {
+            Err(StlError::Truncated { triangles, .. }) => assert_eq!(triangles, 12),
+            other => panic!("expected a truncated file, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn ascii_errors_point_at_the_token() {
+        let src = "solid\nfacet normal 0 0 1\nouter loop\nvertex 0 0 0\nvertex 1 x 0\n";
+        let error = StlReader::parse_stl_bytes(src.as_bytes()).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "<source>:5:10: expected a coordinate, found `x`"
+        );
+
+        let unfinished = "solid\nouter loop\nvertex 0 0 0\n";
+        let error = StlReader::parse_stl_bytes(unfinished.as_bytes())
+            .err()
+            .unwrap();
+        let expected = "<source>:3:13: expected endloop, found end of line";
+        assert_eq!(error.to_string(), expected);
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+
+use crate::mesh::Mesh;
+
+impl Mesh {
+    /**
+     * Generates a tangent per vertex for normal mapping, following the conventions of
+     * MikkTSpace: tangents are orthogonal to the normal, W holds the handedness so the
+     * bitangent is cross(normal, tangent) * W, and each triangle contributes weighted by its
+     * angle at the vertex. Vertices shared by triangles with mirrored UVs are split, so each
+     * side gets a consistent handedness.
+     */
+    pub fn compute_tangents(&mut self) -> Result<(), String> {
+        let n_vertices = self.vertex_count();
+        if self.texcoords.len() != n_vertices * 2 {
+            return Err("Tangents need texture coordinates for every vertex".to_string());
+        }
+        self.ensure_normals();
+
+        // Tangent, bitangent and angle weight of every corner
+        let mut corners = Vec::with_capacity(self.indices.len());
+        for t in self.indices.chunks(3) {
+            let (p0, p1, p2) = (
+                self.position(t[0]),
+                self.position(t[1]),
+                self.position(t[2]),
+            );
+            let (uv0, uv1, uv2) = (
+                self.texcoord(t[0]),
+                self.texcoord(t[1]),
+                self.texcoord(t[2]),
+            );
+
+            let (e1, e2) = (p1 - p0, p2 - p0);
+            let (d1, d2) = (uv1 - uv0, uv2 - uv0);
+            let det = d1.x * d2.y - d2.x * d1.y;
+
+            // Triangles without a proper UV mapping do not contribute
+            let (tangent, bitangent) = if det.abs() > 1e-12 {
+                ((e1 * d2.y - e2 * d1.y) / det, (e2 * d1.x - e1 * d2.x) / det)
+            } else {
+                (glm::zero(), glm::zero())
+            };
+
+            for k in 0..3 {
+                let p = self.position(t[k]);
+                let a = self.position(t[(k + 1) % 3]) - p;
+                let b = self.position(t[(k + 2) % 3]) - p;
+                let weight = if glm::length(&a) > 0. && glm::length(&b) > 0. {
+                    glm::angle(&a, &b)
+                } else {
+                    0.
+                };
+                corners.push((tangent, bitangent, weight));
+            }
+        }
+
+        // Split vertices where the triangles around them disagree on handedness
+        let mut sources = vec![];
+        let mut split: HashMap<(u32, bool), u32> = HashMap::new();
+        let mut handedness = vec![];
+        for (corner, index) in self.indices.iter_mut().enumerate() {
+            let (tangent, bitangent, _) = corners[corner];
+            let n = {
+                let i = *index as usize * 3;
+                glm::vec3(self.normals[i], self.normals[i + 1], self.normals[i + 2])
+            };
+            let right_handed = glm::dot(&glm::cross(&n, &tangent), &bitangent) >= 0.;
+            *index = *split.entry((*index, right_handed)).or_insert_with(|| {
+                sources.push(*index);
+                handedness.push(right_handed);
+                (sources.len() - 1) as u32
+            });
+        }
+        self.rebuild_vertices(&sources);
+
+        // Accumulate, then Gram-Schmidt orthogonalize against the normal
+        let mut sums = vec![glm::zero::<glm::Vec3>(); sources.len()];
+        for (corner, &index) in self.indices.iter().enumerate() {
+            let (tangent, _, weight) = corners[corner];
+            sums[index as usize] += tangent * weight;
+        }
+
+        self.tangents = Vec::with_capacity(sources.len() * 4);
+        for (i, sum) in sums.iter().enumerate() {
+            let n = glm::vec3(
+                self.normals[i * 3],
+                self.normals[i * 3 + 1],
+                self.normals[i * 3 + 2],
+            );
+            let mut tangent = sum - n * glm::dot(&n, sum);
+            if glm::length(&tangent) > 0. {
+                tangent = glm::normalize(&tangent);
+            } else {
+                // Any vector orthogonal to the normal will do for vertices without UV mapping
+                let axis = if n.x.abs() < 0.9 {
+                    glm::vec3(1., 0., 0.)
+                } else {
+                    glm::vec3(0., 1., 0.)
+                };
+                tangent = glm::normalize(&glm::cross(&n, &axis));
+            }
+            let w = if handedness[i] { 1. } else { -1. };
+            self.tangents.extend(&[tangent.x, tangent.y, tangent.z, w]);
+        }
+
+        Ok(())
+    }
+
+    pub fn texcoord(&self, i: u32) -> glm::Vec2 {
+        let i = i as usize * 2;
+        glm::vec2(self.texcoords[i], self.texcoords[i + 1])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A unit quad in the xy-plane facing +z, with the given UV at each corner
+    fn quad(texcoords: Vec<f32>) -> Mesh {
+        let vertices = vec![0., 0., 0., 1., 0., 0., 1., 1., 0., 0., 1., 0.];
+        let mut mesh = Mesh::from_shape((vertices, vec![0, 1, 2, 0, 2, 3]), [1., 1., 1., 1.]);
+        mesh.texcoords = texcoords;
+        mesh
+    }
+
+    fn tangent(mesh: &Mesh, i: usize) -> (glm::Vec3, f32) {
+        let t = &mesh.tangents[i * 4..i * 4 + 4];
+        (glm::vec3(t[0], t[1], t[2]), t[3])
+    }
+
+    fn normal(mesh: &Mesh, i: usize) -> glm::Vec3 {
+        glm::make_vec3(&mesh.normals[i * 3..i * 3 + 3])
+    }
+
+    #[test]
+    fn tangents_follow_u_and_are_right_handed() {
+        let mut mesh = quad(vec![0., 0., 1., 0., 1., 1., 0., 1.]);
+        mesh.compute_tangents().unwrap();
+
+        assert_eq!(mesh.vertex_count(), 4);
+        for i in 0..4 {
+            let (t, w) = tangent(&mesh, i);
+            assert!(glm::distance(&t, &glm::vec3(1., 0., 0.)) < 1e-5, "{:?}", t);
+            assert!(glm::dot(&t, &normal(&mesh, i)).abs() < 1e-5);
+            assert_eq!(w, 1.);
+        }
+    }
+
+    #[test]
+    fn mirrored_uvs_flip_the_handedness() {
+        // u runs along -x, so cross(normal, tangent) points away from v and W has to make up
+        // for it
+        let mut mesh = quad(vec![1., 0., 0., 0., 0., 1., 1., 1.]);
+        mesh.compute_tangents().unwrap();
+
+        for i in 0..4 {
+            let (t, w) = tangent(&mesh, i);
+            assert!(glm::distance(&t, &glm::vec3(-1., 0., 0.)) < 1e-5, "{:?}", t);
+            assert_eq!(w, -1.);
+
+            // The bitangent still points along v
+            let bitangent = glm::cross(&normal(&mesh, i), &t) * w;
+            assert!(glm::distance(&bitangent, &glm::vec3(0., 1., 0.)) < 1e-5);
+        }
+    }
+
+    #[test]
+    fn tangents_are_orthogonal_to_tilted_normals() {
+        let mut mesh = quad(vec![0., 0., 1., 0., 1., 1., 0., 1.]);
+        let tilted = glm::normalize(&glm::vec3(1., 0.5, 1.));
+        mesh.normals = [tilted.x, tilted.y, tilted.z].repeat(4);
+        mesh.compute_tangents().unwrap();
+
+        for i in 0..4 {
+            let (t, w) = tangent(&mesh, i);
+            assert!((glm::length(&t) - 1.).abs() < 1e-5);
+            assert!(glm::dot(&t, &tilted).abs() < 1e-5);
+            assert!(t.x > 0.);
+            assert_eq!(w, 1.);
+        }
+    }
+
+    #[test]
+    fn vertices_shared_by_mirrored_halves_are_split() {
+        // The UVs are mirrored across the diagonal, as on a symmetric model, so the two triangles
+        // disagree on handedness at the corners they share
+        let mut mesh = quad(vec![0., 0., 1., 0.5, 0., 1., 1., 0.5]);
+        mesh.compute_tangents().unwrap();
+        assert_eq!(mesh.vertex_count(), 6);
+
+        let sides = [(glm::vec3(1., -1., 0.), 1.), (glm::vec3(-1., 1., 0.), -1.)];
+        for (triangle, &(expected, expected_w)) in mesh.indices.chunks(3).zip(&sides) {
+            let expected = glm::normalize(&expected);
+            for &i in triangle {
+                let (t, w) = tangent(&mesh, i as usize);
+                assert!(glm::distance(&t, &expected) < 1e-5, "{:?}", t);
+                assert_eq!(w, expected_w);
+            }
+        }
+    }
+
+    #[test]
+    fn tangents_need_texcoords() {
+        assert!(quad(vec![]).compute_tangents().is_err());
+    }
+}
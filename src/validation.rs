@@ -0,0 +1,446 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::mesh::Mesh;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MeshIssue {
+    IndexCountNotTriangles {
+        count: usize,
+    },
+    IndexOutOfRange {
+        triangle: usize,
+        index: u32,
+    },
+    DegenerateTriangle {
+        triangle: usize,
+    },
+    NonFinite {
+        attribute: &'static str,
+        vertex: usize,
+    },
+    AttributeLength {
+        attribute: &'static str,
+        expected: usize,
+        found: usize,
+    },
+    NonManifoldEdge {
+        a: u32,
+        b: u32,
+        triangles: usize,
+    },
+    Empty, // No vertices or no triangles, so there is nothing to draw
+}
+
+impl MeshIssue {
+    // Issues that make the mesh unsafe to upload, as opposed to merely looking wrong
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            MeshIssue::IndexCountNotTriangles { .. }
+            | MeshIssue::IndexOutOfRange { .. }
+            | MeshIssue::NonFinite { .. }
+            | MeshIssue::AttributeLength { .. }
+            | MeshIssue::Empty => true,
+            MeshIssue::DegenerateTriangle { .. } | MeshIssue::NonManifoldEdge { .. } => false,
+        }
+    }
+}
+
+impl fmt::Display for MeshIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MeshIssue::IndexCountNotTriangles { count } => {
+                write!(f, "{} indices is not a whole number of triangles", count)
+            }
+            MeshIssue::IndexOutOfRange { triangle, index } => {
+                write!(
+                    f,
+                    "triangle {} refers to missing vertex {}",
+                    triangle, index
+                )
+            }
+            MeshIssue::DegenerateTriangle { triangle } => {
+                write!(f, "triangle {} has no area", triangle)
+            }
+            MeshIssue::NonFinite { attribute, vertex } => {
+                write!(
+                    f,
+                    "vertex {} has a NaN or infinite {} value",
+                    vertex, attribute
+                )
+            }
+            MeshIssue::AttributeLength {
+                attribute,
+                expected,
+                found,
+            } => write!(
+                f,
+                "expected {} {} values for the vertices, found {}",
+                expected, attribute, found
+            ),
+            MeshIssue::NonManifoldEdge { a, b, triangles } => write!(
+                f,
+                "edge between vertices {} and {} is shared by {} triangles",
+                a, b, triangles
+            ),
+            MeshIssue::Empty => write!(f, "the mesh has no triangles"),
+        }
+    }
+}
+
+// What repair changed
+#[derive(Clone, Debug, Default)]
+pub struct RepairSummary {
+    pub welded_vertices: usize,
+    pub removed_vertices: usize,  // No longer used by any triangle
+    pub removed_triangles: usize, // Degenerate or referring to missing vertices
+}
+
+impl Mesh {
+    /**
+     * Checks the mesh for problems, returning every issue found. Positions are compared exactly
+     * when looking for non-manifold edges, so vertices split along e.g. texture seams still
+     * count as connected.
+     */
+    pub fn validate(&self) -> Vec<MeshIssue> {
+        let mut issues = vec![];
+        let n_vertices = self.vertex_count();
+
+        if n_vertices == 0 || self.indices.len() < 3 {
+            issues.push(MeshIssue::Empty);
+        }
+
+        if !self.vertices.len().is_multiple_of(3) {
+            issues.push(MeshIssue::AttributeLength {
+                attribute: "position",
+                expected: n_vertices * 3,
+                found: self.vertices.len(),
+            });
+        }
+        for (attribute, values, size, optional) in self.attributes() {
+            if values.len() != n_vertices * size && !(optional && values.is_empty()) {
+                issues.push(MeshIssue::AttributeLength {
+                    attribute,
+                    expected: n_vertices * size,
+                    found: values.len(),
+                });
+            }
+            if let Some(i) = values.iter().position(|v| !v.is_finite()) {
+                issues.push(MeshIssue::NonFinite {
+                    attribute,
+                    vertex: i / size,
+                });
+            }
+        }
+
+        if !self.indices.len().is_multiple_of(3) {
+            issues.push(MeshIssue::IndexCountNotTriangles {
+                count: self.indices.len(),
+            });
+        }
+
+        let position_ids = self.position_ids(0.);
+        // Keyed by position, holding the first vertices seen for the edge and its triangle count
+        let mut edges: HashMap<(u32, u32), ((u32, u32), usize)> = HashMap::new();
+        for (triangle, t) in self.indices.chunks_exact(3).enumerate() {
+            if let Some(&index) = t.iter().find(|&&i| i as usize >= n_vertices) {
+                issues.push(MeshIssue::IndexOutOfRange { triangle, index });
+                continue;
+            }
+
+            if self.is_degenerate(t) {
+                issues.push(MeshIssue::DegenerateTriangle { triangle });
+                continue;
+            }
+
+            for k in 0..3 {
+                let (a, b) = (t[k], t[(k + 1) % 3]);
+                let (pa, pb) = (position_ids[a as usize], position_ids[b as usize]);
+                let key = if pa < pb { (pa, pb) } else { (pb, pa) };
+                edges.entry(key).or_insert(((a.min(b), a.max(b)), 0)).1 += 1;
+            }
+        }
+
+        let mut non_manifold: Vec<_> = edges.into_values().filter(|&(_, n)| n > 2).collect();
+        non_manifold.sort();
+        issues.extend(
+            non_manifold
+                .into_iter()
+                .map(|((a, b), triangles)| MeshIssue::NonManifoldEdge { a, b, triangles }),
+        );
+
+        issues
+    }
+
+    /**
+     * Welds vertices whose attributes all lie within the tolerance of each other, then removes
+     * degenerate triangles, triangles referring to missing vertices and unused vertices.
+     * Attributes of the wrong length are dropped, except for colours which are reset to white.
+     * Fails with MeshIssue::Empty if no triangles are left, e.g. when all of them were
+     * degenerate, so the caller can skip the mesh rather than draw nothing.
+     */
+    pub fn repair(&mut self, tolerance: f32) -> Result<RepairSummary, MeshIssue> {
+        let mut summary = RepairSummary::default();
+        let n_vertices = self.vertex_count();
+
+        // Truncated or padded attributes cannot be trusted to line up with the vertices
+        self.vertices.truncate(n_vertices * 3);
+        if self.colors.len() != n_vertices * 4 {
+            self.set_color([1.0, 1.0, 1.0, 1.0]);
+        }
+        if self.normals.len() != n_vertices * 3 {
+            self.normals.clear();
+        }
+        if self.texcoords.len() != n_vertices * 2 {
+            self.texcoords.clear();
+        }
+        if self.tangents.len() != n_vertices * 4 {
+            self.tangents.clear();
+        }
+
+        // Remove triangles referring to missing vertices or containing NaN
+        let finite: Vec<bool> = (0..n_vertices as u32)
+            .map(|i| self.vertex_values(i).iter().all(|v| v.is_finite()))
+            .collect();
+        let before = self.indices.len() / 3;
+        let mut triangles: Vec<[u32; 3]> = self
+            .indices
+            .chunks_exact(3)
+            .map(|t| [t[0], t[1], t[2]])
+            .filter(|t| {
+                t.iter()
+                    .all(|&i| (i as usize) < n_vertices && finite[i as usize])
+            })
+            .collect();
+
+        // Weld, by pointing every index at the first equivalent vertex
+        let weld_ids = self.position_ids(tolerance);
+        let mut canonical: HashMap<u32, Vec<u32>> = HashMap::new();
+        let mut weld_target: Vec<u32> = (0..n_vertices as u32).collect();
+        for i in 0..n_vertices as u32 {
+            let candidates = canonical.entry(weld_ids[i as usize]).or_default();
+            match candidates
+                .iter()
+                .find(|&&c| self.vertices_equal(c, i, tolerance))
+            {
+                Some(&c) => {
+                    weld_target[i as usize] = c;
+                    summary.welded_vertices += 1;
+                }
+                None => candidates.push(i),
+            }
+        }
+        for t in &mut triangles {
+            for i in t.iter_mut() {
+                *i = weld_target[*i as usize];
+            }
+        }
+
+        triangles.retain(|t| !self.is_degenerate(t));
+        summary.removed_triangles = before - triangles.len();
+
+        // Compact the vertices, keeping only those still in use
+        let mut remap = vec![u32::MAX; n_vertices];
+        let mut sources = vec![];
+        self.indices = triangles
+            .iter()
+            .flatten()
+            .map(|&i| {
+                if remap[i as usize] == u32::MAX {
+                    remap[i as usize] = sources.len() as u32;
+                    sources.push(i);
+                }
+                remap[i as usize]
+            })
+            .collect();
+        // Welded vertices are never referenced, so are not counted twice
+        summary.removed_vertices = n_vertices - summary.welded_vertices - sources.len();
+
+        self.rebuild_vertices(&sources);
+        self.index_count = self.indices.len() as i32;
+        self.update_bounds();
+
+        if self.indices.is_empty() {
+            return Err(MeshIssue::Empty);
+        }
+        Ok(summary)
+    }
+
+    // Name, values, components per vertex and whether the attribute may be missing entirely
+    fn attributes(&self) -> Vec<(&'static str, &Vec<f32>, usize, bool)> {
+        vec![
+            ("position", &self.vertices, 3, false),
+            ("normal", &self.normals, 3, true),
+            ("color", &self.colors, 4, false),
+            ("texture coordinate", &self.texcoords, 2, true),
+            ("tangent", &self.tangents, 4, true),
+        ]
+    }
+
+    // Every attribute value of a vertex, for comparing and checking vertices as a whole
+    fn vertex_values(&self, i: u32) -> Vec<f32> {
+        let i = i as usize;
+        self.attributes()
+            .into_iter()
+            .filter(|(_, values, size, _)| values.len() >= (i + 1) * size)
+            .flat_map(|(_, values, size, _)| values[i * size..(i + 1) * size].to_vec())
+            .collect()
+    }
+
+    fn vertices_equal(&self, a: u32, b: u32, tolerance: f32) -> bool {
+        let (a, b) = (self.vertex_values(a), self.vertex_values(b));
+        a.len() == b.len() && a.iter().zip(&b).all(|(x, y)| (x - y).abs() <= tolerance)
+    }
+
+    fn is_degenerate(&self, t: &[u32]) -> bool {
+        t[0] == t[1] || t[1] == t[2] || t[0] == t[2] || glm::length(&self.triangle_cross(t)) <= 0.
+    }
+
+    /**
+     * Gives vertices within the tolerance of each other's position the same id, by snapping
+     * them to a grid of that size. Vertices close to a grid line may end up in neighbouring
+     * cells, which only means some welds are missed.
+     */
+    fn position_ids(&self, tolerance: f32) -> Vec<u32> {
+        let mut ids: HashMap<[i64; 3], u32> = HashMap::new();
+        (0..self.vertex_count() as u32)
+            .map(|i| {
+                let p = self.position(i);
+                let key = if tolerance > 0. {
+                    [
+                        (p.x / tolerance).round() as i64,
+                        (p.y / tolerance).round() as i64,
+                        (p.z / tolerance).round() as i64,
+                    ]
+                } else {
+                    [
+                        p.x.to_bits() as i64,
+                        p.y.to_bits() as i64,
+                        p.z.to_bits() as i64,
+                    ]
+                };
+                let next_id = ids.len() as u32;
+                *ids.entry(key).or_insert(next_id)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mesh(vertices: Vec<f32>, indices: Vec<u32>) -> Mesh {
+        Mesh::from_shape((vertices, indices), [1., 1., 1., 1.])
+    }
+
+    // A unit square split along its diagonal, with each triangle having its own vertices
+    fn split_square() -> Mesh {
+        #[rustfmt::skip]
+        let vertices = vec![
+            0., 0., 0.,  1., 0., 0.,  1., 1., 0.,
+            0., 0., 0.,  1., 1., 0.,  0., 1., 0.,
+        ];
+        mesh(vertices, (0..6).collect())
+    }
+
+    #[test]
+    fn sound_meshes_have_no_issues() {
+        assert_eq!(split_square().validate(), vec![]);
+    }
+
+    #[test]
+    fn empty_meshes_are_fatal() {
+        for empty in [mesh(vec![], vec![]), mesh(vec![0., 0., 0.], vec![])] {
+            let issues = empty.validate();
+            assert_eq!(issues, vec![MeshIssue::Empty]);
+            assert!(issues[0].is_fatal());
+        }
+    }
+
+    #[test]
+    fn broken_meshes_are_reported() {
+        let mut square = split_square();
+        square.indices.extend([0, 1, 9, 0, 0, 1]);
+        square.vertices[4] = f32::NAN;
+        square.normals = vec![0.; 5];
+        square.indices.push(2);
+        let issues = square.validate();
+        for issue in [
+            MeshIssue::AttributeLength {
+                attribute: "normal",
+                expected: 18,
+                found: 5,
+            },
+            MeshIssue::NonFinite {
+                attribute: "position",
+                vertex: 1,
+            },
+            MeshIssue::IndexCountNotTriangles { count: 13 },
+            MeshIssue::IndexOutOfRange {
+                triangle: 2,
+                index: 9,
+            },
+            MeshIssue::DegenerateTriangle { triangle: 3 },
+        ] {
+            assert!(
+                issues.contains(&issue),
+                "{} is missing from {:?}",
+                issue,
+                issues
+            );
+        }
+        assert!(!MeshIssue::DegenerateTriangle { triangle: 3 }.is_fatal());
+    }
+
+    #[test]
+    fn edges_shared_by_more_than_two_triangles_are_non_manifold() {
+        // Three fins along the X axis, one of them on vertices of its own at the same place
+        #[rustfmt::skip]
+        let vertices = vec![
+            0., 0., 0.,  1., 0., 0.,  0., 1., 0.,  0., 0., 1.,
+            0., 0., 0.,  1., 0., 0.,  0., -1., 0.,
+        ];
+        let fins = mesh(vertices, vec![0, 1, 2, 0, 1, 3, 4, 5, 6]);
+        assert_eq!(
+            fins.validate(),
+            vec![MeshIssue::NonManifoldEdge {
+                a: 0,
+                b: 1,
+                triangles: 3
+            }]
+        );
+    }
+
+    #[test]
+    fn repair_welds_vertices_and_drops_broken_triangles() {
+        let mut square = split_square();
+        // A triangle through a NaN vertex, one with no area and one past the last vertex
+        square.vertices.extend([f32::NAN, 0., 0.]);
+        square.colors.extend([1.; 4]);
+        square.indices.extend([0, 1, 6, 0, 2, 3, 1, 2, 7]);
+
+        let summary = square.repair(0.).unwrap();
+        assert_eq!(summary.welded_vertices, 2);
+        assert_eq!(summary.removed_vertices, 1);
+        assert_eq!(summary.removed_triangles, 3);
+        assert_eq!(square.vertex_count(), 4);
+        assert_eq!(square.indices, vec![0, 1, 2, 0, 2, 3]);
+        assert_eq!(square.validate(), vec![]);
+    }
+
+    #[test]
+    fn repair_welds_within_the_tolerance() {
+        let mut square = split_square();
+        square.vertices[9] = 0.001;
+        assert_eq!(square.clone().repair(0.).unwrap().welded_vertices, 1);
+        assert_eq!(square.repair(0.01).unwrap().welded_vertices, 2);
+    }
+
+    #[test]
+    fn repair_fails_when_nothing_is_left() {
+        let mut line = mesh(vec![0., 0., 0., 1., 0., 0., 2., 0., 0.], vec![0, 1, 2]);
+        assert_eq!(line.repair(0.).unwrap_err(), MeshIssue::Empty);
+        assert!(line.indices.is_empty());
+    }
+}